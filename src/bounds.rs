@@ -12,12 +12,15 @@ pub struct BoundingBox {
 impl BoundingBox {
     pub fn empty() -> Self {
         BoundingBox {
-            min: Point3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY),
-            max: Point3::new(
-                std::f32::NEG_INFINITY,
-                std::f32::NEG_INFINITY,
-                std::f32::NEG_INFINITY,
-            ),
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn infinite() -> Self {
+        BoundingBox {
+            min: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            max: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
         }
     }
 
@@ -43,6 +46,21 @@ impl BoundingBox {
         }
     }
 
+    pub fn intersection(left: &Self, right: &Self) -> Self {
+        BoundingBox {
+            min: Point3::new(
+                float::max(left.min.x(), right.min.x()),
+                float::max(left.min.y(), right.min.y()),
+                float::max(left.min.z(), right.min.z()),
+            ),
+            max: Point3::new(
+                float::min(left.max.x(), right.max.x()),
+                float::min(left.max.y(), right.max.y()),
+                float::min(left.max.z(), right.max.z()),
+            ),
+        }
+    }
+
     pub fn merge(&mut self, other: &Self) {
         *self = BoundingBox::union(self, other);
     }
@@ -54,6 +72,15 @@ impl BoundingBox {
         });
     }
 
+    /// Checks whether the box contains no points at all.
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|i| self.min[i] > self.max[i])
+    }
+
+    pub fn is_finite(&self) -> bool {
+        (0..3).all(|i| self.min[i].is_finite() && self.max[i].is_finite())
+    }
+
     pub fn centroid(&self) -> Point3 {
         self.min + ((self.max - self.min) / 2.0)
     }
@@ -65,11 +92,17 @@ impl BoundingBox {
         2.0 * (x * y + x * z + y * z)
     }
 
-    pub fn intersects(&self, ray: Ray) -> bool {
+    /// Finds the distance along a ray at which it enters this box, or zero if
+    /// the ray starts inside of it.
+    pub fn intersects(&self, ray: Ray) -> Option<f32> {
         increment_statistic!(statistics::BOUNDING_BOX_TESTS);
+        self.clip(ray).map(|(tmin, _tmax)| float::max(tmin, 0.0))
+    }
 
-        let mut tmin = std::f32::NEG_INFINITY;
-        let mut tmax = std::f32::INFINITY;
+    /// Finds the range of distances along a ray that lie inside this box.
+    pub fn clip(&self, ray: Ray) -> Option<(f32, f32)> {
+        let mut tmin = f32::NEG_INFINITY;
+        let mut tmax = f32::INFINITY;
         for i in 0..3 {
             let origin = ray.origin[i];
             let dir = ray.dir[i];
@@ -78,6 +111,10 @@ impl BoundingBox {
             tmin = float::max(tmin, float::min(t1, t2));
             tmax = float::min(tmax, float::max(t1, t2));
         }
-        tmin <= tmax && tmax > 0.0
+        if tmin <= tmax && tmax > 0.0 {
+            Some((tmin, tmax))
+        } else {
+            None
+        }
     }
 }
@@ -1,85 +1,1300 @@
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::ops::ControlFlow;
+
+use bytemuck::{Pod, Zeroable};
+use rayon::prelude::*;
+
 use crate::bounds::BoundingBox;
+use crate::cache::{CacheKey, CacheReader, CacheWriter, Cacheable, MappedVec};
 use crate::float;
+use crate::mesh::TriangleMesh;
 use crate::object::{Object, Sample};
+use crate::packet::{self, RayPacket};
 use crate::ray::Ray;
-use crate::types::Axis;
+use crate::simd::{self, Lanes, SimdRay, LANES};
+use crate::types::{Axis, Point3};
+use crate::{increment_statistic, statistics};
+
+// The most levels of nodes a hierarchy may have, which sets the size of the
+// traversal stacks.
+const MAX_DEPTH: usize = 64;
+
+// From this depth on, nodes are split in half rather than by the SAH, so that
+// even degenerate trees end within MAX_DEPTH. Seventeen halvings take any
+// number of primitives a u32 can index down to one that a leaf can hold.
+const BALANCED_DEPTH: usize = MAX_DEPTH - 18;
+
+// Packets which this few rays enter a child of split up into single rays.
+const SINGLE_RAY_PACKET_SIZE: u32 = 16;
+
+// Spatial splits are only considered where the children of the best object
+// split overlap by more than this fraction of the root's surface area, which
+// limits how many references get duplicated.
+const SPATIAL_SPLIT_OVERLAP: f32 = 1e-5;
+// Nodes with at least this many references are built in parallel.
+const PARALLEL_BUILD_SIZE: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct BvhOptions {
+    /// The maximum number of objects stored in a single leaf.
+    pub max_leaf_size: usize,
+    /// The number of bins candidate split planes are placed between.
+    pub bins: usize,
+    /// The estimated cost of traversing a node, for the surface area
+    /// heuristic.
+    pub traversal_cost: f32,
+    /// The estimated cost of intersecting an object, for the surface area
+    /// heuristic.
+    pub intersection_cost: f32,
+    /// Whether to also consider splitting objects which straddle a plane into
+    /// both children, as in Stich et al., "Spatial Splits in Bounding Volume
+    /// Hierarchies". This reduces overlap around long, thin triangles.
+    pub spatial_splits: bool,
+    /// When refitting raises the tree's SAH cost above this multiple of its
+    /// cost when built, it is rebuilt from scratch instead.
+    pub rebuild_threshold: Option<f32>,
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        BvhOptions {
+            max_leaf_size: 4,
+            bins: 8,
+            traversal_cost: 0.5,
+            intersection_cost: 1.0,
+            spatial_splits: false,
+            rebuild_threshold: None,
+        }
+    }
+}
+
+impl BvhOptions {
+    /// Adds the options which affect how the tree is built to a cache key.
+    pub fn add_to_key(&self, key: &mut CacheKey) {
+        key.add_usize(self.max_leaf_size);
+        key.add_usize(self.bins);
+        key.add_f32(self.traversal_cost);
+        key.add_f32(self.intersection_cost);
+        key.add_usize(self.spatial_splits as usize);
+    }
+}
+
+/// Measures of how well a BVH fits the objects in it.
+#[derive(Clone, Debug, Default)]
+pub struct BvhReport {
+    pub nodes: usize,
+    pub leaves: usize,
+    /// The number of objects referenced by leaves, which exceeds the number
+    /// of objects when spatial splits duplicate them.
+    pub references: usize,
+    /// The number of objects, counting each face of a mesh separately.
+    pub objects: usize,
+    /// The expected cost of intersecting a ray with the tree, according to
+    /// the surface area heuristic.
+    pub sah_cost: f32,
+    /// The total surface area of the overlap between siblings, relative to
+    /// the surface area of the root.
+    pub overlap: f32,
+}
+
+impl fmt::Display for BvhReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "BVH:")?;
+        writeln!(f, "  {:<20} {}", "Nodes:", self.nodes)?;
+        writeln!(f, "  {:<20} {}", "Leaves:", self.leaves)?;
+        writeln!(f, "  {:<20} {}", "Objects:", self.objects)?;
+        writeln!(f, "  {:<20} {}", "References:", self.references)?;
+        writeln!(f, "  {:<20} {:.2}", "SAH cost:", self.sah_cost)?;
+        write!(f, "  {:<20} {:.2}", "Overlap:", self.overlap)
+    }
+}
 
+/// A bounding volume hierarchy, built as a binary tree and then collapsed so
+/// that each node has several children, whose boxes are tested against a ray
+/// at once. Nodes are stored in depth-first order.
 #[derive(Debug)]
 pub struct BoundingVolumeHierarchy {
-    root: Box<BvhNode>,
+    nodes: MappedVec<WideNode>,
+    // Leaves refer to ranges of this list.
+    indices: Vec<Primitive>,
+    // Objects and meshes are kept in the order they were given in.
+    objects: Vec<Object>,
+    meshes: Vec<TriangleMesh>,
+    // Surfaces without finite bounds, such as infinite planes, can't be
+    // partitioned, so they are tested against every ray instead.
+    unbounded: Vec<Primitive>,
+    options: BvhOptions,
+    report: BvhReport,
+    // The SAH cost of the tree when it was last built.
+    built_cost: f32,
 }
 
-impl BoundingVolumeHierarchy {
-    pub fn new(objects: Vec<Object>) -> Self {
-        let objects = objects
-            .into_iter()
-            .map(|obj| (obj.surface.bounding_box(), obj))
-            .collect();
-        BoundingVolumeHierarchy {
-            root: BvhNode::new(objects),
+#[derive(Clone, Debug)]
+struct BinaryNode {
+    bounds: BoundingBox,
+    // For leaves, the position of the first object in `indices`. For interior
+    // nodes, the index of the second child; the first immediately follows.
+    offset: u32,
+    // The number of objects in a leaf, or zero for interior nodes.
+    count: u16,
+}
+
+/// Something the hierarchy partitions: an object, or a face of a mesh.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Primitive {
+    Object(u32),
+    Face { mesh: u32, face: u32 },
+}
+
+impl Cacheable for Primitive {
+    fn write(&self, cache: &mut CacheWriter) {
+        // Objects are stored as faces of a mesh which can't exist.
+        let (mesh, index) = match *self {
+            Primitive::Object(index) => (u32::MAX, index),
+            Primitive::Face { mesh, face } => (mesh, face),
+        };
+        cache.write(&mesh);
+        cache.write(&index);
+    }
+
+    fn read(cache: &mut CacheReader) -> Option<Self> {
+        let (mesh, index) = (cache.read()?, cache.read()?);
+        Some(match mesh {
+            u32::MAX => Primitive::Object(index),
+            mesh => Primitive::Face { mesh, face: index },
+        })
+    }
+}
+
+/// The objects and meshes a hierarchy is built over.
+#[derive(Copy, Clone)]
+struct Primitives<'a> {
+    objects: &'a [Object],
+    meshes: &'a [TriangleMesh],
+}
+
+impl<'a> Primitives<'a> {
+    fn all(self) -> impl ParallelIterator<Item = Primitive> + 'a {
+        let objects = (0..self.objects.len() as u32).into_par_iter();
+        let faces = self
+            .meshes
+            .par_iter()
+            .enumerate()
+            .flat_map(|(mesh, faces)| {
+                (0..faces.len() as u32)
+                    .into_par_iter()
+                    .map(move |face| Primitive::Face {
+                        mesh: mesh as u32,
+                        face,
+                    })
+            });
+        objects.map(Primitive::Object).chain(faces)
+    }
+
+    fn count(self) -> usize {
+        self.objects.len() + self.meshes.iter().map(TriangleMesh::len).sum::<usize>()
+    }
+
+    fn contains(self, primitive: Primitive) -> bool {
+        match primitive {
+            Primitive::Object(index) => (index as usize) < self.objects.len(),
+            Primitive::Face { mesh, face } => self
+                .meshes
+                .get(mesh as usize)
+                .is_some_and(|mesh| (face as usize) < mesh.len()),
         }
     }
 
-    pub fn sample(&self, ray: Ray) -> Option<Sample> {
-        self.root.sample(ray)
+    fn bounding_box(self, primitive: Primitive) -> BoundingBox {
+        match primitive {
+            Primitive::Object(index) => self.objects[index as usize].surface.bounding_box(),
+            Primitive::Face { mesh, face } => {
+                self.meshes[mesh as usize].bounding_box(face as usize)
+            }
+        }
+    }
+
+    fn clip_bounds(self, primitive: Primitive, bounds: &BoundingBox) -> BoundingBox {
+        match primitive {
+            Primitive::Object(index) => self.objects[index as usize].surface.clip_bounds(bounds),
+            Primitive::Face { mesh, face } => {
+                self.meshes[mesh as usize].clip_bounds(face as usize, bounds)
+            }
+        }
+    }
+
+    fn sample(self, primitive: Primitive, ray: Ray, t_max: f32) -> Option<Sample<'a>> {
+        match primitive {
+            Primitive::Object(index) => self.objects[index as usize]
+                .sample(ray)
+                .filter(|sample| sample.intersection.distance < t_max),
+            Primitive::Face { mesh, face } => {
+                let mesh = &self.meshes[mesh as usize];
+                mesh.intersect(face as usize, ray, t_max)
+                    .map(|intersection| Sample {
+                        intersection,
+                        material: mesh.material(face as usize),
+                    })
+            }
+        }
+    }
+
+    fn occluded(self, primitive: Primitive, ray: Ray, t_max: f32) -> bool {
+        match primitive {
+            Primitive::Object(index) => self.objects[index as usize].occluded(ray, t_max),
+            Primitive::Face { mesh, face } => {
+                self.meshes[mesh as usize].occluded(face as usize, ray, t_max)
+            }
+        }
+    }
+}
+
+/// A node with up to `LANES` children, with their bounds stored per axis so
+/// that they line up with SIMD lanes. Unused slots have empty bounds.
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C, align(32))]
+struct WideNode {
+    min: [Lanes; 3],
+    max: [Lanes; 3],
+    // For leaf children, the position of their first object in `indices`. For
+    // interior children, the index of the child node.
+    children: [u32; LANES],
+    // The number of objects in each leaf child, or zero for interior children.
+    counts: [u16; LANES],
+    // Fills the node out to its alignment, so that it has no uninitialized
+    // bytes and can be cached as it is.
+    padding: [u16; LANES],
+}
+
+impl WideNode {
+    fn empty() -> Self {
+        WideNode {
+            min: [[f32::INFINITY; LANES]; 3],
+            max: [[f32::NEG_INFINITY; LANES]; 3],
+            children: [0; LANES],
+            counts: [0; LANES],
+            padding: [0; LANES],
+        }
+    }
+
+    fn bounds(&self, lane: usize) -> BoundingBox {
+        BoundingBox {
+            min: Point3::new(self.min[0][lane], self.min[1][lane], self.min[2][lane]),
+            max: Point3::new(self.max[0][lane], self.max[1][lane], self.max[2][lane]),
+        }
+    }
+
+    fn set_bounds(&mut self, lane: usize, bounds: &BoundingBox) {
+        for axis in 0..3 {
+            self.min[axis][lane] = bounds.min[axis];
+            self.max[axis][lane] = bounds.max[axis];
+        }
     }
 }
 
+/// The structure of a hierarchy without its objects, as stored in a cache.
 #[derive(Debug)]
-enum BvhNode {
-    Node(BoundingBox, Box<BvhNode>, Box<BvhNode>),
-    Leaf(Object),
+pub struct BvhLayout {
+    nodes: MappedVec<WideNode>,
+    indices: Vec<Primitive>,
+    unbounded: Vec<Primitive>,
+    built_cost: f32,
 }
 
-impl BvhNode {
-    fn new(mut objects: Vec<(BoundingBox, Object)>) -> Box<Self> {
-        if objects.len() == 1 {
-            Box::new(BvhNode::Leaf(objects.remove(0).1))
-        } else {
-            let bb = objects
-                .iter()
-                .map(|(bb, _obj)| bb)
-                .fold(BoundingBox::empty(), |ref left, right| {
+impl BvhLayout {
+    /// Checks that the layout refers only to the given objects and meshes,
+    /// that child nodes come after their parents, and that it is no deeper
+    /// than traversal allows.
+    pub fn fits(&self, objects: &[Object], meshes: &[TriangleMesh]) -> bool {
+        let primitives = Primitives { objects, meshes };
+        let nodes_fit = self.nodes.iter().enumerate().all(|(i, node)| {
+            (0..LANES).all(|lane| {
+                let child = node.children[lane] as usize;
+                match node.counts[lane] {
+                    0 => node.bounds(lane).is_empty() || (i < child && child < self.nodes.len()),
+                    count => child + count as usize <= self.indices.len(),
+                }
+            })
+        });
+        nodes_fit
+            && self.depth() <= MAX_DEPTH
+            && self.indices.iter().all(|&p| primitives.contains(p))
+            && self.unbounded.iter().all(|&p| primitives.contains(p))
+    }
+
+    // The number of levels of nodes, given that children come after their
+    // parents.
+    fn depth(&self) -> usize {
+        let mut depths = vec![1; self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            for lane in 0..LANES {
+                if node.counts[lane] == 0 && !node.bounds(lane).is_empty() {
+                    depths[node.children[lane] as usize] = depths[i] + 1;
+                }
+            }
+        }
+        depths.into_iter().max().unwrap_or(0)
+    }
+}
+
+impl Cacheable for BvhLayout {
+    fn write(&self, cache: &mut CacheWriter) {
+        write_layout(
+            cache,
+            &self.nodes,
+            &self.indices,
+            &self.unbounded,
+            self.built_cost,
+        );
+    }
+
+    fn read(cache: &mut CacheReader) -> Option<Self> {
+        Some(BvhLayout {
+            nodes: cache.read_mapped_vec()?,
+            indices: cache.read_vec()?,
+            unbounded: cache.read_vec()?,
+            built_cost: cache.read()?,
+        })
+    }
+}
+
+fn write_layout(
+    cache: &mut CacheWriter,
+    nodes: &[WideNode],
+    indices: &[Primitive],
+    unbounded: &[Primitive],
+    built_cost: f32,
+) {
+    cache.write_pod_slice(nodes);
+    cache.write_slice(indices);
+    cache.write_slice(unbounded);
+    cache.write(&built_cost);
+}
+
+// A child queued for traversal, along with the distance at which the ray
+// enters its bounds.
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    distance: f32,
+    offset: u32,
+    count: u16,
+}
+
+// A child queued for traversal by a packet, along with the rays which enter
+// its bounds and the nearest distance at which any of them does.
+#[derive(Copy, Clone, Debug)]
+struct PacketEntry {
+    distance: f32,
+    offset: u32,
+    count: u16,
+    rays: u64,
+}
+
+impl BoundingVolumeHierarchy {
+    pub fn new(objects: Vec<Object>, meshes: Vec<TriangleMesh>) -> Self {
+        BoundingVolumeHierarchy::with_options(objects, meshes, &BvhOptions::default())
+    }
+
+    pub fn with_options(
+        objects: Vec<Object>,
+        meshes: Vec<TriangleMesh>,
+        options: &BvhOptions,
+    ) -> Self {
+        let primitives = Primitives {
+            objects: &objects,
+            meshes: &meshes,
+        };
+        let (references, unbounded): (References, References) = primitives
+            .all()
+            .map(|primitive| (primitives.bounding_box(primitive), primitive))
+            .partition(|(bb, _)| bb.is_finite());
+        let unbounded = unbounded
+            .into_iter()
+            .map(|(_bb, primitive)| primitive)
+            .collect();
+
+        let threads = rayon::current_num_threads();
+        let (bounds, _) = bounds_of(&references, threads > 1);
+        let builder = Builder {
+            primitives,
+            options,
+            min_overlap: SPATIAL_SPLIT_OVERLAP * bounds.surface_area(),
+            parallel: threads > 1,
+            // Split the work a few times more than there are threads, to
+            // balance uneven subtrees.
+            parallel_depth: threads.next_power_of_two().ilog2() as usize + 2,
+        };
+        let mut binary = Vec::new();
+        let mut indices = Vec::with_capacity(references.len());
+        let mut nodes = Vec::new();
+        if !references.is_empty() {
+            builder.build(references, 0, &mut binary, &mut indices);
+            collapse(&binary, 0, &mut nodes);
+        }
+        let mut bvh = BoundingVolumeHierarchy {
+            nodes: nodes.into(),
+            indices,
+            objects,
+            meshes,
+            unbounded,
+            options: options.clone(),
+            report: BvhReport::default(),
+            built_cost: 0.0,
+        };
+        bvh.report = bvh.measure();
+        bvh.built_cost = bvh.report.sah_cost;
+        bvh
+    }
+
+    /// Restores a hierarchy over the objects and meshes it was built for from
+    /// its layout, which must fit them.
+    pub fn from_layout(
+        objects: Vec<Object>,
+        meshes: Vec<TriangleMesh>,
+        options: &BvhOptions,
+        layout: BvhLayout,
+    ) -> Self {
+        debug_assert!(layout.fits(&objects, &meshes));
+        let mut bvh = BoundingVolumeHierarchy {
+            nodes: layout.nodes,
+            indices: layout.indices,
+            objects,
+            meshes,
+            unbounded: layout.unbounded,
+            options: options.clone(),
+            report: BvhReport::default(),
+            built_cost: layout.built_cost,
+        };
+        bvh.report = bvh.measure();
+        bvh
+    }
+
+    /// Writes the hierarchy's layout to a cache, to be read back as a
+    /// `BvhLayout`.
+    pub fn write_layout(&self, cache: &mut CacheWriter) {
+        write_layout(
+            cache,
+            &self.nodes,
+            &self.indices,
+            &self.unbounded,
+            self.built_cost,
+        );
+    }
+
+    pub fn report(&self) -> &BvhReport {
+        &self.report
+    }
+
+    /// The objects in the hierarchy, in the order they were given in. After
+    /// moving any of them, the hierarchy must be refit before it is used.
+    pub fn objects_mut(&mut self) -> &mut [Object] {
+        &mut self.objects
+    }
+
+    /// The meshes in the hierarchy, in the order they were given in.
+    pub fn meshes_mut(&mut self) -> &mut [TriangleMesh] {
+        &mut self.meshes
+    }
+
+    fn primitives(&self) -> Primitives<'_> {
+        Primitives {
+            objects: &self.objects,
+            meshes: &self.meshes,
+        }
+    }
+
+    /// Updates the bounds of every node bottom-up after objects or the
+    /// vertices of meshes have moved, keeping the structure of the tree. This
+    /// is much faster than building a new tree, but the tree gets worse the
+    /// further objects move, so it is rebuilt instead once its cost exceeds
+    /// the `rebuild_threshold`. Returns whether the tree was rebuilt.
+    ///
+    /// Objects must keep finite bounds if they had them, and vice versa.
+    pub fn refit(&mut self) -> bool {
+        // Children always come after their parents, so updating nodes in
+        // reverse order reaches every child first.
+        for node in (0..self.nodes.len()).rev() {
+            for lane in 0..LANES {
+                let bounds = match self.nodes[node].counts[lane] {
+                    0 if self.nodes[node].bounds(lane).is_empty() => continue,
+                    0 => {
+                        let child = &self.nodes[self.nodes[node].children[lane] as usize];
+                        (0..LANES)
+                            .map(|lane| child.bounds(lane))
+                            .fold(BoundingBox::empty(), |ref left, ref right| {
+                                BoundingBox::union(left, right)
+                            })
+                    }
+                    count => {
+                        let start = self.nodes[node].children[lane] as usize;
+                        self.indices[start..start + count as usize]
+                            .iter()
+                            .map(|&primitive| self.primitives().bounding_box(primitive))
+                            .fold(BoundingBox::empty(), |ref left, ref right| {
+                                BoundingBox::union(left, right)
+                            })
+                    }
+                };
+                self.nodes[node].set_bounds(lane, &bounds);
+            }
+        }
+
+        self.report = self.measure();
+        match self.options.rebuild_threshold {
+            Some(threshold) if self.report.sah_cost > self.built_cost * threshold => {
+                let objects = std::mem::take(&mut self.objects);
+                let meshes = std::mem::take(&mut self.meshes);
+                *self = BoundingVolumeHierarchy::with_options(objects, meshes, &self.options);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn measure(&self) -> BvhReport {
+        let mut report = BvhReport {
+            references: self.indices.len(),
+            objects: self.primitives().count(),
+            ..BvhReport::default()
+        };
+        if let Some(root) = self.nodes.first() {
+            let bounds = (0..LANES)
+                .map(|lane| root.bounds(lane))
+                .fold(BoundingBox::empty(), |ref left, ref right| {
                     BoundingBox::union(left, right)
                 });
-            let (left, right) = partition(objects);
-            Box::new(BvhNode::Node(bb, BvhNode::new(left), BvhNode::new(right)))
-        }
-    }
-
-    fn sample(&self, ray: Ray) -> Option<Sample> {
-        match self {
-            BvhNode::Node(bb, left, right) => {
-                if bb.intersects(ray) {
-                    let left = left.sample(ray);
-                    let right = right.sample(ray);
-                    match (left, right) {
-                        (Some(lc), Some(rc)) => {
-                            if lc.intersection.distance < rc.intersection.distance {
-                                Some(lc)
-                            } else {
-                                Some(rc)
-                            }
+            let area = bounds.surface_area();
+            self.measure_node(0, area, area, &mut report);
+        }
+        report
+    }
+
+    fn measure_node(&self, node: usize, area: f32, root_area: f32, report: &mut BvhReport) {
+        let options = &self.options;
+        let node = &self.nodes[node];
+        report.nodes += 1;
+        report.sah_cost += options.traversal_cost * area / root_area;
+        let children: Vec<_> = (0..LANES)
+            .map(|lane| node.bounds(lane))
+            .enumerate()
+            .filter(|(_, bounds)| !bounds.is_empty())
+            .collect();
+        for (i, (lane, bounds)) in children.iter().enumerate() {
+            let child_area = bounds.surface_area();
+            if node.counts[*lane] > 0 {
+                report.leaves += 1;
+                report.sah_cost +=
+                    options.intersection_cost * node.counts[*lane] as f32 * child_area / root_area;
+            } else {
+                let child = node.children[*lane] as usize;
+                self.measure_node(child, child_area, root_area, report);
+            }
+            for (_, sibling) in &children[i + 1..] {
+                let overlap = BoundingBox::intersection(bounds, sibling);
+                if !overlap.is_empty() {
+                    report.overlap += overlap.surface_area() / root_area;
+                }
+            }
+        }
+    }
+
+    pub fn sample(&self, ray: Ray) -> Option<Sample<'_>> {
+        let mut closest = self
+            .unbounded
+            .iter()
+            .filter_map(|&primitive| self.primitives().sample(primitive, ray, f32::INFINITY))
+            .min_by(|left, right| {
+                float::compare(&left.intersection.distance, &right.intersection.distance)
+            });
+        let t_max = closest
+            .as_ref()
+            .map_or(f32::INFINITY, |sample| sample.intersection.distance);
+        let primitives = self.primitives();
+        let _ = self.traverse(ray, t_max, |primitive, t_max| {
+            match primitives.sample(primitive, ray, t_max) {
+                Some(sample) => {
+                    let distance = sample.intersection.distance;
+                    closest = Some(sample);
+                    ControlFlow::Continue(distance)
+                }
+                None => ControlFlow::Continue(t_max),
+            }
+        });
+        closest
+    }
+
+    /// Checks whether the ray hits anything before reaching `t_max`, stopping
+    /// at the first hit found rather than searching for the closest.
+    pub fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        increment_statistic!(statistics::OCCLUSION_RAYS_CAST);
+        let primitives = self.primitives();
+        self.unbounded
+            .iter()
+            .any(|&primitive| primitives.occluded(primitive, ray, t_max))
+            || self
+                .traverse(ray, t_max, |primitive, t_max| {
+                    if primitives.occluded(primitive, ray, t_max) {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(t_max)
+                    }
+                })
+                .is_break()
+    }
+
+    /// Finds the closest hit of each ray in a packet, as `sample` would for
+    /// each ray in turn, but sharing the work of traversal between them.
+    pub fn sample_packet(&self, packet: &RayPacket) -> Vec<Option<Sample<'_>>> {
+        increment_statistic!(statistics::PACKETS_CAST);
+        let primitives = self.primitives();
+        let mut closest: Vec<_> = packet
+            .rays()
+            .iter()
+            .map(|&ray| {
+                self.unbounded
+                    .iter()
+                    .filter_map(|&primitive| primitives.sample(primitive, ray, f32::INFINITY))
+                    .min_by(|left, right| {
+                        float::compare(&left.intersection.distance, &right.intersection.distance)
+                    })
+            })
+            .collect();
+        let mut t_max: Vec<_> = closest
+            .iter()
+            .map(|sample| sample.map_or(f32::INFINITY, |sample| sample.intersection.distance))
+            .collect();
+        self.traverse_packet(
+            packet,
+            &mut t_max,
+            |primitive, ray, t_max| match primitives.sample(primitive, packet.rays()[ray], t_max) {
+                Some(sample) => {
+                    let distance = sample.intersection.distance;
+                    closest[ray] = Some(sample);
+                    ControlFlow::Continue(distance)
+                }
+                None => ControlFlow::Continue(t_max),
+            },
+        );
+        closest
+    }
+
+    /// Checks whether each ray in a packet hits anything before reaching its
+    /// `t_max`, as `occluded` would for each ray in turn.
+    pub fn occluded_packet(&self, packet: &RayPacket, t_max: &[f32]) -> Vec<bool> {
+        increment_statistic!(statistics::PACKETS_CAST);
+        assert_eq!(packet.len(), t_max.len());
+        let primitives = self.primitives();
+        let mut occluded: Vec<_> = packet
+            .rays()
+            .iter()
+            .zip(t_max)
+            .map(|(&ray, &t_max)| {
+                self.unbounded
+                    .iter()
+                    .any(|&primitive| primitives.occluded(primitive, ray, t_max))
+            })
+            .collect();
+        // Rays which are already occluded have nothing left to find.
+        let mut t_max: Vec<_> = t_max
+            .iter()
+            .zip(&occluded)
+            .map(|(&t_max, &occluded)| if occluded { 0.0 } else { t_max })
+            .collect();
+        self.traverse_packet(packet, &mut t_max, |primitive, ray, t_max| {
+            if primitives.occluded(primitive, packet.rays()[ray], t_max) {
+                occluded[ray] = true;
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(t_max)
+            }
+        });
+        occluded
+    }
+
+    /// Passes each primitive whose leaf the ray enters before `t_max` to `visit`,
+    /// nearest leaves first. The visitor returns the new `t_max`, to skip boxes
+    /// the ray enters beyond a hit, or breaks to end the traversal early.
+    fn traverse<F>(&self, ray: Ray, t_max: f32, visit: F) -> ControlFlow<()>
+    where
+        F: FnMut(Primitive, f32) -> ControlFlow<(), f32>,
+    {
+        if self.nodes.is_empty() {
+            return ControlFlow::Continue(());
+        }
+        let root = Entry {
+            distance: 0.0,
+            offset: 0,
+            count: 0,
+        };
+        self.traverse_from(root, &SimdRay::new(ray), t_max, visit)
+    }
+
+    /// Like `traverse`, but starting from a child queued by another
+    /// traversal.
+    fn traverse_from<F>(
+        &self,
+        root: Entry,
+        simd_ray: &SimdRay,
+        mut t_max: f32,
+        mut visit: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(Primitive, f32) -> ControlFlow<(), f32>,
+    {
+        // The stack is left uninitialized, as clearing it would cost more than
+        // traversing a small scene.
+        let mut stack = [const { MaybeUninit::<Entry>::uninit() }; MAX_DEPTH * LANES];
+        stack[0].write(root);
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            // SAFETY: every entry below `stack_len` has been written.
+            let entry = unsafe { stack[stack_len].assume_init() };
+            if entry.distance >= t_max {
+                continue;
+            }
+            if entry.count > 0 {
+                let start = entry.offset as usize;
+                for &primitive in &self.indices[start..start + entry.count as usize] {
+                    t_max = visit(primitive, t_max)?;
+                }
+                continue;
+            }
+
+            let node = &self.nodes[entry.offset as usize];
+            increment_statistic!(statistics::BOUNDING_BOX_TESTS);
+            let distances = simd::intersect_boxes(simd_ray, &node.min, &node.max, t_max);
+            // Push the children hit farthest first, so that the nearest is
+            // popped next.
+            let first = stack_len;
+            for (lane, &distance) in distances.iter().enumerate() {
+                if distance == f32::INFINITY {
+                    continue;
+                }
+                let child = Entry {
+                    distance,
+                    offset: node.children[lane],
+                    count: node.counts[lane],
+                };
+                let mut i = stack_len;
+                // SAFETY: every entry below `stack_len` has been written.
+                while i > first && unsafe { stack[i - 1].assume_init().distance } < child.distance {
+                    stack[i] = stack[i - 1];
+                    i -= 1;
+                }
+                stack[i].write(child);
+                stack_len += 1;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Like `traverse`, but for every ray in a packet at once, with a `t_max`
+    /// per ray. Each primitive is passed to `visit` along with the index of
+    /// a ray which enters its leaf, and breaking ends the traversal for that
+    /// ray alone.
+    ///
+    /// Each node's children are first checked against the packet's frustum,
+    /// and then against the rays which entered the node, to find which rays
+    /// go on to enter each child. Once few rays are left in a subtree, each
+    /// finishes it with `traverse_from`.
+    fn traverse_packet<F>(&self, packet: &RayPacket, t_max: &mut [f32], mut visit: F)
+    where
+        F: FnMut(Primitive, usize, f32) -> ControlFlow<(), f32>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        // Rays which haven't yet been stopped by the visitor.
+        let mut active = packet.all();
+        // The stack is left uninitialized, as in `traverse`.
+        let mut stack = [const { MaybeUninit::<PacketEntry>::uninit() }; MAX_DEPTH * LANES];
+        stack[0].write(PacketEntry {
+            distance: 0.0,
+            offset: 0,
+            count: 0,
+            rays: active,
+        });
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            // SAFETY: every entry below `stack_len` has been written.
+            let entry = unsafe { stack[stack_len].assume_init() };
+            // Drop the rays which have found a hit nearer than the child.
+            let rays = packet::rays_in(entry.rays & active)
+                .filter(|&ray| entry.distance < t_max[ray])
+                .fold(0u64, |rays, ray| rays | 1 << ray);
+            if rays == 0 {
+                continue;
+            }
+            if rays.count_ones() <= SINGLE_RAY_PACKET_SIZE {
+                // Too few rays are left to share much work, so each finishes
+                // the subtree on its own.
+                let root = Entry {
+                    distance: entry.distance,
+                    offset: entry.offset,
+                    count: entry.count,
+                };
+                for ray in packet::rays_in(rays) {
+                    let simd_ray = packet.simd_ray(ray);
+                    let flow = self.traverse_from(root, simd_ray, t_max[ray], |primitive, t| {
+                        let flow = visit(primitive, ray, t);
+                        if let ControlFlow::Continue(distance) = flow {
+                            t_max[ray] = distance;
+                        }
+                        flow
+                    });
+                    if flow.is_break() {
+                        active &= !(1 << ray);
+                    }
+                }
+                continue;
+            }
+            if entry.count > 0 {
+                let start = entry.offset as usize;
+                for &primitive in &self.indices[start..start + entry.count as usize] {
+                    for ray in packet::rays_in(rays & active) {
+                        match visit(primitive, ray, t_max[ray]) {
+                            ControlFlow::Continue(distance) => t_max[ray] = distance,
+                            ControlFlow::Break(()) => active &= !(1 << ray),
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let node = &self.nodes[entry.offset as usize];
+            let furthest =
+                packet::rays_in(rays).fold(0.0, |furthest, ray| t_max[ray].max(furthest));
+            // Where the packet's frustum bounds the distance to each child,
+            // and none of them are leaves, rays are only tested until one
+            // enters each child. The untested rays are passed on to every
+            // child which was entered, as coherent rays mostly enter the same
+            // children. Otherwise every ray is tested, so that only the rays
+            // which enter a leaf are tested against its primitives.
+            let (bounds, first_hits) =
+                match packet.frustum_distances(&node.min, &node.max, furthest) {
+                    Some(bounds) => {
+                        let mut lanes = (0..LANES).filter(|&lane| bounds[lane] != f32::INFINITY);
+                        if lanes.clone().next().is_none() {
+                            continue;
                         }
-                        (Some(c), _) => Some(c),
-                        (_, Some(c)) => Some(c),
-                        _ => None,
+                        (bounds, lanes.all(|lane| node.counts[lane] == 0))
+                    }
+                    None => ([0.0; LANES], false),
+                };
+            let mut pending = (0..LANES)
+                .filter(|&lane| bounds[lane] != f32::INFINITY)
+                .fold(0u32, |pending, lane| pending | 1 << lane);
+            let mut untested = rays;
+            let mut hits = [0; LANES];
+            let mut nearest = [f32::INFINITY; LANES];
+            for ray in packet::rays_in(rays) {
+                if first_hits && pending == 0 {
+                    break;
+                }
+                untested &= !(1 << ray);
+                increment_statistic!(statistics::BOUNDING_BOX_TESTS);
+                let simd_ray = packet.simd_ray(ray);
+                let distances = simd::intersect_boxes(simd_ray, &node.min, &node.max, t_max[ray]);
+                for (lane, &distance) in distances.iter().enumerate() {
+                    if distance != f32::INFINITY {
+                        hits[lane] |= 1 << ray;
+                        nearest[lane] = nearest[lane].min(distance);
+                        pending &= !(1 << lane);
+                    }
+                }
+            }
+            if untested != 0 {
+                for lane in 0..LANES {
+                    if hits[lane] != 0 {
+                        hits[lane] |= untested;
+                        nearest[lane] = bounds[lane].min(nearest[lane]);
                     }
+                }
+            }
+            // Push the children hit farthest first, so that the nearest is
+            // popped next.
+            let first = stack_len;
+            for lane in 0..LANES {
+                if hits[lane] == 0 {
+                    continue;
+                }
+                let child = PacketEntry {
+                    distance: nearest[lane],
+                    offset: node.children[lane],
+                    count: node.counts[lane],
+                    rays: hits[lane],
+                };
+                let mut i = stack_len;
+                // SAFETY: every entry below `stack_len` has been written.
+                while i > first && unsafe { stack[i - 1].assume_init().distance } < child.distance {
+                    stack[i] = stack[i - 1];
+                    i -= 1;
+                }
+                stack[i].write(child);
+                stack_len += 1;
+            }
+        }
+    }
+}
+
+struct Builder<'a> {
+    primitives: Primitives<'a>,
+    options: &'a BvhOptions,
+    // The overlap above which spatial splits are considered.
+    min_overlap: f32,
+    // Whether to spread the work for large nodes across threads.
+    parallel: bool,
+    // Subtrees are only built on separate threads above this depth, as
+    // joining them takes a copy.
+    parallel_depth: usize,
+}
+
+impl Builder<'_> {
+    /// Builds a subtree over the references, appending its nodes in
+    /// depth-first order and the objects in its leaves.
+    fn build(
+        &self,
+        references: References,
+        depth: usize,
+        nodes: &mut Vec<BinaryNode>,
+        indices: &mut Vec<Primitive>,
+    ) {
+        let (bounds, centroids) = bounds_of(&references, self.in_parallel(references.len()));
+        let node = nodes.len();
+        nodes.push(BinaryNode {
+            bounds: bounds.clone(),
+            offset: 0,
+            count: 0,
+        });
+
+        // Split until the leaves are small enough, and then for as long as
+        // the surface area heuristic estimates splitting is cheaper than
+        // testing every object in the leaf.
+        let count = references.len();
+        if count > 1 && depth + 1 < MAX_DEPTH {
+            let split = if depth < BALANCED_DEPTH {
+                self.find_split(&references, &bounds, &centroids)
+            } else {
+                Split::half(Axis::X)
+            };
+            if count > self.options.max_leaf_size.clamp(1, u16::MAX as usize)
+                || split.cost < count as f32 * self.options.intersection_cost
+            {
+                let (left, right) = self.apply_split(&split, references, &bounds);
+                if !self.in_parallel(count) || depth >= self.parallel_depth {
+                    self.build(left, depth + 1, nodes, indices);
+                    nodes[node].offset = nodes.len() as u32;
+                    self.build(right, depth + 1, nodes, indices);
+                } else {
+                    // Build large subtrees on separate threads, and then
+                    // shift the offsets within them to where they end up.
+                    let (left, right) = rayon::join(
+                        || self.build_separately(left, depth + 1),
+                        || self.build_separately(right, depth + 1),
+                    );
+                    append(left, nodes, indices);
+                    nodes[node].offset = nodes.len() as u32;
+                    append(right, nodes, indices);
+                }
+                return;
+            }
+        }
+        nodes[node].offset = indices.len() as u32;
+        nodes[node].count = count as u16;
+        indices.extend(references.into_iter().map(|(_bb, index)| index));
+    }
+
+    // Whether the work for a node is worth spreading across threads.
+    fn in_parallel(&self, count: usize) -> bool {
+        self.parallel && count >= PARALLEL_BUILD_SIZE
+    }
+
+    fn build_separately(
+        &self,
+        references: References,
+        depth: usize,
+    ) -> (Vec<BinaryNode>, Vec<Primitive>) {
+        let mut nodes = Vec::new();
+        let mut indices = Vec::new();
+        self.build(references, depth, &mut nodes, &mut indices);
+        (nodes, indices)
+    }
+
+    fn find_split(
+        &self,
+        references: &References,
+        bounds: &BoundingBox,
+        centroids: &BoundingBox,
+    ) -> Split {
+        // Building with spatial splits is slower anyway, so object splits are
+        // compared along every axis. Otherwise only the axis the centroids
+        // spread along most is tried.
+        let axes = if self.options.spatial_splits {
+            vec![Axis::X, Axis::Y, Axis::Z]
+        } else {
+            vec![widest_axis(centroids)]
+        };
+        let mut best = Split::half(axes[0]);
+        for &axis in &axes {
+            if let Some(binning) = Binning::new(axis, centroids, self.options.bins) {
+                let split = self.object_split(&binning, references, bounds);
+                if split.cost < best.cost {
+                    best = split;
+                }
+            }
+        }
+
+        let overlap = match best.kind {
+            SplitKind::Object => BoundingBox::intersection(&best.left, &best.right),
+            _ => bounds.clone(),
+        };
+        if self.options.spatial_splits
+            && !overlap.is_empty()
+            && overlap.surface_area() > self.min_overlap
+        {
+            // Clipping references is expensive, so spatial splits are only
+            // tried along the axis where the node is widest.
+            let axis = widest_axis(bounds);
+            if let Some(binning) = Binning::new(axis, bounds, self.options.bins) {
+                let split = self.spatial_split(&binning, references, bounds);
+                if split.cost < best.cost {
+                    best = split;
+                }
+            }
+        }
+        best
+    }
+
+    fn object_split(
+        &self,
+        binning: &Binning,
+        references: &References,
+        bounds: &BoundingBox,
+    ) -> Split {
+        let bins = binning.fill(
+            references,
+            self.in_parallel(references.len()),
+            |bins, (bb, _)| {
+                let bin = &mut bins[binning.bin(bb.centroid()[binning.axis])];
+                bin.bb.merge(bb);
+                bin.count += 1;
+                bin.exits += 1;
+            },
+        );
+        self.best_split(SplitKind::Object, binning, &bins, bounds)
+    }
+
+    fn spatial_split(
+        &self,
+        binning: &Binning,
+        references: &References,
+        bounds: &BoundingBox,
+    ) -> Split {
+        // Clip each reference to every bin it passes through, and count it in
+        // the bins where it starts and ends.
+        let bins = binning.fill(
+            references,
+            self.in_parallel(references.len()),
+            |bins, (bb, index)| {
+                let first = binning.bin(bb.min[binning.axis]);
+                let last = binning.bin(bb.max[binning.axis]);
+                bins[first].count += 1;
+                bins[last].exits += 1;
+                if first == last {
+                    bins[first].bb.merge(bb);
+                    return;
+                }
+                for (bin, slab) in bins
+                    .iter_mut()
+                    .zip(binning.slabs(bounds))
+                    .take(last + 1)
+                    .skip(first)
+                {
+                    bin.bb.merge(&self.clip(*index, bb, &slab));
+                }
+            },
+        );
+        let split = self.best_split(SplitKind::Spatial, binning, &bins, bounds);
+
+        // Splits which leave either side with every reference may never
+        // terminate, so rule them out.
+        let left = Bin::combined(&bins[..split.index]);
+        let right = Bin::combined(&bins[split.index..]);
+        if left.count == references.len() || right.exits == references.len() {
+            Split::half(binning.axis)
+        } else {
+            split
+        }
+    }
+
+    /// Picks the split between bins with the lowest cost according to the
+    /// surface area heuristic.
+    fn best_split(
+        &self,
+        kind: SplitKind,
+        binning: &Binning,
+        bins: &[Bin],
+        bounds: &BoundingBox,
+    ) -> Split {
+        let mut best = Split::half(binning.axis);
+        for index in 1..bins.len() {
+            let left = Bin::combined(&bins[..index]);
+            let right = Bin::combined(&bins[index..]);
+            if left.count == 0 || right.exits == 0 {
+                continue;
+            }
+            let sah = left.bb.surface_area() * left.count as f32
+                + right.bb.surface_area() * right.exits as f32;
+            let cost = self.options.traversal_cost
+                + self.options.intersection_cost * sah / bounds.surface_area();
+            if cost < best.cost {
+                best = Split {
+                    kind,
+                    axis: binning.axis,
+                    index,
+                    plane: binning.plane(index),
+                    cost,
+                    left: left.bb,
+                    right: right.bb,
+                };
+            }
+        }
+        best
+    }
+
+    fn apply_split(
+        &self,
+        split: &Split,
+        references: References,
+        bounds: &BoundingBox,
+    ) -> (References, References) {
+        let axis = split.axis;
+        let (left, right): (References, References) = match split.kind {
+            SplitKind::Half => {
+                let mut left = references;
+                let right = left.split_off(left.len() / 2);
+                (left, right)
+            }
+            SplitKind::Object => {
+                let is_left =
+                    |(bb, _): &(BoundingBox, Primitive)| bb.centroid()[axis] < split.plane;
+                if !self.in_parallel(references.len()) {
+                    references.into_iter().partition(is_left)
                 } else {
-                    None
+                    references.into_par_iter().partition(is_left)
+                }
+            }
+            SplitKind::Spatial => {
+                let mut left_bounds = bounds.clone();
+                left_bounds.max[axis] = split.plane;
+                let mut right_bounds = bounds.clone();
+                right_bounds.min[axis] = split.plane;
+
+                let mut left = Vec::new();
+                let mut right = Vec::new();
+                for (bb, index) in references {
+                    if bb.max[axis] < split.plane {
+                        left.push((bb, index));
+                    } else if bb.min[axis] >= split.plane {
+                        right.push((bb, index));
+                    } else {
+                        // Objects straddling the plane are referenced from
+                        // both sides, clipped to each. Either side may turn
+                        // out not to contain any of the surface itself.
+                        let clipped = self.clip(index, &bb, &left_bounds);
+                        if !clipped.is_empty() {
+                            left.push((clipped, index));
+                        }
+                        let clipped = self.clip(index, &bb, &right_bounds);
+                        if !clipped.is_empty() {
+                            right.push((clipped, index));
+                        }
+                    }
                 }
+                (left, right)
             }
-            BvhNode::Leaf(obj) => obj.sample(ray),
+        };
+        if left.is_empty() || right.is_empty() {
+            // Rounding moved every reference to one side, so fall back to
+            // splitting evenly.
+            let references = [left, right].concat();
+            return self.apply_split(&Split::half(axis), references, bounds);
         }
+        (left, right)
+    }
+
+    // Bounds the part of a primitive inside both its reference and a box.
+    fn clip(&self, primitive: Primitive, bb: &BoundingBox, bounds: &BoundingBox) -> BoundingBox {
+        self.primitives
+            .clip_bounds(primitive, &BoundingBox::intersection(bb, bounds))
     }
 }
 
-const NUM_BINS: usize = 8;
+/// Collapses the binary subtree rooted at `node` into wide nodes, appended to
+/// `wide`, and returns the index of its root.
+fn collapse(binary: &[BinaryNode], node: usize, wide: &mut Vec<WideNode>) -> u32 {
+    // Gather children by repeatedly opening up the largest interior node, until
+    // either there are enough of them or only leaves remain.
+    let mut children = vec![node];
+    if binary[node].count == 0 {
+        children = vec![node + 1, binary[node].offset as usize];
+    }
+    while children.len() < LANES {
+        let largest = children
+            .iter()
+            .enumerate()
+            .filter(|(_, &child)| binary[child].count == 0)
+            .max_by(|(_, &left), (_, &right)| {
+                float::compare(
+                    &binary[left].bounds.surface_area(),
+                    &binary[right].bounds.surface_area(),
+                )
+            })
+            .map(|(i, _)| i);
+        match largest {
+            Some(i) => {
+                let child = children.swap_remove(i);
+                children.push(child + 1);
+                children.push(binary[child].offset as usize);
+            }
+            None => break,
+        }
+    }
+
+    let index = wide.len();
+    wide.push(WideNode::empty());
+    for (lane, &child) in children.iter().enumerate() {
+        let BinaryNode {
+            bounds,
+            offset,
+            count,
+        } = &binary[child];
+        let offset = if *count > 0 {
+            *offset
+        } else {
+            collapse(binary, child, wide)
+        };
+        let node = &mut wide[index];
+        node.set_bounds(lane, bounds);
+        node.children[lane] = offset;
+        node.counts[lane] = *count;
+    }
+    index as u32
+}
 
 #[derive(Debug)]
 struct Bin {
     bb: BoundingBox,
+    // The number of references which start in this bin, and so are placed on
+    // the left of any split after it.
     count: usize,
+    // The number of references which end in this bin, and so are placed on
+    // the right of any split before it.
+    exits: usize,
 }
 
 impl Bin {
@@ -87,61 +1302,184 @@ impl Bin {
         Bin {
             bb: BoundingBox::empty(),
             count: 0,
+            exits: 0,
         }
     }
 
+    fn merge(&mut self, other: &Self) {
+        self.bb.merge(&other.bb);
+        self.count += other.count;
+        self.exits += other.exits;
+    }
+
     fn combined(bins: &[Self]) -> Self {
         let mut result = Bin::empty();
         for bin in bins {
-            result.bb.merge(&bin.bb);
-            result.count += bin.count;
+            result.merge(bin);
         }
         result
     }
 }
 
-#[allow(clippy::type_complexity)]
-fn partition(
-    objects: Vec<(BoundingBox, Object)>,
-) -> (Vec<(BoundingBox, Object)>, Vec<(BoundingBox, Object)>) {
-    let centroids = objects.iter().map(|(bb, _obj)| bb.centroid()).fold(
-        BoundingBox::empty(),
-        |mut bb, centroid| {
-            bb.add_point(centroid);
-            bb
-        },
-    );
+/// Evenly sized bins along an axis.
+struct Binning {
+    axis: Axis,
+    min: f32,
+    size: f32,
+    count: usize,
+}
 
-    let axis = widest_axis(&centroids);
-    let (axis_min, axis_max) = (centroids.min[axis], centroids.max[axis]);
-    let bin_size = (axis_max - axis_min) / NUM_BINS as f32;
+impl Binning {
+    /// Divides the extent of the box along the axis into bins, unless it is
+    /// too thin to split.
+    fn new(axis: Axis, bb: &BoundingBox, count: usize) -> Option<Self> {
+        let count = count.max(2);
+        let size = (bb.max[axis] - bb.min[axis]) / count as f32;
+        if size < float::EPSILON {
+            return None;
+        }
+        Some(Binning {
+            axis,
+            min: bb.min[axis],
+            size,
+            count,
+        })
+    }
 
-    if bin_size < float::EPSILON {
-        let mut left = objects;
-        let right = left.split_off(left.len() / 2);
-        (left, right)
-    } else {
-        let mut assignments = Vec::with_capacity(objects.len());
-        let mut bins: Vec<_> = (0..NUM_BINS).map(|_| Bin::empty()).collect();
-        for (bb, _) in objects.iter() {
-            let relative_pos = bb.centroid()[axis] - axis_min;
-            let bin = (relative_pos / bin_size - float::EPSILON) as usize;
-            assignments.push(bin);
-            bins[bin].bb.merge(bb);
-            bins[bin].count += 1;
-        }
-
-        let partition_idx = best_partition(&bins);
-        let mut left = Vec::new();
-        let mut right = Vec::new();
-        for (i, obj) in objects.into_iter().enumerate() {
-            if assignments[i] < partition_idx {
-                left.push(obj);
-            } else {
-                right.push(obj);
+    fn empty_bins(&self) -> Vec<Bin> {
+        (0..self.count).map(|_| Bin::empty()).collect()
+    }
+
+    /// Adds each reference to the bins.
+    fn fill<F>(&self, references: &References, parallel: bool, add: F) -> Vec<Bin>
+    where
+        F: Fn(&mut [Bin], &(BoundingBox, Primitive)) + Sync,
+    {
+        if !parallel {
+            let mut bins = self.empty_bins();
+            for reference in references {
+                add(&mut bins, reference);
             }
+            return bins;
+        }
+        references
+            .par_iter()
+            .fold(
+                || self.empty_bins(),
+                |mut bins, reference| {
+                    add(&mut bins, reference);
+                    bins
+                },
+            )
+            .reduce(
+                || self.empty_bins(),
+                |mut left, right| {
+                    for (bin, other) in left.iter_mut().zip(&right) {
+                        bin.merge(other);
+                    }
+                    left
+                },
+            )
+    }
+
+    fn bin(&self, x: f32) -> usize {
+        (((x - self.min) / self.size) as usize).min(self.count - 1)
+    }
+
+    // The plane between bin `index` and the one before it.
+    fn plane(&self, index: usize) -> f32 {
+        self.min + self.size * index as f32
+    }
+
+    // The part of a box which lies within each bin.
+    fn slabs<'a>(&'a self, bb: &'a BoundingBox) -> impl Iterator<Item = BoundingBox> + 'a {
+        (0..self.count).map(move |index| {
+            let mut slab = bb.clone();
+            slab.min[self.axis] = self.plane(index);
+            slab.max[self.axis] = self.plane(index + 1);
+            slab
+        })
+    }
+}
+
+type References = Vec<(BoundingBox, Primitive)>;
+
+/// Finds the bounds of the references, and of their centroids.
+fn bounds_of(references: &References, parallel: bool) -> (BoundingBox, BoundingBox) {
+    let add = |(mut bounds, mut centroids): (BoundingBox, BoundingBox),
+               (bb, _): &(BoundingBox, Primitive)| {
+        bounds.merge(bb);
+        centroids.add_point(bb.centroid());
+        (bounds, centroids)
+    };
+    let empty = || (BoundingBox::empty(), BoundingBox::empty());
+    if !parallel {
+        return references.iter().fold(empty(), add);
+    }
+    references.par_iter().fold(empty, add).reduce(
+        empty,
+        |(mut bounds, mut centroids), (other_bounds, other_centroids)| {
+            bounds.merge(&other_bounds);
+            centroids.merge(&other_centroids);
+            (bounds, centroids)
+        },
+    )
+}
+
+/// Appends a subtree built separately, shifting its offsets to match.
+fn append(
+    (subtree, subtree_indices): (Vec<BinaryNode>, Vec<Primitive>),
+    nodes: &mut Vec<BinaryNode>,
+    indices: &mut Vec<Primitive>,
+) {
+    let (node_base, index_base) = (nodes.len() as u32, indices.len() as u32);
+    nodes.extend(subtree.into_iter().map(|mut node| {
+        node.offset += if node.count > 0 {
+            index_base
+        } else {
+            node_base
+        };
+        node
+    }));
+    indices.extend(subtree_indices);
+}
+
+#[derive(Copy, Clone, Debug)]
+enum SplitKind {
+    /// Objects are divided by which side of the plane their centroid is on.
+    Object,
+    /// Objects are divided by the plane, and referenced on both sides if they
+    /// straddle it.
+    Spatial,
+    /// Objects are divided evenly, as no useful plane could be found.
+    Half,
+}
+
+#[derive(Debug)]
+struct Split {
+    kind: SplitKind,
+    axis: Axis,
+    // The bin the right side starts at.
+    index: usize,
+    plane: f32,
+    // The estimated cost of intersecting a ray with the split node.
+    cost: f32,
+    // The bounds of either side.
+    left: BoundingBox,
+    right: BoundingBox,
+}
+
+impl Split {
+    fn half(axis: Axis) -> Self {
+        Split {
+            kind: SplitKind::Half,
+            axis,
+            index: 0,
+            plane: 0.0,
+            cost: f32::INFINITY,
+            left: BoundingBox::empty(),
+            right: BoundingBox::empty(),
         }
-        (left, right)
     }
 }
 
@@ -156,18 +1494,153 @@ fn widest_axis(bb: &BoundingBox) -> Axis {
     }
 }
 
-fn best_partition(bins: &[Bin]) -> usize {
-    let mut min_partition = 0;
-    let mut min_sah = std::f32::INFINITY;
-    for partition in 1..(NUM_BINS - 1) {
-        let left = Bin::combined(&bins[..partition]);
-        let right = Bin::combined(&bins[partition..]);
-        let sah = left.bb.surface_area() * left.count as f32
-            + right.bb.surface_area() * right.count as f32;
-        if sah < min_sah {
-            min_partition = partition;
-            min_sah = sah;
+#[cfg(test)]
+mod tests {
+    use palette::LinSrgb;
+
+    use super::*;
+    use crate::material::{Color, Material};
+    use crate::mesh::Face;
+    use crate::surface::{Plane, Sphere};
+    use crate::types::Vector3;
+
+    fn sphere(x: f32, y: f32) -> Object {
+        let material = Material::diffuse(Color::solid(LinSrgb::new(1.0, 1.0, 1.0)));
+        Object::new(Sphere::new(Point3::new(x, y, 0.0), 1.0), material)
+    }
+
+    // A row of spheres along the x axis, three apart.
+    fn row(count: usize, rebuild_threshold: Option<f32>) -> BoundingVolumeHierarchy {
+        let objects = (0..count).map(|i| sphere(3.0 * i as f32, 0.0)).collect();
+        let options = BvhOptions {
+            max_leaf_size: 1,
+            rebuild_threshold,
+            ..BvhOptions::default()
+        };
+        BoundingVolumeHierarchy::with_options(objects, Vec::new(), &options)
+    }
+
+    fn distance_towards(bvh: &BoundingVolumeHierarchy, x: f32, y: f32) -> Option<f32> {
+        let ray = Ray::new(Point3::new(x, y, -10.0), Vector3::new(0.0, 0.0, 1.0));
+        bvh.sample(ray).map(|sample| sample.intersection.distance)
+    }
+
+    #[test]
+    fn refit_follows_moved_objects() {
+        let mut bvh = row(4, None);
+        bvh.objects_mut()[3] = sphere(9.0, 10.0);
+        assert!(!bvh.refit());
+        let distance = distance_towards(&bvh, 9.0, 10.0).unwrap();
+        assert!((distance - 9.0).abs() < 1e-3);
+        assert!(distance_towards(&bvh, 9.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn refit_rebuilds_once_the_tree_degrades() {
+        let mut bvh = row(16, Some(1.1));
+        // Swapping the ends of the row leaves the root's bounds alone, but
+        // makes both halves of the tree span all of it.
+        bvh.objects_mut().swap(0, 15);
+        bvh.objects_mut()[0] = sphere(45.0, 0.0);
+        bvh.objects_mut()[15] = sphere(0.0, 0.0);
+        assert!(bvh.refit());
+        for i in 0..16 {
+            let distance = distance_towards(&bvh, 3.0 * i as f32, 0.0).unwrap();
+            assert!((distance - 9.0).abs() < 1e-3);
+        }
+    }
+
+    // Checks that tracing rays as a packet finds what tracing them one at a
+    // time does.
+    fn assert_packet_matches_rays(bvh: &BoundingVolumeHierarchy, rays: Vec<Ray>) {
+        let distances: Vec<_> = rays
+            .iter()
+            .map(|&ray| bvh.sample(ray).map(|sample| sample.intersection.distance))
+            .collect();
+        let packet = RayPacket::new(rays.clone());
+        let samples = bvh.sample_packet(&packet);
+        for (sample, &distance) in samples.iter().zip(&distances) {
+            assert_eq!(sample.map(|sample| sample.intersection.distance), distance);
         }
+        // Half of the rays stop short of their hits.
+        let t_max: Vec<_> = distances
+            .iter()
+            .enumerate()
+            .map(|(i, distance)| distance.map_or(f32::INFINITY, |d| d * (i % 2 + 1) as f32 * 0.75))
+            .collect();
+        let occluded = bvh.occluded_packet(&packet, &t_max);
+        for ((&ray, &t_max), occluded) in rays.iter().zip(&t_max).zip(occluded) {
+            assert_eq!(occluded, bvh.occluded(ray, t_max));
+        }
+    }
+
+    #[test]
+    fn packets_match_single_rays() {
+        // A grid of spheres at varying depths, a mesh in front of some of
+        // them and an unbounded plane behind them all.
+        let mut objects: Vec<_> = (0..64)
+            .map(|i| {
+                let material = Material::diffuse(Color::solid(LinSrgb::new(1.0, 1.0, 1.0)));
+                let center =
+                    Point3::new(3.0 * (i % 8) as f32, 3.0 * (i / 8) as f32, (i % 3) as f32);
+                Object::new(Sphere::new(center, 1.0), material)
+            })
+            .collect();
+        let material = Material::diffuse(Color::solid(LinSrgb::new(1.0, 1.0, 1.0)));
+        objects.push(Object::new(
+            Plane::new(Point3::new(0.0, 0.0, 10.0), Vector3::new(0.0, 0.0, -1.0)),
+            material.clone(),
+        ));
+        let positions = vec![
+            Point3::new(2.0, 2.0, -2.0),
+            Point3::new(12.0, 2.0, -2.0),
+            Point3::new(2.0, 12.0, -3.0),
+            Point3::new(12.0, 12.0, -3.0),
+        ];
+        let faces = vec![
+            Face {
+                vertices: [0, 1, 2],
+                material: 0,
+            },
+            Face {
+                vertices: [2, 1, 3],
+                material: 0,
+            },
+        ];
+        let mesh = TriangleMesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            faces,
+            vec![material],
+        );
+        let bvh = BoundingVolumeHierarchy::new(objects, vec![mesh]);
+
+        // A coherent packet, like one for a tile of pixels.
+        let origin = Point3::new(10.5, 10.5, -30.0);
+        let coherent = (0..64)
+            .map(|i| {
+                let target = Point3::new((i % 8) as f32 * 3.1, (i / 8) as f32 * 3.1, 0.0);
+                Ray::new(origin, target - origin)
+            })
+            .collect();
+        assert_packet_matches_rays(&bvh, coherent);
+
+        // An incoherent one, like one for reflections, whose rays go every
+        // which way from all over the grid.
+        let scatter = |i: u32, k: u32| ((i * 7919 + k * 104729) % 1000) as f32 / 1000.0;
+        let incoherent = (0..64)
+            .map(|i| {
+                let origin = Point3::new(21.0 * scatter(i, 0), 21.0 * scatter(i, 1), -5.0);
+                let dir = Vector3::new(
+                    scatter(i, 2) - 0.5,
+                    scatter(i, 3) - 0.5,
+                    scatter(i, 4) - 0.3,
+                );
+                Ray::new(origin, dir)
+            })
+            .collect();
+        assert_packet_matches_rays(&bvh, incoherent);
     }
-    min_partition
 }
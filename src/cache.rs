@@ -0,0 +1,396 @@
+// A binary cache of the parts of a scene which are slow to compile, namely
+// parsed meshes and the BVH over every object. It is kept in a file next to
+// the scene, and keyed by a hash of everything those parts depend on, so that
+// a stale cache is ignored and replaced when the scene's sources change.
+//
+// Values are stored in little endian order, one after another, with the
+// length before each list. Lists of plain data, such as mesh vertices and BVH
+// nodes, are stored as they are laid out in memory instead, padded to their
+// alignment, so that they are used in place in the mapped file rather than
+// decoded or copied. That layout depends on the machine, which the key
+// accounts for.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use memmap2::Mmap;
+
+use crate::simd::LANES;
+
+const MAGIC: &[u8; 8] = b"RAYSCACH";
+// Bump this whenever the format of any cached value changes.
+const VERSION: u32 = 5;
+
+/// Builds the key a cache is stored under. The hash is only stable for a
+/// given build of the renderer, which at worst costs rebuilding the cache.
+pub struct CacheKey(DefaultHasher);
+
+impl CacheKey {
+    pub fn new() -> Self {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u32(VERSION);
+        // The width of BVH nodes depends on the `bvh8` feature.
+        hasher.write_usize(LANES);
+        hasher.write_u8(cfg!(target_endian = "little") as u8);
+        CacheKey(hasher)
+    }
+
+    pub fn add_bytes(&mut self, bytes: &[u8]) {
+        self.0.write_usize(bytes.len());
+        self.0.write(bytes);
+    }
+
+    pub fn add_f32(&mut self, value: f32) {
+        self.0.write_u32(value.to_bits());
+    }
+
+    pub fn add_usize(&mut self, value: usize) {
+        self.0.write_usize(value);
+    }
+
+    pub fn add_file(&mut self, path: &Path) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut len = 0;
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            self.0.write(buf);
+            let consumed = buf.len();
+            reader.consume(consumed);
+            len += consumed;
+        }
+        self.0.write_usize(len);
+        Ok(())
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl Default for CacheKey {
+    fn default() -> Self {
+        CacheKey::new()
+    }
+}
+
+/// A value which can be stored in a cache.
+pub trait Cacheable: Sized {
+    fn write(&self, cache: &mut CacheWriter);
+    fn read(cache: &mut CacheReader) -> Option<Self>;
+}
+
+/// A cache file, mapped into memory.
+pub struct CacheFile {
+    map: Arc<Mmap>,
+}
+
+impl CacheFile {
+    /// Opens the cache at `path` if it exists and was stored under `key`.
+    pub fn open(path: &Path, key: &CacheKey) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        // SAFETY: caches are only ever replaced by renaming a new file over
+        // them, never modified in place, so the mapped file doesn't change.
+        let map = Arc::new(unsafe { Mmap::map(&file) }.ok()?);
+        let cache = CacheFile { map };
+        let mut reader = CacheReader {
+            bytes: &cache.map,
+            map: &cache.map,
+        };
+        let current = reader.take(MAGIC.len())? == MAGIC
+            && u32::read(&mut reader)? == VERSION
+            && u64::read(&mut reader)? == key.finish();
+        current.then_some(cache)
+    }
+
+    /// Reads the values stored after the header.
+    pub fn reader(&self) -> CacheReader<'_> {
+        let header = MAGIC.len() + 4 + 8;
+        CacheReader {
+            bytes: &self.map[header..],
+            map: &self.map,
+        }
+    }
+}
+
+pub struct CacheReader<'a> {
+    // What's left to read of the map.
+    bytes: &'a [u8],
+    map: &'a Arc<Mmap>,
+}
+
+impl<'a> CacheReader<'a> {
+    pub fn read<T: Cacheable>(&mut self) -> Option<T> {
+        T::read(self)
+    }
+
+    pub fn read_vec<T: Cacheable>(&mut self) -> Option<Vec<T>> {
+        let len = self.read::<u64>()? as usize;
+        // Every value takes at least a byte, so a corrupt length is caught
+        // before allocating for it.
+        if len > self.bytes.len() {
+            return None;
+        }
+        (0..len).map(|_| self.read()).collect()
+    }
+
+    /// Reads a list written by `CacheWriter::write_pod_slice`, which stays
+    /// in the mapped file rather than being copied out of it.
+    pub fn read_mapped_vec<T: Pod + Sync>(&mut self) -> Option<MappedVec<T>> {
+        let len = self.read::<u64>()? as usize;
+        let position = self.map.len() - self.bytes.len();
+        self.take(position.next_multiple_of(std::mem::align_of::<T>()) - position)?;
+        let bytes = self.take(len.checked_mul(std::mem::size_of::<T>())?)?;
+        // The map starts on a page boundary, so this only fails if the cache
+        // is corrupt.
+        let values: &[T] = bytemuck::try_cast_slice(bytes).ok()?;
+        Some(MappedVec(Storage::Mapped {
+            _map: self.map.clone(),
+            values: values.as_ptr(),
+            len,
+        }))
+    }
+
+    /// Checks that every value in the cache has been read.
+    pub fn is_done(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+}
+
+/// A list of plain values, which either lives in a mapped cache file or is
+/// owned. Mutating a mapped list copies it out of the file first.
+pub struct MappedVec<T>(Storage<T>);
+
+enum Storage<T> {
+    Owned(Vec<T>),
+    Mapped {
+        // Keeps the values mapped.
+        _map: Arc<Mmap>,
+        values: *const T,
+        len: usize,
+    },
+}
+
+// SAFETY: mapped values are never written to, and the map is shared
+// immutably between threads like any other read-only data.
+unsafe impl<T: Send + Sync> Send for MappedVec<T> {}
+unsafe impl<T: Sync> Sync for MappedVec<T> {}
+
+impl<T> Deref for MappedVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.0 {
+            Storage::Owned(values) => values,
+            // SAFETY: the values were checked to be aligned and in bounds
+            // when read, and the map they point into is kept alive.
+            Storage::Mapped { values, len, .. } => unsafe {
+                std::slice::from_raw_parts(*values, *len)
+            },
+        }
+    }
+}
+
+impl<T: Copy> DerefMut for MappedVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        if let Storage::Mapped { .. } = self.0 {
+            self.0 = Storage::Owned(self.to_vec());
+        }
+        match &mut self.0 {
+            Storage::Owned(values) => values,
+            Storage::Mapped { .. } => unreachable!(),
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for MappedVec<T> {
+    fn from(values: Vec<T>) -> Self {
+        MappedVec(Storage::Owned(values))
+    }
+}
+
+impl<T> FromIterator<T> for MappedVec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        MappedVec::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl<T> Default for MappedVec<T> {
+    fn default() -> Self {
+        MappedVec::from(Vec::new())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for MappedVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+pub struct CacheWriter {
+    bytes: Vec<u8>,
+}
+
+impl CacheWriter {
+    pub fn new(key: &CacheKey) -> Self {
+        let mut cache = CacheWriter {
+            bytes: MAGIC.to_vec(),
+        };
+        cache.write(&VERSION);
+        cache.write(&key.finish());
+        cache
+    }
+
+    pub fn write<T: Cacheable>(&mut self, value: &T) {
+        value.write(self);
+    }
+
+    pub fn write_slice<T: Cacheable>(&mut self, values: &[T]) {
+        self.write(&(values.len() as u64));
+        for value in values {
+            self.write(value);
+        }
+    }
+
+    /// Writes a list of plain values as their bytes in memory, aligned for
+    /// their type so that they can be used in place once mapped.
+    pub fn write_pod_slice<T: Pod>(&mut self, values: &[T]) {
+        self.write(&(values.len() as u64));
+        let aligned = self.bytes.len().next_multiple_of(std::mem::align_of::<T>());
+        self.bytes.resize(aligned, 0);
+        self.bytes.extend_from_slice(bytemuck::cast_slice(values));
+    }
+
+    /// Stores the cache at `path`, replacing any older cache there.
+    pub fn save(self, path: &Path) -> io::Result<()> {
+        // Write to a temporary file first, so that a cache which is mapped
+        // by another process is never modified.
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, &self.bytes)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+macro_rules! cacheable_number {
+    ($($t:ty),*) => {
+        $(
+            impl Cacheable for $t {
+                fn write(&self, cache: &mut CacheWriter) {
+                    cache.bytes.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn read(cache: &mut CacheReader) -> Option<Self> {
+                    Some(<$t>::from_le_bytes(cache.take_array()?))
+                }
+            }
+        )*
+    };
+}
+
+cacheable_number!(u32, u64, f32);
+
+impl Cacheable for String {
+    fn write(&self, cache: &mut CacheWriter) {
+        cache.write(&(self.len() as u64));
+        cache.bytes.extend_from_slice(self.as_bytes());
+    }
+
+    fn read(cache: &mut CacheReader) -> Option<Self> {
+        let len = cache.read::<u64>()? as usize;
+        String::from_utf8(cache.take(len)?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Point3;
+
+    // A directory of its own for each test, as tests run in parallel.
+    fn directory(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rays-cache-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = directory("round-trip").join("scene.cache");
+        let key = CacheKey::new();
+        let points = vec![Point3::new(1.0, 2.0, 3.0), Point3::new(4.0, 5.0, 6.0)];
+        let mut writer = CacheWriter::new(&key);
+        // An odd number of bytes before the points, which have to be padded.
+        writer.write(&"odd".to_string());
+        writer.write_pod_slice(&points);
+        writer.write(&7u32);
+        writer.save(&path).unwrap();
+
+        let file = CacheFile::open(&path, &key).unwrap();
+        let mut reader = file.reader();
+        assert_eq!(reader.read::<String>().unwrap(), "odd");
+        let mapped = reader.read_mapped_vec::<Point3>().unwrap();
+        assert_eq!(reader.read::<u32>(), Some(7));
+        assert!(reader.is_done());
+        // The points outlive the file they are mapped from.
+        drop(file);
+        assert_eq!(mapped.len(), 2);
+        assert_eq!(mapped[1].y(), 5.0);
+    }
+
+    #[test]
+    fn changed_source_invalidates_cache() {
+        let dir = directory("invalidation");
+        let (source, path) = (dir.join("mesh.obj"), dir.join("scene.cache"));
+        let key = |contents: &str| {
+            fs::write(&source, contents).unwrap();
+            let mut key = CacheKey::new();
+            key.add_file(&source).unwrap();
+            key
+        };
+        let old = key("v 0 0 0\n");
+        CacheWriter::new(&old).save(&path).unwrap();
+        assert!(CacheFile::open(&path, &old).is_some());
+        let new = key("v 0 0 1\n");
+        assert!(CacheFile::open(&path, &new).is_none());
+    }
+
+    #[test]
+    fn mapped_vec_copies_on_write() {
+        let path = directory("copy-on-write").join("scene.cache");
+        let key = CacheKey::new();
+        let mut writer = CacheWriter::new(&key);
+        writer.write_pod_slice(&[1u32, 2, 3]);
+        writer.save(&path).unwrap();
+
+        let file = CacheFile::open(&path, &key).unwrap();
+        let mut mapped = file.reader().read_mapped_vec::<u32>().unwrap();
+        mapped[0] = 4;
+        assert_eq!(*mapped, [4, 2, 3]);
+        let mut again = file.reader().read_mapped_vec::<u32>().unwrap();
+        assert_eq!(*again, [1, 2, 3]);
+        again[2] = 0;
+        assert_eq!(*again, [1, 2, 0]);
+    }
+}
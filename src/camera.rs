@@ -21,6 +21,12 @@ impl Camera {
         }
     }
 
+    /// The angle between the rays through neighbouring pixels, for an image
+    /// the given number of pixels wide.
+    pub fn pixel_spread(&self, width: u32) -> f32 {
+        1.0 / (self.z * width as f32)
+    }
+
     pub fn get_ray(&self, x: f32, y: f32) -> Ray {
         // Create a view matrix pointing along the z axis, then rotate it to
         // face down the camera ray. Flip the x axis because we calculate left
@@ -0,0 +1,194 @@
+use crate::bounds::BoundingBox;
+use crate::float;
+use crate::ray::Ray;
+use crate::surface::{Intersection, Solid, Span, Surface};
+
+#[derive(Copy, Clone, Debug)]
+pub enum Operation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl Operation {
+    fn contains(self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            Operation::Union => in_left || in_right,
+            Operation::Intersection => in_left && in_right,
+            Operation::Difference => in_left && !in_right,
+        }
+    }
+}
+
+/// A boolean combination of two solids.
+#[derive(Debug)]
+pub struct Csg {
+    operation: Operation,
+    left: Box<dyn Solid>,
+    right: Box<dyn Solid>,
+}
+
+impl Csg {
+    pub fn new(operation: Operation, left: Box<dyn Solid>, right: Box<dyn Solid>) -> Self {
+        Csg {
+            operation,
+            left,
+            right,
+        }
+    }
+}
+
+impl Surface for Csg {
+    fn bounding_box(&self) -> BoundingBox {
+        let left = self.left.bounding_box();
+        let right = self.right.bounding_box();
+        match self.operation {
+            Operation::Union => BoundingBox::union(&left, &right),
+            Operation::Intersection => {
+                let bounds = BoundingBox::intersection(&left, &right);
+                if bounds.is_empty() {
+                    // Solids which can't overlap intersect nowhere, but the
+                    // BVH tests objects without finite bounds against every
+                    // ray, so they get a single point instead.
+                    let mut point = BoundingBox::empty();
+                    point.add_point(bounds.centroid());
+                    point
+                } else {
+                    bounds
+                }
+            }
+            Operation::Difference => left,
+        }
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        self.spans(ray)
+            .into_iter()
+            .flat_map(|span| [span.enter, span.exit])
+            .find(|intersection| intersection.distance > 0.0)
+    }
+}
+
+impl Solid for Csg {
+    fn spans(&self, ray: Ray) -> Vec<Span> {
+        // Walk every boundary of both solids in order, tracking which of them
+        // we are inside of. The result's boundaries are wherever that changes
+        // whether we are inside the combined solid.
+        let mut boundaries: Vec<(bool, Intersection)> = Vec::new();
+        for span in self.left.spans(ray) {
+            boundaries.push((true, span.enter));
+            boundaries.push((true, span.exit));
+        }
+        for span in self.right.spans(ray) {
+            boundaries.push((false, span.enter));
+            boundaries.push((false, span.exit));
+        }
+        boundaries.sort_by(|(_, left), (_, right)| float::compare(&left.distance, &right.distance));
+
+        let mut spans = Vec::new();
+        let mut enter = None;
+        let (mut in_left, mut in_right) = (false, false);
+        for (is_left, mut boundary) in boundaries {
+            if is_left {
+                in_left = !in_left;
+            } else {
+                in_right = !in_right;
+                if let Operation::Difference = self.operation {
+                    // The inside of the subtracted solid is now outside.
+                    boundary.normal = -boundary.normal;
+                    boundary.geometric_normal = -boundary.geometric_normal;
+                    boundary.tangent = -boundary.tangent;
+                }
+            }
+
+            let inside = self.operation.contains(in_left, in_right);
+            match enter {
+                None if inside => enter = Some(boundary),
+                Some(entered) if !inside => {
+                    spans.push(Span {
+                        enter: entered,
+                        exit: boundary,
+                    });
+                    enter = None;
+                }
+                _ => (),
+            }
+        }
+        spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::surface::Sphere;
+    use crate::types::{Point3, Vector3};
+
+    // Two unit spheres, one unit apart along x.
+    fn csg(operation: Operation) -> Csg {
+        Csg::new(
+            operation,
+            Box::new(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0)),
+            Box::new(Sphere::new(Point3::new(1.0, 0.0, 0.0), 1.0)),
+        )
+    }
+
+    // The distances at which a ray along x, from five units away, enters and
+    // exits the solid.
+    fn spans(solid: &dyn Solid) -> Vec<(f32, f32)> {
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        solid
+            .spans(ray)
+            .into_iter()
+            .map(|span| (span.enter.distance, span.exit.distance))
+            .collect()
+    }
+
+    fn assert_spans(actual: Vec<(f32, f32)>, expected: &[(f32, f32)]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                (a.0 - e.0).abs() < 1e-4 && (a.1 - e.1).abs() < 1e-4,
+                "{:?}",
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn union() {
+        assert_spans(spans(&csg(Operation::Union)), &[(4.0, 7.0)]);
+    }
+
+    #[test]
+    fn intersection() {
+        assert_spans(spans(&csg(Operation::Intersection)), &[(5.0, 6.0)]);
+    }
+
+    #[test]
+    fn difference() {
+        let difference = csg(Operation::Difference);
+        assert_spans(spans(&difference), &[(4.0, 5.0)]);
+
+        // The exit is where the ray enters the subtracted sphere, which faces
+        // back into the carved hole.
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let exit = difference.spans(ray)[0].exit;
+        assert!((exit.normal.x() - 1.0).abs() < 1e-4);
+        assert!((exit.geometric_normal.x() - 1.0).abs() < 1e-4);
+        let carved = Sphere::new(Point3::new(1.0, 0.0, 0.0), 1.0);
+        let tangent = carved.intersect(ray).unwrap().tangent;
+        assert!((exit.tangent.dot(tangent) + 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn disjoint_intersection_is_bounded() {
+        let disjoint = Csg::new(
+            Operation::Intersection,
+            Box::new(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0)),
+            Box::new(Sphere::new(Point3::new(4.0, 0.0, 0.0), 1.0)),
+        );
+        assert!(disjoint.bounding_box().is_finite());
+        assert!(spans(&disjoint).is_empty());
+    }
+}
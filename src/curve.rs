@@ -0,0 +1,242 @@
+use crate::bounds::BoundingBox;
+use crate::float;
+use crate::ray::Ray;
+use crate::surface::{Intersection, Surface};
+use crate::texture::TextureCoords;
+use crate::types::{Point3, Vector3};
+use crate::{increment_statistic, statistics};
+
+#[derive(Copy, Clone, Debug)]
+pub enum CurveShape {
+    /// A flat strip which always faces the incoming ray.
+    Ribbon,
+    /// A ribbon shaded as though it were a tube.
+    Cylinder,
+}
+
+/// A cubic Bézier curve swept with a width that varies linearly along its
+/// length, intersected by recursive subdivision in the ray's coordinate space
+/// (Nakamaru and Ohno, "Ray Tracing for Curves Primitive").
+#[derive(Copy, Clone, Debug)]
+pub struct Curve {
+    control_points: [Point3; 4],
+    widths: (f32, f32),
+    shape: CurveShape,
+    max_depth: u32,
+}
+
+/// The nearest hit found so far while subdividing a curve.
+struct CurveHit {
+    distance: f32,
+    u: f32,
+    v: f32,
+    // Offset from the center of the curve to the ray, relative to the curve's
+    // half width, in ray space.
+    offset: Vector3,
+}
+
+impl Curve {
+    pub fn new(control_points: [Point3; 4], widths: (f32, f32), shape: CurveShape) -> Self {
+        // Subdivide until the curve is within a small fraction of its width of
+        // the line segments approximating it.
+        let cp = control_points.map(|p| p - Point3::origin());
+        let l0 = (0..2)
+            .flat_map(|i| {
+                let d = cp[i] - 2.0 * cp[i + 1] + cp[i + 2];
+                [d.x().abs(), d.y().abs(), d.z().abs()]
+            })
+            .fold(0.0, float::max);
+        let epsilon = float::max(widths.0, widths.1) * 0.05;
+        let depth = (2f32.sqrt() * 6.0 * l0 / (8.0 * epsilon)).log2() / 2.0;
+        Curve {
+            control_points,
+            widths,
+            shape,
+            max_depth: depth.clamp(0.0, 10.0) as u32,
+        }
+    }
+
+    fn width(&self, u: f32) -> f32 {
+        lerp_f32(u, self.widths.0, self.widths.1)
+    }
+
+    fn subdivide(
+        &self,
+        cp: &[Vector3; 4],
+        u0: f32,
+        u1: f32,
+        depth: u32,
+        best: &mut Option<CurveHit>,
+    ) {
+        // Cull segments whose bounds, padded by the curve width, miss the ray.
+        let max_distance = best.as_ref().map_or(f32::INFINITY, |hit| hit.distance);
+        let half_width = float::max(self.width(u0), self.width(u1)) / 2.0;
+        for axis in 0..3 {
+            let min = cp.iter().map(|p| p[axis]).fold(f32::INFINITY, float::min) - half_width;
+            let max = cp
+                .iter()
+                .map(|p| p[axis])
+                .fold(f32::NEG_INFINITY, float::max)
+                + half_width;
+            let (ray_min, ray_max) = if axis == 2 {
+                (0.0, max_distance)
+            } else {
+                (0.0, 0.0)
+            };
+            if max < ray_min || min > ray_max {
+                return;
+            }
+        }
+
+        if depth > 0 {
+            let split = split_bezier(cp);
+            let um = (u0 + u1) / 2.0;
+            self.subdivide(&split[0], u0, um, depth - 1, best);
+            self.subdivide(&split[1], um, u1, depth - 1, best);
+            return;
+        }
+
+        // Test the ray against the perpendicular planes at either end of the
+        // segment, so that adjacent segments don't both report the hit.
+        let edge = (cp[1].y() - cp[0].y()) * -cp[0].y() + cp[0].x() * (cp[0].x() - cp[1].x());
+        if edge < 0.0 {
+            return;
+        }
+        let edge = (cp[2].y() - cp[3].y()) * -cp[3].y() + cp[3].x() * (cp[3].x() - cp[2].x());
+        if edge < 0.0 {
+            return;
+        }
+
+        // Approximate the segment as a line to find the closest parameter to
+        // the ray.
+        let segment = Vector3::new(cp[3].x() - cp[0].x(), cp[3].y() - cp[0].y(), 0.0);
+        let denom = segment.norm_squared();
+        if denom == 0.0 {
+            return;
+        }
+        let w = (Vector3::new(-cp[0].x(), -cp[0].y(), 0.0).dot(segment) / denom).clamp(0.0, 1.0);
+        let u = lerp_f32(w, u0, u1).clamp(u0, u1);
+        let half_width = self.width(u) / 2.0;
+
+        let (center, derivative) = eval_bezier(cp, w);
+        let distance_squared = center.x() * center.x() + center.y() * center.y();
+        if distance_squared > half_width * half_width {
+            return;
+        }
+        if center.z() <= 0.0 || center.z() > max_distance {
+            return;
+        }
+        // Measure v across the width of the curve, from one edge to the other.
+        let relative = distance_squared.sqrt() / half_width;
+        let side = derivative.x() * -center.y() + center.x() * derivative.y();
+        *best = Some(CurveHit {
+            distance: center.z(),
+            u,
+            v: if side > 0.0 {
+                0.5 + relative / 2.0
+            } else {
+                0.5 - relative / 2.0
+            },
+            offset: Vector3::new(-center.x(), -center.y(), 0.0) / half_width,
+        });
+    }
+}
+
+impl Surface for Curve {
+    fn bounding_box(&self) -> BoundingBox {
+        let half_width = float::max(self.widths.0, self.widths.1) / 2.0;
+        let padding = Vector3::new(half_width, half_width, half_width);
+        let mut bb = BoundingBox::empty();
+        for p in self.control_points {
+            bb.add_point(p - padding);
+            bb.add_point(p + padding);
+        }
+        bb
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        increment_statistic!(statistics::CURVE_TESTS);
+
+        // Transform into a coordinate space where the ray starts at the origin
+        // and points down the z axis.
+        let world_from_ray = ray.dir.tangent_space();
+        let ray_from_world = world_from_ray.transpose();
+        let cp = self
+            .control_points
+            .map(|p| ray_from_world * (p - ray.origin));
+
+        let mut best = None;
+        self.subdivide(&cp, 0.0, 1.0, self.max_depth, &mut best);
+        let hit = best?;
+
+        let (_, derivative) =
+            eval_bezier(&self.control_points.map(|p| p - Point3::origin()), hit.u);
+        let tangent = derivative.normalize();
+        let facing = {
+            let n = -ray.dir + tangent * tangent.dot(ray.dir);
+            if n.norm_squared() > 0.0 {
+                n.normalize()
+            } else {
+                -ray.dir
+            }
+        };
+        let normal = match self.shape {
+            CurveShape::Ribbon => facing,
+            CurveShape::Cylinder => {
+                // Bend the normal away from the center of the curve, as it
+                // would be on a tube.
+                let offset = world_from_ray * hit.offset;
+                let sin = offset.norm().min(1.0);
+                let side = offset - tangent * tangent.dot(offset);
+                if side.norm_squared() > 0.0 {
+                    facing * (1.0 - sin * sin).sqrt() + side.normalize() * sin
+                } else {
+                    facing
+                }
+            }
+        };
+
+        Some(Intersection {
+            distance: hit.distance,
+            position: ray.along(hit.distance),
+            incident: ray.dir,
+            normal,
+            geometric_normal: normal,
+            tangent,
+            texture_coords: TextureCoords::new(hit.u, hit.v),
+            texture_density: 0.0,
+            vertex_color: None,
+        })
+    }
+}
+
+/// Splits a Bézier curve in half with de Casteljau's algorithm.
+fn split_bezier(cp: &[Vector3; 4]) -> [[Vector3; 4]; 2] {
+    let mid = |a: Vector3, b: Vector3| (a + b) / 2.0;
+    let p01 = mid(cp[0], cp[1]);
+    let p12 = mid(cp[1], cp[2]);
+    let p23 = mid(cp[2], cp[3]);
+    let p012 = mid(p01, p12);
+    let p123 = mid(p12, p23);
+    let p0123 = mid(p012, p123);
+    [[cp[0], p01, p012, p0123], [p0123, p123, p23, cp[3]]]
+}
+
+/// Evaluates a Bézier curve and its derivative.
+fn eval_bezier(cp: &[Vector3; 4], u: f32) -> (Vector3, Vector3) {
+    let lerp = |a: Vector3, b: Vector3| a * (1.0 - u) + b * u;
+    let cp1 = [lerp(cp[0], cp[1]), lerp(cp[1], cp[2]), lerp(cp[2], cp[3])];
+    let cp2 = [lerp(cp1[0], cp1[1]), lerp(cp1[1], cp1[2])];
+    let derivative = if (cp2[1] - cp2[0]).norm_squared() > 0.0 {
+        3.0 * (cp2[1] - cp2[0])
+    } else {
+        // The derivative vanishes at a cusp; fall back to the overall
+        // direction of the curve.
+        cp[3] - cp[0]
+    };
+    (lerp(cp2[0], cp2[1]), derivative)
+}
+
+fn lerp_f32(t: f32, a: f32, b: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
@@ -0,0 +1,521 @@
+// An importer for glTF 2.0 scenes, in either the JSON `.gltf` form or the
+// binary `.glb` one. The nodes of the default scene are flattened into one
+// mesh in world space, their metallic-roughness materials are approximated
+// with the renderer's own, and the first camera found can view the scene.
+//
+// Buffers and images are read here, rather than by the gltf crate, so that
+// images are decoded by the same version of the image crate as textures from
+// other formats.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use gltf::mesh::Mode;
+use palette::LinSrgb;
+
+use crate::camera::Camera;
+use crate::material::{Bump, Color, Material, Scalar};
+use crate::mesh::Face;
+use crate::ray::Ray;
+use crate::texture::{Filter, Texture, TextureCoords, Wrap};
+use crate::types::{Mat3, Point3, Vector3};
+
+// The index of refraction glTF gives dielectrics.
+const DIELECTRIC_INDEX: f32 = 1.5;
+
+/// The triangles of a glTF scene, with their transforms applied. Attributes
+/// besides positions are empty unless some primitive has them, in which case
+/// vertices of the rest get zero normals, zero texture coordinates or white.
+#[derive(Debug, Default)]
+pub struct GltfMesh {
+    pub positions: Vec<Point3>,
+    pub normals: Vec<Vector3>,
+    pub texture_coords: Vec<TextureCoords>,
+    pub colors: Vec<LinSrgb>,
+    // Face materials index into `GltfFile::materials`.
+    pub faces: Vec<Face>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GltfError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Gltf(#[from] gltf::Error),
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("invalid data: {0}")]
+    Data(String),
+}
+
+/// A parsed glTF file, along with the contents of its buffers.
+pub struct GltfFile {
+    document: gltf::Document,
+    buffers: Vec<Vec<u8>>,
+    root: PathBuf,
+}
+
+impl GltfFile {
+    pub fn open(path: &Path) -> Result<Self, GltfError> {
+        let root = path.parent().unwrap().to_path_buf();
+        let gltf::Gltf { document, mut blob } = gltf::Gltf::from_slice(&std::fs::read(path)?)?;
+        let buffers = document
+            .buffers()
+            .map(|buffer| {
+                let data = match buffer.source() {
+                    gltf::buffer::Source::Bin => blob
+                        .take()
+                        .ok_or_else(|| GltfError::Data("missing binary chunk".to_string()))?,
+                    gltf::buffer::Source::Uri(uri) => read_uri(&root, uri)?,
+                };
+                if data.len() < buffer.length() {
+                    return Err(GltfError::Data(format!(
+                        "buffer {} is shorter than its length",
+                        buffer.index()
+                    )));
+                }
+                Ok(data)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(GltfFile {
+            document,
+            buffers,
+            root,
+        })
+    }
+
+    /// The external files the buffers of a glTF file are read from, which
+    /// its meshes depend on as much as the file itself.
+    pub fn buffer_files(path: &Path) -> Result<Vec<PathBuf>, GltfError> {
+        let root = path.parent().unwrap();
+        let gltf = gltf::Gltf::from_slice(&std::fs::read(path)?)?;
+        let files = gltf
+            .buffers()
+            .filter_map(|buffer| match buffer.source() {
+                gltf::buffer::Source::Uri(uri) if !uri.starts_with("data:") => Some(root.join(uri)),
+                _ => None,
+            })
+            .collect();
+        Ok(files)
+    }
+
+    /// Flattens the nodes of the default scene, or else the first scene, into
+    /// one mesh. Primitives of points or lines are skipped.
+    pub fn mesh(&self) -> Result<GltfMesh, GltfError> {
+        let mut mesh = GltfMesh::default();
+        let has_normals = self.has_attribute(gltf::Semantic::Normals);
+        let has_texture_coords = self.has_attribute(gltf::Semantic::TexCoords(0));
+        let has_colors = self.has_attribute(gltf::Semantic::Colors(0));
+        let default_material = self.document.materials().len() as u32;
+
+        for (node, transform) in self.nodes() {
+            let Some(node_mesh) = node.mesh() else {
+                continue;
+            };
+            for primitive in node_mesh.primitives() {
+                let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+                let first = mesh.positions.len() as u32;
+                let positions = reader.read_positions().ok_or_else(|| {
+                    GltfError::Data(format!("mesh {} has no positions", node_mesh.index()))
+                })?;
+                mesh.positions
+                    .extend(positions.map(|p| transform.point(Point3::new(p[0], p[1], p[2]))));
+                let count = mesh.positions.len() - first as usize;
+
+                if has_normals {
+                    match reader.read_normals() {
+                        Some(normals) => mesh.normals.extend(
+                            normals.map(|n| transform.normal(Vector3::new(n[0], n[1], n[2]))),
+                        ),
+                        None => mesh
+                            .normals
+                            .extend((0..count).map(|_| Vector3::new(0.0, 0.0, 0.0))),
+                    }
+                }
+                if has_texture_coords {
+                    match reader.read_tex_coords(0) {
+                        // glTF puts the origin of textures at their top left,
+                        // rather than their bottom left.
+                        Some(coords) => mesh.texture_coords.extend(
+                            coords
+                                .into_f32()
+                                .map(|[u, v]| TextureCoords::new(u, 1.0 - v)),
+                        ),
+                        None => mesh
+                            .texture_coords
+                            .extend((0..count).map(|_| TextureCoords::default())),
+                    }
+                }
+                if has_colors {
+                    match reader.read_colors(0) {
+                        Some(colors) => mesh
+                            .colors
+                            .extend(colors.into_rgb_f32().map(|[r, g, b]| LinSrgb::new(r, g, b))),
+                        None => mesh
+                            .colors
+                            .extend((0..count).map(|_| LinSrgb::new(1.0, 1.0, 1.0))),
+                    }
+                }
+
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..count as u32).collect(),
+                };
+                if let Some(&index) = indices.iter().find(|&&i| i as usize >= count) {
+                    return Err(GltfError::Data(format!(
+                        "mesh {} refers to missing vertex {}",
+                        node_mesh.index(),
+                        index
+                    )));
+                }
+                let material = primitive
+                    .material()
+                    .index()
+                    .map_or(default_material, |i| i as u32);
+                // Mirroring transforms reverse the winding of triangles.
+                let flip = transform.mirrors();
+                for [a, b, c] in triangles(primitive.mode(), &indices) {
+                    let vertices = if flip { [a, c, b] } else { [a, b, c] };
+                    mesh.faces.push(Face {
+                        vertices: vertices.map(|i| first + i),
+                        material,
+                    });
+                }
+            }
+        }
+
+        let vertices = mesh.positions.len();
+        let lengths = [
+            mesh.normals.len(),
+            mesh.texture_coords.len(),
+            mesh.colors.len(),
+        ];
+        if lengths.iter().any(|&len| len != 0 && len != vertices) {
+            return Err(GltfError::Data(
+                "vertex attributes differ in length".to_string(),
+            ));
+        }
+        Ok(mesh)
+    }
+
+    /// Converts the file's materials, followed by the default material for
+    /// primitives without one. Occlusion textures are ignored.
+    pub fn materials(&self) -> Result<Vec<Material>, GltfError> {
+        let mut materials = self
+            .document
+            .materials()
+            .map(|material| self.convert_material(&material))
+            .collect::<Result<Vec<_>, _>>()?;
+        materials.push(Material::metallic(
+            Color::solid(LinSrgb::new(1.0, 1.0, 1.0)),
+            DIELECTRIC_INDEX,
+            1.0,
+        ));
+        Ok(materials)
+    }
+
+    /// The first camera in the scene, looking the way its node faces. Only
+    /// perspective cameras are supported.
+    pub fn camera(&self) -> Option<Camera> {
+        self.nodes().find_map(|(node, transform)| {
+            let camera = node.camera()?;
+            let fov = match camera.projection() {
+                gltf::camera::Projection::Perspective(perspective) => {
+                    // The renderer's field of view is horizontal.
+                    let aspect_ratio = perspective.aspect_ratio().unwrap_or(1.0);
+                    let half = (0.5 * perspective.yfov()).tan() * aspect_ratio;
+                    2.0 * half.atan().to_degrees()
+                }
+                gltf::camera::Projection::Orthographic(_) => return None,
+            };
+            let origin = transform.point(Point3::origin());
+            let dir = (transform.linear * Vector3::new(0.0, 0.0, -1.0)).normalize();
+            Some(Camera::new(
+                Ray::new(origin, dir),
+                (fov.round() as u32).clamp(1, 179),
+            ))
+        })
+    }
+
+    fn has_attribute(&self, semantic: gltf::Semantic) -> bool {
+        self.document.meshes().any(|mesh| {
+            mesh.primitives()
+                .any(|primitive| primitive.get(&semantic).is_some())
+        })
+    }
+
+    /// Every node of the scene, with the transform from it to world space.
+    fn nodes(&self) -> impl Iterator<Item = (gltf::Node<'_>, Transform)> {
+        let scene = self
+            .document
+            .default_scene()
+            .or_else(|| self.document.scenes().next());
+        let mut stack: Vec<_> = scene
+            .into_iter()
+            .flat_map(|scene| scene.nodes())
+            .map(|node| (node, Transform::identity()))
+            .collect();
+        std::iter::from_fn(move || {
+            let (node, parent) = stack.pop()?;
+            let transform = parent.then(&Transform::from_matrix(node.transform().matrix()));
+            stack.extend(node.children().map(|child| (child, transform)));
+            Some((node, transform))
+        })
+    }
+
+    fn convert_material(&self, material: &gltf::Material) -> Result<Material, GltfError> {
+        let emissive = material.emissive_factor();
+        if emissive.iter().any(|&c| c > 0.0) {
+            let emissive = LinSrgb::new(emissive[0], emissive[1], emissive[2]);
+            let color = match material.emissive_texture() {
+                Some(info) => Color::texture(self.texture(&info)?.tinted(emissive)),
+                None => Color::solid(emissive),
+            };
+            return Ok(Material::light(color));
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let base_color = LinSrgb::new(r, g, b);
+        let color = match pbr.base_color_texture() {
+            Some(info) => Color::texture(self.texture(&info)?.tinted(base_color)),
+            None => Color::solid(base_color),
+        };
+        let factors = (pbr.roughness_factor(), pbr.metallic_factor());
+        let (roughness, metalness) = match pbr.metallic_roughness_texture() {
+            Some(info) => {
+                let texture = info.texture();
+                let image = self.image(&texture.source())?.into_rgb32f();
+                let (roughness, metalness) = split_metallic_roughness(&image, factors);
+                (
+                    Scalar::texture(sampled(roughness, &texture.sampler())),
+                    Scalar::texture(sampled(metalness, &texture.sampler())),
+                )
+            }
+            None => (
+                Scalar::constant(convert_roughness(1.0, factors.0)),
+                Scalar::constant(factors.1),
+            ),
+        };
+        let converted = Material::glossy(color, DIELECTRIC_INDEX, 0.0)
+            .with_roughness(roughness)
+            .with_metalness(metalness);
+        Ok(match material.normal_texture() {
+            Some(normal) => {
+                let texture = normal.texture();
+                let image = self.image(&texture.source())?;
+                let image = Texture::from_linear_image(image.into_rgb8());
+                converted.with_bump(Bump::normal(sampled(image, &texture.sampler())))
+            }
+            None => converted,
+        })
+    }
+
+    fn texture(&self, info: &gltf::texture::Info) -> Result<Texture, GltfError> {
+        let texture = info.texture();
+        let image = self.image(&texture.source())?;
+        Ok(sampled(
+            Texture::from_image(image.into_rgb8()),
+            &texture.sampler(),
+        ))
+    }
+
+    fn image(&self, image: &gltf::Image) -> Result<image::DynamicImage, GltfError> {
+        let bytes = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                let buffer = &self.buffers[view.buffer().index()];
+                buffer
+                    .get(view.offset()..view.offset() + view.length())
+                    .ok_or_else(|| {
+                        GltfError::Data(format!("image {} is out of bounds", image.index()))
+                    })?
+                    .to_vec()
+            }
+            gltf::image::Source::Uri { uri, .. } => read_uri(&self.root, uri)?,
+        };
+        Ok(image::load_from_memory(&bytes)?)
+    }
+}
+
+/// An affine transform from a node's space to world space.
+#[derive(Copy, Clone)]
+struct Transform {
+    linear: Mat3,
+    translation: Vector3,
+}
+
+impl Transform {
+    fn identity() -> Self {
+        Transform {
+            linear: Mat3::identity(),
+            translation: Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // glTF matrices are stored column by column.
+    fn from_matrix(m: [[f32; 4]; 4]) -> Self {
+        Transform {
+            linear: Mat3::new([
+                [m[0][0], m[1][0], m[2][0]],
+                [m[0][1], m[1][1], m[2][1]],
+                [m[0][2], m[1][2], m[2][2]],
+            ]),
+            translation: Vector3::new(m[3][0], m[3][1], m[3][2]),
+        }
+    }
+
+    /// Applies `child` within this transform.
+    fn then(&self, child: &Transform) -> Self {
+        Transform {
+            linear: self.linear * child.linear,
+            translation: self.linear * child.translation + self.translation,
+        }
+    }
+
+    fn point(&self, point: Point3) -> Point3 {
+        Point3::origin() + (self.linear * (point - Point3::origin()) + self.translation)
+    }
+
+    // Normals are transformed by the inverse transpose, which is the matrix of
+    // cofactors divided by the determinant. Only its sign matters here.
+    fn normal(&self, normal: Vector3) -> Vector3 {
+        let rows = self.rows();
+        let cofactors = Mat3::new(
+            [
+                rows[1].cross(rows[2]),
+                rows[2].cross(rows[0]),
+                rows[0].cross(rows[1]),
+            ]
+            .map(|row| [row.x(), row.y(), row.z()]),
+        );
+        let sign = if self.mirrors() { -1.0 } else { 1.0 };
+        let normal = cofactors * normal;
+        if normal.norm_squared() > 0.0 {
+            sign * normal.normalize()
+        } else {
+            normal
+        }
+    }
+
+    fn mirrors(&self) -> bool {
+        let rows = self.rows();
+        rows[0].dot(rows[1].cross(rows[2])) < 0.0
+    }
+
+    fn rows(&self) -> [Vector3; 3] {
+        let m = &self.linear;
+        [0, 1, 2].map(|i| Vector3::new(m[(i, 0)], m[(i, 1)], m[(i, 2)]))
+    }
+}
+
+/// Splits the vertices of a primitive into triangles.
+fn triangles(mode: Mode, indices: &[u32]) -> Vec<[u32; 3]> {
+    match mode {
+        Mode::Triangles => indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect(),
+        // Every other triangle in a strip is wound the other way.
+        Mode::TriangleStrip => indices
+            .windows(3)
+            .enumerate()
+            .map(|(i, t)| {
+                if i % 2 == 0 {
+                    [t[0], t[1], t[2]]
+                } else {
+                    [t[1], t[0], t[2]]
+                }
+            })
+            .collect(),
+        Mode::TriangleFan => indices
+            .windows(2)
+            .skip(1)
+            .map(|t| [indices[0], t[0], t[1]])
+            .collect(),
+        Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip => Vec::new(),
+    }
+}
+
+// glTF roughness is squared to give the width of the microfacet distribution.
+fn convert_roughness(roughness: f32, factor: f32) -> f32 {
+    (roughness * factor).powi(2)
+}
+
+// Metallic-roughness textures keep roughness in the green channel, and
+// metalness in the blue one, each scaled by its factor. The red channel may
+// hold ambient occlusion, which is ignored.
+fn split_metallic_roughness(
+    image: &image::Rgb32FImage,
+    (roughness_factor, metallic_factor): (f32, f32),
+) -> (Texture, Texture) {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let channel = |channel: usize, convert: &dyn Fn(f32) -> f32| {
+        let values = image.pixels().map(|p| convert(p[channel])).collect();
+        Texture::from_values(width, height, values)
+    };
+    (
+        channel(1, &|r| convert_roughness(r, roughness_factor)),
+        channel(2, &|m| m * metallic_factor),
+    )
+}
+
+// Filters and wraps a texture as its sampler asks. Samplers which leave the
+// filter unspecified, or ask for mipmaps, are filtered trilinearly.
+fn sampled(texture: Texture, sampler: &gltf::texture::Sampler) -> Texture {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let filter = match (sampler.min_filter(), sampler.mag_filter()) {
+        (Some(MinFilter::Nearest), Some(MagFilter::Nearest)) => Filter::Nearest,
+        (Some(MinFilter::Nearest | MinFilter::Linear), _) => Filter::Bilinear,
+        _ => Filter::Trilinear,
+    };
+    let wrap = |mode| match mode {
+        WrappingMode::ClampToEdge => Wrap::Clamp,
+        WrappingMode::MirroredRepeat => Wrap::Mirror,
+        WrappingMode::Repeat => Wrap::Repeat,
+    };
+    texture
+        .with_filter(filter)
+        .with_wrap(wrap(sampler.wrap_s()), wrap(sampler.wrap_t()))
+}
+
+/// Reads a buffer or image from a base64 data URI, or a file relative to the
+/// glTF file.
+fn read_uri(root: &Path, uri: &str) -> Result<Vec<u8>, GltfError> {
+    match uri.strip_prefix("data:") {
+        Some(data) => {
+            let (_, encoded) = data
+                .split_once(";base64,")
+                .ok_or_else(|| GltfError::Data("data uri is not base64".to_string()))?;
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|e| GltfError::Data(format!("invalid base64: {}", e)))
+        }
+        None => Ok(std::fs::read(root.join(uri))?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::TextureCoords;
+
+    #[test]
+    fn metallic_roughness_channels_split() {
+        // Occlusion, roughness and metalness in each pixel.
+        let pixels = [0.1, 0.5, 0.25, 0.9, 1.0, 0.0];
+        let image = image::Rgb32FImage::from_raw(2, 1, pixels.to_vec()).unwrap();
+        let (roughness, metalness) = split_metallic_roughness(&image, (0.5, 0.8));
+        let value = |texture: &Texture, x: f32| texture.sample(TextureCoords::new(x, 0.5), 0.0);
+        let close = |color: LinSrgb, value: f32| {
+            [color.red, color.green, color.blue]
+                .iter()
+                .all(|c| (c - value).abs() < 1e-6)
+        };
+        assert!(close(value(&roughness, 0.25), 0.0625));
+        assert!(close(value(&roughness, 0.75), 0.25));
+        assert!(close(value(&metalness, 0.25), 0.2));
+        assert!(close(value(&metalness, 0.75), 0.0));
+    }
+}
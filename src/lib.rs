@@ -1,16 +1,27 @@
 pub mod bounds;
 pub mod bvh;
+pub mod cache;
 pub mod camera;
+pub mod csg;
+pub mod curve;
 pub mod float;
+pub mod gltf_import;
 pub mod loader;
 pub mod material;
+pub mod mesh;
 pub mod object;
+pub mod packet;
+pub mod ply;
+pub mod polynomial;
 pub mod prelude;
 pub mod profile;
 pub mod ray;
 pub mod render;
 pub mod scene;
+pub mod sdf;
+pub mod simd;
 pub mod statistics;
+pub mod stl;
 pub mod surface;
 pub mod texture;
 pub mod tracer;
@@ -1,38 +1,131 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io;
-use std::path::Path;
-use std::str::FromStr;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
 
 use palette::LinSrgb;
 use serde::Deserialize;
 
-use crate::bvh::BoundingVolumeHierarchy;
+use crate::bounds::BoundingBox;
+use crate::bvh::{BoundingVolumeHierarchy, BvhLayout, BvhOptions};
+use crate::cache::{CacheFile, CacheKey, CacheReader, CacheWriter, Cacheable, MappedVec};
 use crate::camera::Camera;
-use crate::material::{Color, Material};
+use crate::csg::{Csg, Operation};
+use crate::curve::{Curve, CurveShape};
+use crate::gltf_import::{GltfError, GltfFile, GltfMesh};
+use crate::material::{Bump, Color, Material, Scalar};
+use crate::mesh::{Face, TriangleMesh};
 use crate::object::Object;
+use crate::ply::{load_ply, PlyError};
 use crate::profile;
 use crate::ray::Ray;
 use crate::scene::Scene;
+use crate::sdf::{Sdf, SignedDistanceField};
+use crate::stl::{load_stl, StlError};
 use crate::surface::*;
-use crate::texture::{Texture, TextureCoords};
-use crate::types::{Point3, Vector3};
+use crate::texture::{Filter, Texture, TextureCoords, Wrap};
+use crate::types::{Mat3, Point3, Vector3};
 
+// Parsed meshes and the BVH are cached in this file in the scene's directory.
+const CACHE_FILE: &str = "scene.cache";
+
+// Triangles in a mesh without a material use the default one.
+const NO_MATERIAL: u32 = u32::MAX;
+
+const WAVEFRONT_OPTIONS: tobj::LoadOptions = tobj::LoadOptions {
+    single_index: true,
+    triangulate: true,
+    ignore_points: true,
+    ignore_lines: true,
+};
+
+/// Loads the scene in a directory, described by its `scene.yaml`, or a glTF
+/// file as a whole scene.
 pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Scene, LoadError> {
     profile::start("load.prof");
-    let root: &Path = path.as_ref();
-    let file = File::open(root.join("scene.yaml"))?;
-    let scene_prototype: ScenePrototype = serde_yaml::from_reader(&file)?;
-    let scene = scene_prototype.compile(root);
+    let path: &Path = path.as_ref();
+    let is_gltf = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("gltf" | "glb")
+    );
+    let scene = if is_gltf {
+        load_gltf_scene(path)
+    } else {
+        let source = std::fs::read(path.join("scene.yaml"))?;
+        let scene_prototype: ScenePrototype = serde_yaml::from_slice(&source)?;
+        scene_prototype.compile(path, &source, &path.join(CACHE_FILE))
+    };
     profile::end();
     scene
 }
 
+// glTF files rarely carry lights of their own, so whole glTF scenes are lit by
+// a white sky, and viewed through their first camera. Their cache is kept
+// beside them, named after the file.
+fn load_gltf_scene(path: &Path) -> Result<Scene, LoadError> {
+    let root = path.parent().unwrap();
+    let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+    let cache_path = root.join(format!("{}.cache", file_name));
+    let scene_prototype = ScenePrototype {
+        camera: None,
+        global_illumination: (1.0, 1.0, 1.0),
+        objects: vec![ObjectPrototype {
+            surface: SurfacePrototype::Gltf {
+                gltf_file: file_name.clone(),
+            },
+        }],
+        bvh: BvhPrototype::default(),
+        textures: TexturesPrototype::default(),
+    };
+    scene_prototype.compile(root, file_name.as_bytes(), &cache_path)
+}
+
 #[derive(Debug, Deserialize)]
 struct ScenePrototype {
-    camera: CameraPrototype,
+    // Scenes with a glTF object may leave this out to use its camera.
+    camera: Option<CameraPrototype>,
     #[serde(default)]
     global_illumination: (f32, f32, f32),
     objects: Vec<ObjectPrototype>,
+    #[serde(default)]
+    bvh: BvhPrototype,
+    // How textures are filtered and wrapped unless they choose for
+    // themselves. glTF textures are left to their samplers.
+    #[serde(default)]
+    textures: TexturesPrototype,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TexturesPrototype {
+    filter: FilterPrototype,
+    wrap: WrapPrototype,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct BvhPrototype {
+    leaf_size: usize,
+    bins: usize,
+    traversal_cost: f32,
+    intersection_cost: f32,
+    spatial_splits: bool,
+    rebuild_threshold: Option<f32>,
+}
+
+impl Default for BvhPrototype {
+    fn default() -> Self {
+        let options = BvhOptions::default();
+        BvhPrototype {
+            leaf_size: options.max_leaf_size,
+            bins: options.bins,
+            traversal_cost: options.traversal_cost,
+            intersection_cost: options.intersection_cost,
+            spatial_splits: options.spatial_splits,
+            rebuild_threshold: options.rebuild_threshold,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -62,6 +155,56 @@ enum SurfacePrototype {
         radius: f32,
         material: MaterialPrototype,
     },
+    Plane {
+        point: (f32, f32, f32),
+        normal: (f32, f32, f32),
+        material: MaterialPrototype,
+    },
+    Disk {
+        center: (f32, f32, f32),
+        normal: (f32, f32, f32),
+        radius: f32,
+        material: MaterialPrototype,
+    },
+    Cylinder {
+        base: (f32, f32, f32),
+        axis: (f32, f32, f32),
+        radius: f32,
+        height: f32,
+        material: MaterialPrototype,
+    },
+    Cone {
+        base: (f32, f32, f32),
+        axis: (f32, f32, f32),
+        radius: f32,
+        height: f32,
+        material: MaterialPrototype,
+    },
+    Box {
+        min: (f32, f32, f32),
+        max: (f32, f32, f32),
+        #[serde(default)]
+        rotation: (f32, f32, f32),
+        material: MaterialPrototype,
+    },
+    Torus {
+        center: (f32, f32, f32),
+        axis: (f32, f32, f32),
+        major_radius: f32,
+        minor_radius: f32,
+        material: MaterialPrototype,
+    },
+    Csg {
+        operation: CsgOperationPrototype,
+        left: SolidPrototype,
+        right: SolidPrototype,
+        material: MaterialPrototype,
+    },
+    Sdf {
+        bounds: BoundsPrototype,
+        shape: SdfPrototype,
+        material: MaterialPrototype,
+    },
     Triangle {
         vertices: [(f32, f32, f32); 3],
         material: MaterialPrototype,
@@ -72,6 +215,178 @@ enum SurfacePrototype {
     },
     Wavefront {
         obj_file: String,
+        // Replaces every material of the mesh.
+        material: Option<MaterialPrototype>,
+        // Replaces the materials from its libraries with these names.
+        #[serde(default)]
+        materials: HashMap<String, MaterialPrototype>,
+        smooth_normals: Option<SmoothingPrototype>,
+    },
+    Ply {
+        ply_file: String,
+        smooth_normals: Option<SmoothingPrototype>,
+        material: MaterialPrototype,
+    },
+    Gltf {
+        gltf_file: String,
+    },
+    Stl {
+        stl_file: String,
+        #[serde(default)]
+        weld: bool,
+        smooth_normals: Option<SmoothingPrototype>,
+        // The older way to smooth an STL mesh, which welds it first.
+        crease_angle: Option<f32>,
+        material: MaterialPrototype,
+    },
+    Hair {
+        obj_file: String,
+        width: f32,
+        tip_width: Option<f32>,
+        #[serde(default)]
+        shape: CurveShapePrototype,
+        material: MaterialPrototype,
+    },
+}
+
+/// Generates smooth normals for a mesh, replacing any it has.
+#[derive(Debug, Deserialize)]
+struct SmoothingPrototype {
+    // In degrees.
+    #[serde(default = "default_crease_angle")]
+    crease_angle: f32,
+    #[serde(default)]
+    weighting: WeightingPrototype,
+}
+
+fn default_crease_angle() -> f32 {
+    60.0
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WeightingPrototype {
+    Area,
+    #[default]
+    Angle,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CurveShapePrototype {
+    Ribbon,
+    #[default]
+    Cylinder,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+enum SolidPrototype {
+    Sphere {
+        center: (f32, f32, f32),
+        radius: f32,
+    },
+    Cylinder {
+        base: (f32, f32, f32),
+        axis: (f32, f32, f32),
+        radius: f32,
+        height: f32,
+    },
+    Cone {
+        base: (f32, f32, f32),
+        axis: (f32, f32, f32),
+        radius: f32,
+        height: f32,
+    },
+    Box {
+        min: (f32, f32, f32),
+        max: (f32, f32, f32),
+        #[serde(default)]
+        rotation: (f32, f32, f32),
+    },
+    Torus {
+        center: (f32, f32, f32),
+        axis: (f32, f32, f32),
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Csg {
+        operation: CsgOperationPrototype,
+        left: Box<SolidPrototype>,
+        right: Box<SolidPrototype>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CsgOperationPrototype {
+    Union,
+    Intersection,
+    Difference,
+}
+
+#[derive(Debug, Deserialize)]
+struct BoundsPrototype {
+    min: (f32, f32, f32),
+    max: (f32, f32, f32),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum SdfPrototype {
+    Sphere {
+        #[serde(default)]
+        center: (f32, f32, f32),
+        radius: f32,
+    },
+    Box {
+        #[serde(default)]
+        center: (f32, f32, f32),
+        size: (f32, f32, f32),
+    },
+    RoundBox {
+        #[serde(default)]
+        center: (f32, f32, f32),
+        size: (f32, f32, f32),
+        radius: f32,
+    },
+    Torus {
+        #[serde(default)]
+        center: (f32, f32, f32),
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Capsule {
+        start: (f32, f32, f32),
+        end: (f32, f32, f32),
+        radius: f32,
+    },
+    Translate {
+        offset: (f32, f32, f32),
+        shape: Box<SdfPrototype>,
+    },
+    Union {
+        shapes: Vec<SdfPrototype>,
+    },
+    Intersection {
+        shapes: Vec<SdfPrototype>,
+    },
+    Subtraction {
+        shapes: Vec<SdfPrototype>,
+    },
+    SmoothUnion {
+        smoothness: f32,
+        shapes: Vec<SdfPrototype>,
+    },
+    Repeat {
+        period: (f32, f32, f32),
+        shape: Box<SdfPrototype>,
+    },
+    Twist {
+        rate: f32,
+        shape: Box<SdfPrototype>,
     },
 }
 
@@ -79,7 +394,38 @@ enum SurfacePrototype {
 struct MaterialPrototype {
     #[serde(flatten)]
     kind: MaterialKindPrototype,
-    color: (f32, f32, f32),
+    color: ColorPrototype,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ColorPrototype {
+    Solid((f32, f32, f32)),
+    Texture {
+        texture: String,
+        #[serde(default)]
+        filter: Option<FilterPrototype>,
+        #[serde(default)]
+        wrap: Option<WrapPrototype>,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FilterPrototype {
+    #[default]
+    Nearest,
+    Bilinear,
+    Trilinear,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WrapPrototype {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
 }
 
 #[derive(Debug, Deserialize)]
@@ -93,20 +439,139 @@ enum MaterialKindPrototype {
 }
 
 impl ScenePrototype {
-    fn compile(self, root: &Path) -> Result<Scene, LoadError> {
+    fn compile(self, root: &Path, source: &[u8], cache_path: &Path) -> Result<Scene, LoadError> {
+        let options = self.bvh.into();
+        let textures = self.textures.into();
+        let key = cache_key(root, source, &self.objects, &options)?;
+        let mut cache = match CacheFile::open(cache_path, &key).and_then(|file| read_cache(&file)) {
+            Some((meshes, layout)) => Cache::Current {
+                meshes: meshes.into_iter(),
+                layout,
+            },
+            None => {
+                let mut writer = CacheWriter::new(&key);
+                let meshes = self
+                    .objects
+                    .iter()
+                    .filter(|object| object.is_mesh())
+                    .count();
+                writer.write(&(meshes as u64));
+                Cache::Stale(writer)
+            }
+        };
+
+        let mut camera = self.camera.map(Camera::from);
         let mut objects = Vec::new();
+        let mut meshes = Vec::new();
         for object in self.objects {
-            let new_objects: Result<Vec<Object>, LoadError> = object.load(root);
-            objects.extend(new_objects?.into_iter());
+            object.load(
+                root,
+                textures,
+                &mut cache,
+                &mut camera,
+                &mut objects,
+                &mut meshes,
+            )?;
         }
+        let camera = camera.ok_or_else(|| LoadError::Parse("scene has no camera".to_string()))?;
+        let objects = match cache {
+            Cache::Current { layout, .. } if layout.fits(&objects, &meshes) => {
+                BoundingVolumeHierarchy::from_layout(objects, meshes, &options, layout)
+            }
+            Cache::Current { .. } => {
+                BoundingVolumeHierarchy::with_options(objects, meshes, &options)
+            }
+            Cache::Stale(mut writer) => {
+                let bvh = BoundingVolumeHierarchy::with_options(objects, meshes, &options);
+                bvh.write_layout(&mut writer);
+                // A cache which can't be saved, for example in a read-only
+                // directory, only costs time on the next load.
+                let _ = writer.save(cache_path);
+                bvh
+            }
+        };
         Ok(Scene {
-            camera: self.camera.into(),
+            camera,
             global_illumination: LinSrgb::from_components(self.global_illumination),
-            objects: BoundingVolumeHierarchy::new(objects),
+            objects,
         })
     }
 }
 
+/// Hashes everything the cached parts of a scene depend on: the scene file,
+/// the files its meshes and curves are loaded from, and the BVH options.
+fn cache_key(
+    root: &Path,
+    source: &[u8],
+    objects: &[ObjectPrototype],
+    options: &BvhOptions,
+) -> Result<CacheKey, LoadError> {
+    let mut key = CacheKey::new();
+    key.add_bytes(source);
+    for object in objects {
+        if let Some(file) = object.source_file() {
+            key.add_file(&root.join(file))?;
+        }
+        if let SurfacePrototype::Gltf { gltf_file } = &object.surface {
+            for file in GltfFile::buffer_files(&root.join(gltf_file))? {
+                key.add_file(&file)?;
+            }
+        }
+    }
+    options.add_to_key(&mut key);
+    Ok(key)
+}
+
+fn read_cache(file: &CacheFile) -> Option<(Vec<Mesh>, BvhLayout)> {
+    let mut reader = file.reader();
+    let meshes = reader.read_vec()?;
+    let layout = reader.read()?;
+    reader.is_done().then_some((meshes, layout))
+}
+
+/// Where the slow parts of a scene come from: its cache, if that is current,
+/// or else their sources, which are recorded in a new cache.
+enum Cache {
+    Current {
+        meshes: std::vec::IntoIter<Mesh>,
+        layout: BvhLayout,
+    },
+    Stale(CacheWriter),
+}
+
+impl Cache {
+    fn mesh(
+        &mut self,
+        path: &Path,
+        load: impl FnOnce(&Path) -> Result<Mesh, LoadError>,
+    ) -> Result<Mesh, LoadError> {
+        match self {
+            Cache::Current { meshes, .. } => match meshes.next() {
+                Some(mesh) => Ok(mesh),
+                None => load(path),
+            },
+            Cache::Stale(writer) => {
+                let mesh = load(path)?;
+                writer.write(&mesh);
+                Ok(mesh)
+            }
+        }
+    }
+}
+
+impl From<BvhPrototype> for BvhOptions {
+    fn from(prototype: BvhPrototype) -> BvhOptions {
+        BvhOptions {
+            max_leaf_size: prototype.leaf_size,
+            bins: prototype.bins,
+            traversal_cost: prototype.traversal_cost,
+            intersection_cost: prototype.intersection_cost,
+            spatial_splits: prototype.spatial_splits,
+            rebuild_threshold: prototype.rebuild_threshold,
+        }
+    }
+}
+
 impl From<CameraPrototype> for Camera {
     fn from(prototype: CameraPrototype) -> Camera {
         Camera::new(
@@ -117,8 +582,37 @@ impl From<CameraPrototype> for Camera {
 }
 
 impl ObjectPrototype {
-    fn load(self, root: &Path) -> Result<Vec<Object>, LoadError> {
-        let mut objects = Vec::new();
+    fn is_mesh(&self) -> bool {
+        matches!(
+            self.surface,
+            SurfacePrototype::Wavefront { .. }
+                | SurfacePrototype::Ply { .. }
+                | SurfacePrototype::Stl { .. }
+                | SurfacePrototype::Gltf { .. }
+        )
+    }
+
+    /// The file the object's geometry is loaded from, if any.
+    fn source_file(&self) -> Option<&str> {
+        match &self.surface {
+            SurfacePrototype::Wavefront { obj_file, .. }
+            | SurfacePrototype::Hair { obj_file, .. } => Some(obj_file),
+            SurfacePrototype::Ply { ply_file, .. } => Some(ply_file),
+            SurfacePrototype::Stl { stl_file, .. } => Some(stl_file),
+            SurfacePrototype::Gltf { gltf_file } => Some(gltf_file),
+            _ => None,
+        }
+    }
+
+    fn load(
+        self,
+        root: &Path,
+        textures: TextureOptions,
+        cache: &mut Cache,
+        camera: &mut Option<Camera>,
+        objects: &mut Vec<Object>,
+        meshes: &mut Vec<TriangleMesh>,
+    ) -> Result<(), LoadError> {
         match self.surface {
             SurfacePrototype::Sphere {
                 center,
@@ -127,7 +621,97 @@ impl ObjectPrototype {
             } => {
                 objects.push(Object::new(
                     Sphere::new(center.into(), radius),
-                    material.into(),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Plane {
+                point,
+                normal,
+                material,
+            } => {
+                objects.push(Object::new(
+                    Plane::new(point.into(), normal.into()),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Disk {
+                center,
+                normal,
+                radius,
+                material,
+            } => {
+                objects.push(Object::new(
+                    Disk::new(center.into(), normal.into(), radius),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Cylinder {
+                base,
+                axis,
+                radius,
+                height,
+                material,
+            } => {
+                objects.push(Object::new(
+                    Cylinder::new(base.into(), axis.into(), radius, height),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Cone {
+                base,
+                axis,
+                radius,
+                height,
+                material,
+            } => {
+                objects.push(Object::new(
+                    Cone::new(base.into(), axis.into(), radius, height),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Box {
+                min,
+                max,
+                rotation,
+                material,
+            } => {
+                let (x, y, z) = rotation;
+                objects.push(Object::new(
+                    Cuboid::new(min.into(), max.into(), Mat3::rotation(x, y, z)),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Torus {
+                center,
+                axis,
+                major_radius,
+                minor_radius,
+                material,
+            } => {
+                objects.push(Object::new(
+                    Torus::new(center.into(), axis.into(), major_radius, minor_radius),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Csg {
+                operation,
+                left,
+                right,
+                material,
+            } => {
+                objects.push(Object::new(
+                    Csg::new(operation.into(), left.into(), right.into()),
+                    material.compile(root, textures)?,
+                ));
+            }
+            SurfacePrototype::Sdf {
+                bounds,
+                shape,
+                material,
+            } => {
+                objects.push(Object::new(
+                    SignedDistanceField::new(shape.into(), bounds.into()),
+                    material.compile(root, textures)?,
                 ));
             }
             SurfacePrototype::Triangle { vertices, material } => {
@@ -137,7 +721,7 @@ impl ObjectPrototype {
                         None,
                         None,
                     ),
-                    material.into(),
+                    material.compile(root, textures)?,
                 ));
             }
             SurfacePrototype::Quadrilateral { vertices, material } => {
@@ -145,82 +729,631 @@ impl ObjectPrototype {
                 let v1 = vertices[1].into();
                 let v2 = vertices[2].into();
                 let v3 = vertices[3].into();
-                let mat: Material = material.into();
+                let mat = material.compile(root, textures)?;
                 objects.push(Object::new(
                     Triangle::new([v0, v1, v2], None, None),
                     mat.clone(),
                 ));
                 objects.push(Object::new(Triangle::new([v0, v2, v3], None, None), mat));
             }
-            SurfacePrototype::Wavefront { obj_file } => {
-                for object in load_wavefront(&root.join(obj_file))? {
-                    objects.push(object);
+            SurfacePrototype::Wavefront {
+                obj_file,
+                material,
+                materials,
+                smooth_normals,
+            } => {
+                if material.is_some() && !materials.is_empty() {
+                    return Err(LoadError::Parse(
+                        "obj mesh has both material and materials".to_string(),
+                    ));
+                }
+                let path = root.join(obj_file);
+                let load =
+                    |path: &Path| Ok(Mesh::load_wavefront(path)?.smoothed(smooth_normals.as_ref()));
+                let mesh = cache.mesh(&path, load)?;
+                if let Some(material) = material {
+                    meshes.push(mesh.with_material(material.compile(root, textures)?));
+                } else {
+                    let overrides = materials
+                        .into_iter()
+                        .map(|(name, material)| Ok((name, material.compile(root, textures)?)))
+                        .collect::<Result<_, LoadError>>()?;
+                    meshes.push(mesh.into_triangle_mesh(&path, overrides, textures)?);
+                }
+            }
+            SurfacePrototype::Ply {
+                ply_file,
+                smooth_normals,
+                material,
+            } => {
+                let load =
+                    |path: &Path| Ok(Mesh::load_ply(path)?.smoothed(smooth_normals.as_ref()));
+                let mesh = cache.mesh(&root.join(ply_file), load)?;
+                meshes.push(mesh.with_material(material.compile(root, textures)?));
+            }
+            SurfacePrototype::Gltf { gltf_file } => {
+                // Materials aren't cached, so the file is read either way.
+                let path = root.join(gltf_file);
+                let file = GltfFile::open(&path)?;
+                let mesh = cache.mesh(&path, |_| Ok(Mesh::from_gltf(file.mesh()?)))?;
+                meshes.push(mesh.with_materials(file.materials()?));
+                if camera.is_none() {
+                    *camera = file.camera();
+                }
+            }
+            SurfacePrototype::Stl {
+                stl_file,
+                weld,
+                smooth_normals,
+                crease_angle,
+                material,
+            } => {
+                let smooth_normals = match (smooth_normals, crease_angle) {
+                    (Some(_), Some(_)) => {
+                        return Err(LoadError::Parse(
+                            "stl mesh has both smooth_normals and crease_angle".to_string(),
+                        ))
+                    }
+                    (smooth_normals, None) => smooth_normals,
+                    (None, Some(crease_angle)) => Some(SmoothingPrototype {
+                        crease_angle,
+                        weighting: WeightingPrototype::default(),
+                    }),
+                };
+                let weld = weld || crease_angle.is_some();
+                let load =
+                    |path: &Path| Ok(Mesh::load_stl(path, weld)?.smoothed(smooth_normals.as_ref()));
+                let mesh = cache.mesh(&root.join(stl_file), load)?;
+                meshes.push(mesh.with_material(material.compile(root, textures)?));
+            }
+            SurfacePrototype::Hair {
+                obj_file,
+                width,
+                tip_width,
+                shape,
+                material,
+            } => {
+                let material = material.compile(root, textures)?;
+                let widths = (width, tip_width.unwrap_or(width));
+                for curve in load_hair(&root.join(obj_file), widths, shape.into())? {
+                    objects.push(Object::new(curve, material.clone()));
                 }
             }
         }
-        Ok(objects)
+        Ok(())
     }
 }
 
-impl From<MaterialPrototype> for Material {
-    fn from(prototype: MaterialPrototype) -> Material {
-        let color = LinSrgb::from_components(prototype.color);
-        match prototype.kind {
-            MaterialKindPrototype::Diffuse => Material::diffuse(Color::solid(color)),
-            MaterialKindPrototype::Specular => Material::specular(Color::solid(color)),
-            MaterialKindPrototype::Glossy { index, roughness } => {
-                Material::glossy(Color::solid(color), index, roughness)
+impl From<TexturesPrototype> for TextureOptions {
+    fn from(prototype: TexturesPrototype) -> TextureOptions {
+        TextureOptions {
+            filter: prototype.filter.into(),
+            wrap: prototype.wrap.into(),
+        }
+    }
+}
+
+impl From<FilterPrototype> for Filter {
+    fn from(prototype: FilterPrototype) -> Filter {
+        match prototype {
+            FilterPrototype::Nearest => Filter::Nearest,
+            FilterPrototype::Bilinear => Filter::Bilinear,
+            FilterPrototype::Trilinear => Filter::Trilinear,
+        }
+    }
+}
+
+impl From<WrapPrototype> for Wrap {
+    fn from(prototype: WrapPrototype) -> Wrap {
+        match prototype {
+            WrapPrototype::Repeat => Wrap::Repeat,
+            WrapPrototype::Clamp => Wrap::Clamp,
+            WrapPrototype::Mirror => Wrap::Mirror,
+        }
+    }
+}
+
+impl From<CurveShapePrototype> for CurveShape {
+    fn from(prototype: CurveShapePrototype) -> CurveShape {
+        match prototype {
+            CurveShapePrototype::Ribbon => CurveShape::Ribbon,
+            CurveShapePrototype::Cylinder => CurveShape::Cylinder,
+        }
+    }
+}
+
+impl From<SolidPrototype> for Box<dyn Solid> {
+    fn from(prototype: SolidPrototype) -> Box<dyn Solid> {
+        match prototype {
+            SolidPrototype::Sphere { center, radius } => {
+                Box::new(Sphere::new(center.into(), radius))
+            }
+            SolidPrototype::Cylinder {
+                base,
+                axis,
+                radius,
+                height,
+            } => Box::new(Cylinder::new(base.into(), axis.into(), radius, height)),
+            SolidPrototype::Cone {
+                base,
+                axis,
+                radius,
+                height,
+            } => Box::new(Cone::new(base.into(), axis.into(), radius, height)),
+            SolidPrototype::Box { min, max, rotation } => {
+                let (x, y, z) = rotation;
+                Box::new(Cuboid::new(min.into(), max.into(), Mat3::rotation(x, y, z)))
             }
-            MaterialKindPrototype::Light => Material::light(Color::solid(color)),
+            SolidPrototype::Torus {
+                center,
+                axis,
+                major_radius,
+                minor_radius,
+            } => Box::new(Torus::new(
+                center.into(),
+                axis.into(),
+                major_radius,
+                minor_radius,
+            )),
+            SolidPrototype::Csg {
+                operation,
+                left,
+                right,
+            } => Box::new(Csg::new(operation.into(), (*left).into(), (*right).into())),
         }
     }
 }
 
-fn load_wavefront(path: &Path) -> Result<Vec<Object>, LoadError> {
-    let (models, raw_materials) = tobj::load_obj(
-        path,
-        &tobj::LoadOptions {
-            single_index: true,
-            triangulate: true,
-            ignore_points: true,
-            ignore_lines: true,
-        },
-    )?;
+impl From<CsgOperationPrototype> for Operation {
+    fn from(prototype: CsgOperationPrototype) -> Operation {
+        match prototype {
+            CsgOperationPrototype::Union => Operation::Union,
+            CsgOperationPrototype::Intersection => Operation::Intersection,
+            CsgOperationPrototype::Difference => Operation::Difference,
+        }
+    }
+}
 
-    let root = path.parent().unwrap();
-    let default_material = Material::diffuse(Color::solid(LinSrgb::new(1.0, 1.0, 1.0)));
-    let materials = raw_materials?
-        .iter()
-        .map(|m| convert_material(root, m))
-        .collect::<Result<Vec<_>, _>>()?;
-
-    let mut objects = Vec::new();
-    for model in models.iter() {
-        let mesh = &model.mesh;
-        let material = mesh
-            .material_id
-            .and_then(|idx| materials.get(idx))
-            .unwrap_or(&default_material);
-        for i in (0..mesh.indices.len()).step_by(3) {
-            let v = |j| mesh_vertex(mesh, mesh.indices[j] as usize);
-            let n = |j| mesh_normal(mesh, mesh.indices[j] as usize);
-            let t = |j| mesh_texture(mesh, mesh.indices[j] as usize);
-            let vertices = [v(i), v(i + 1), v(i + 2)];
-            let normals = if mesh.normals.is_empty() {
-                None
-            } else {
-                Some([n(i), n(i + 1), n(i + 2)])
-            };
-            let texture_coords = if mesh.texcoords.is_empty() {
-                None
-            } else {
-                Some([t(i), t(i + 1), t(i + 2)])
-            };
-            let surface = Triangle::new(vertices, normals, texture_coords);
-            objects.push(Object::new(surface, material.clone()));
+impl From<BoundsPrototype> for BoundingBox {
+    fn from(prototype: BoundsPrototype) -> BoundingBox {
+        let (min, max) = (prototype.min, prototype.max);
+        BoundingBox::axis_aligned(min.0, max.0, min.1, max.1, min.2, max.2)
+    }
+}
+
+impl From<SdfPrototype> for Sdf {
+    fn from(prototype: SdfPrototype) -> Sdf {
+        let translate = |center: (f32, f32, f32), shape| Sdf::Translate {
+            offset: center.into(),
+            shape: Box::new(shape),
+        };
+        let half = |size: (f32, f32, f32)| Vector3::from(size) / 2.0;
+        let all = |shapes: Vec<SdfPrototype>| shapes.into_iter().map(Sdf::from).collect();
+        match prototype {
+            SdfPrototype::Sphere { center, radius } => translate(center, Sdf::Sphere { radius }),
+            SdfPrototype::Box { center, size } => translate(
+                center,
+                Sdf::Box {
+                    half_extents: half(size),
+                },
+            ),
+            SdfPrototype::RoundBox {
+                center,
+                size,
+                radius,
+            } => translate(
+                center,
+                Sdf::RoundBox {
+                    half_extents: half(size),
+                    radius,
+                },
+            ),
+            SdfPrototype::Torus {
+                center,
+                major_radius,
+                minor_radius,
+            } => translate(
+                center,
+                Sdf::Torus {
+                    major_radius,
+                    minor_radius,
+                },
+            ),
+            SdfPrototype::Capsule { start, end, radius } => Sdf::Capsule {
+                start: start.into(),
+                end: end.into(),
+                radius,
+            },
+            SdfPrototype::Translate { offset, shape } => translate(offset, (*shape).into()),
+            SdfPrototype::Union { shapes } => Sdf::Union(all(shapes)),
+            SdfPrototype::Intersection { shapes } => Sdf::Intersection(all(shapes)),
+            SdfPrototype::Subtraction { shapes } => Sdf::Subtraction(all(shapes)),
+            SdfPrototype::SmoothUnion { smoothness, shapes } => Sdf::SmoothUnion {
+                smoothness,
+                shapes: all(shapes),
+            },
+            SdfPrototype::Repeat { period, shape } => Sdf::Repeat {
+                period: period.into(),
+                shape: Box::new((*shape).into()),
+            },
+            SdfPrototype::Twist { rate, shape } => Sdf::Twist {
+                rate,
+                shape: Box::new((*shape).into()),
+            },
+        }
+    }
+}
+
+impl MaterialPrototype {
+    fn compile(self, root: &Path, textures: TextureOptions) -> Result<Material, LoadError> {
+        let color = match self.color {
+            ColorPrototype::Solid(color) => Color::solid(LinSrgb::from_components(color)),
+            ColorPrototype::Texture {
+                texture,
+                filter,
+                wrap,
+            } => {
+                let filter = filter.map_or(textures.filter, Filter::from);
+                let wrap = wrap.map_or(textures.wrap, Wrap::from);
+                let texture = load_texture(&root.join(texture))?;
+                Color::texture(texture.with_filter(filter).with_wrap(wrap, wrap))
+            }
+        };
+        Ok(match self.kind {
+            MaterialKindPrototype::Diffuse => Material::diffuse(color),
+            MaterialKindPrototype::Specular => Material::specular(color),
+            MaterialKindPrototype::Glossy { index, roughness } => {
+                Material::glossy(color, index, roughness)
+            }
+            MaterialKindPrototype::Light => Material::light(color),
+        })
+    }
+}
+
+/// The vertices and faces of a mesh file, along with the material libraries
+/// an obj file names, as its materials are loaded separately.
+struct Mesh {
+    material_libraries: Vec<String>,
+    // The names of the materials in the libraries, which face materials
+    // index into. Naming them keeps a cached mesh right when its libraries
+    // are edited.
+    material_names: Vec<String>,
+    positions: MappedVec<Point3>,
+    normals: MappedVec<Vector3>,
+    texture_coords: MappedVec<TextureCoords>,
+    colors: MappedVec<LinSrgb>,
+    faces: MappedVec<Face>,
+}
+
+impl Mesh {
+    fn load_wavefront(path: &Path) -> Result<Mesh, LoadError> {
+        let root = path.parent().unwrap();
+        let material_libraries = RefCell::new(Vec::new());
+        let (models, materials) = tobj::load_obj_buf(
+            &mut BufReader::new(File::open(path)?),
+            &WAVEFRONT_OPTIONS,
+            |library| {
+                let name = library.to_string_lossy().into_owned();
+                material_libraries.borrow_mut().push(name);
+                tobj::load_mtl(root.join(library))
+            },
+        )?;
+
+        // The models in the file are merged into one mesh. If only some of
+        // them have normals, the rest get zero normals to be shaded flat.
+        let has_normals = models.iter().any(|model| !model.mesh.normals.is_empty());
+        let has_texture_coords = models.iter().any(|model| !model.mesh.texcoords.is_empty());
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut texture_coords = Vec::new();
+        let mut faces = Vec::new();
+        for model in models.iter() {
+            let mesh = &model.mesh;
+            let first = positions.len() as u32;
+            let count = mesh.positions.len() / 3;
+            positions.extend((0..count).map(|i| mesh_vertex(mesh, i)));
+            if has_normals {
+                normals.extend((0..count).map(|i| {
+                    if mesh.normals.is_empty() {
+                        Vector3::new(0.0, 0.0, 0.0)
+                    } else {
+                        mesh_normal(mesh, i)
+                    }
+                }));
+            }
+            if has_texture_coords {
+                texture_coords.extend((0..count).map(|i| mesh_texture(mesh, i)));
+            }
+            let material = mesh.material_id.map_or(NO_MATERIAL, |idx| idx as u32);
+            faces.extend(mesh.indices.chunks_exact(3).map(|indices| Face {
+                vertices: [first + indices[0], first + indices[1], first + indices[2]],
+                material,
+            }));
+        }
+        // Libraries which fail to load are reported when the mesh is built.
+        let material_names = materials
+            .map(|materials| materials.into_iter().map(|m| m.name).collect())
+            .unwrap_or_default();
+        Ok(Mesh {
+            material_libraries: material_libraries.into_inner(),
+            material_names,
+            positions: positions.into(),
+            normals: normals.into(),
+            texture_coords: texture_coords.into(),
+            colors: MappedVec::default(),
+            faces: faces.into(),
+        })
+    }
+
+    fn load_ply(path: &Path) -> Result<Mesh, LoadError> {
+        let ply = load_ply(path)?;
+        let faces = ply
+            .triangles
+            .into_iter()
+            .map(|vertices| Face {
+                vertices,
+                material: NO_MATERIAL,
+            })
+            .collect();
+        Ok(Mesh {
+            material_libraries: Vec::new(),
+            material_names: Vec::new(),
+            positions: ply.positions.into(),
+            normals: ply.normals.into(),
+            texture_coords: ply.texture_coords.into(),
+            colors: ply.colors.into(),
+            faces,
+        })
+    }
+
+    fn from_gltf(gltf: GltfMesh) -> Mesh {
+        Mesh {
+            material_libraries: Vec::new(),
+            material_names: Vec::new(),
+            positions: gltf.positions.into(),
+            normals: gltf.normals.into(),
+            texture_coords: gltf.texture_coords.into(),
+            colors: gltf.colors.into(),
+            faces: gltf.faces.into(),
+        }
+    }
+
+    fn load_stl(path: &Path, weld: bool) -> Result<Mesh, LoadError> {
+        let stl = load_stl(path, weld)?;
+        let faces = stl
+            .triangles
+            .into_iter()
+            .map(|vertices| Face {
+                vertices,
+                material: NO_MATERIAL,
+            })
+            .collect();
+        Ok(Mesh {
+            material_libraries: Vec::new(),
+            material_names: Vec::new(),
+            positions: stl.positions.into(),
+            normals: MappedVec::default(),
+            texture_coords: MappedVec::default(),
+            colors: MappedVec::default(),
+            faces,
+        })
+    }
+
+    fn smoothed(self, smoothing: Option<&SmoothingPrototype>) -> Mesh {
+        match smoothing {
+            Some(smoothing) => self.smooth_normals(smoothing),
+            None => self,
+        }
+    }
+
+    /// Replaces the mesh's normals with ones averaged over the faces around
+    /// each vertex, weighted by their areas or by the angles of their corners
+    /// there. Weighting by angle means how the faces happen to be triangulated
+    /// doesn't matter. Faces which meet at more than the crease angle stay
+    /// sharp, so vertices along such creases are split into one per side.
+    fn smooth_normals(self, smoothing: &SmoothingPrototype) -> Mesh {
+        // Faces with no area have no normal, and add nothing to their
+        // neighbours' normals.
+        let face_normals: Vec<Option<Vector3>> = self
+            .faces
+            .iter()
+            .map(|face| {
+                let [a, b, c] = face.vertices.map(|i| self.positions[i as usize]);
+                let normal = (b - a).cross(c - a);
+                let norm = normal.norm();
+                (norm > 0.0).then(|| normal / norm)
+            })
+            .collect();
+        // Faces meet wherever their vertices are in the same place, even if
+        // the vertices differ in other attributes, such as along the seams of
+        // a texture, or aren't shared at all.
+        let mut groups = HashMap::new();
+        let group: Vec<usize> = self
+            .positions
+            .iter()
+            .map(|p| {
+                // Adding zero turns negative zeros positive.
+                let key = [p.x(), p.y(), p.z()].map(|c| (c + 0.0).to_bits());
+                let next = groups.len();
+                *groups.entry(key).or_insert(next)
+            })
+            .collect();
+        let mut adjacent = vec![Vec::new(); groups.len()];
+        for (f, face) in self.faces.iter().enumerate() {
+            let corners = face.vertices.map(|i| self.positions[i as usize]);
+            for (k, &vertex) in face.vertices.iter().enumerate() {
+                let edge1 = corners[(k + 1) % 3] - corners[k];
+                let edge2 = corners[(k + 2) % 3] - corners[k];
+                let cross = edge1.cross(edge2).norm();
+                let weight = match smoothing.weighting {
+                    WeightingPrototype::Area => cross,
+                    WeightingPrototype::Angle => cross.atan2(edge1.dot(edge2)),
+                };
+                adjacent[group[vertex as usize]].push((f, weight));
+            }
+        }
+
+        let min_cos = smoothing.crease_angle.to_radians().cos();
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut texture_coords = Vec::new();
+        let mut colors = Vec::new();
+        let mut faces = Vec::with_capacity(self.faces.len());
+        let mut vertices = HashMap::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let vertices = face.vertices.map(|vertex| {
+                let sum = adjacent[group[vertex as usize]]
+                    .iter()
+                    .filter_map(|&(g, weight)| {
+                        let other = face_normals[g]?;
+                        let smooth = face_normals[f].is_none_or(|own| own.dot(other) >= min_cos);
+                        smooth.then_some(weight * other)
+                    })
+                    .fold(Vector3::new(0.0, 0.0, 0.0), |sum, normal| sum + normal);
+                let normal = if sum.norm_squared() > 0.0 {
+                    sum.normalize()
+                } else {
+                    sum
+                };
+                let key = (
+                    vertex,
+                    [normal.x(), normal.y(), normal.z()].map(f32::to_bits),
+                );
+                *vertices.entry(key).or_insert_with(|| {
+                    let i = vertex as usize;
+                    positions.push(self.positions[i]);
+                    normals.push(normal);
+                    if !self.texture_coords.is_empty() {
+                        texture_coords.push(self.texture_coords[i]);
+                    }
+                    if !self.colors.is_empty() {
+                        colors.push(self.colors[i]);
+                    }
+                    positions.len() as u32 - 1
+                })
+            });
+            faces.push(Face {
+                vertices,
+                material: face.material,
+            });
+        }
+        Mesh {
+            material_libraries: self.material_libraries,
+            material_names: self.material_names,
+            positions: positions.into(),
+            normals: normals.into(),
+            texture_coords: texture_coords.into(),
+            colors: colors.into(),
+            faces: faces.into(),
+        }
+    }
+
+    /// Builds the mesh with the materials from its libraries, except for
+    /// those named in `overrides`, which are replaced.
+    fn into_triangle_mesh(
+        self,
+        path: &Path,
+        mut overrides: HashMap<String, Material>,
+        textures: TextureOptions,
+    ) -> Result<TriangleMesh, LoadError> {
+        // Loading the material libraries through an obj file which only names
+        // them gives their materials without reading the mesh again.
+        let root = path.parent().unwrap();
+        let libraries: String = self
+            .material_libraries
+            .iter()
+            .map(|library| format!("mtllib {}\n", library))
+            .collect();
+        let (_, raw_materials) =
+            tobj::load_obj_buf(&mut libraries.as_bytes(), &WAVEFRONT_OPTIONS, |library| {
+                tobj::load_mtl(root.join(library))
+            })?;
+
+        let raw_materials = raw_materials?;
+        // A misspelt name would otherwise be silently ignored.
+        if let Some(name) = overrides
+            .keys()
+            .find(|&name| raw_materials.iter().all(|m| m.name != *name))
+        {
+            return Err(LoadError::Parse(format!(
+                "{} has no material named {}",
+                path.display(),
+                name
+            )));
         }
+        // Faces without a material, or whose material has since been removed
+        // from the libraries, get the default one after the others.
+        let default_material = Material::diffuse(Color::solid(LinSrgb::new(1.0, 1.0, 1.0)));
+        let mut materials = self
+            .material_names
+            .iter()
+            .map(|name| {
+                if let Some(material) = overrides.remove(name) {
+                    return Ok(material);
+                }
+                match raw_materials.iter().find(|m| m.name == *name) {
+                    Some(m) => convert_material(root, m, textures),
+                    None => Ok(default_material.clone()),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        materials.push(default_material);
+        Ok(self.with_materials(materials))
+    }
+
+    /// Gives every face of the mesh the same material.
+    fn with_material(self, material: Material) -> TriangleMesh {
+        self.with_materials(vec![material])
+    }
+
+    /// Builds the mesh with the materials its faces index into, where faces
+    /// past the last material get the last one.
+    fn with_materials(self, materials: Vec<Material>) -> TriangleMesh {
+        TriangleMesh::new(
+            self.positions,
+            self.normals,
+            self.texture_coords,
+            self.colors,
+            self.faces,
+            materials,
+        )
+    }
+}
+
+impl Cacheable for Mesh {
+    fn write(&self, cache: &mut CacheWriter) {
+        cache.write_slice(&self.material_libraries);
+        cache.write_slice(&self.material_names);
+        cache.write_pod_slice(&self.positions);
+        cache.write_pod_slice(&self.normals);
+        cache.write_pod_slice(&self.texture_coords);
+        cache.write_pod_slice(&self.colors);
+        cache.write_pod_slice(&self.faces);
+    }
+
+    fn read(cache: &mut CacheReader) -> Option<Self> {
+        let mesh = Mesh {
+            material_libraries: cache.read_vec()?,
+            material_names: cache.read_vec()?,
+            positions: cache.read_mapped_vec()?,
+            normals: cache.read_mapped_vec()?,
+            texture_coords: cache.read_mapped_vec()?,
+            colors: cache.read_mapped_vec()?,
+            faces: cache.read_mapped_vec()?,
+        };
+        let vertices = mesh.positions.len();
+        let lengths = [
+            mesh.normals.len(),
+            mesh.texture_coords.len(),
+            mesh.colors.len(),
+        ];
+        let valid = lengths.iter().all(|&len| len == 0 || len == vertices)
+            && mesh
+                .faces
+                .iter()
+                .all(|face| face.vertices.iter().all(|&i| (i as usize) < vertices));
+        valid.then_some(mesh)
     }
-    Ok(objects)
 }
 
 fn mesh_vertex(mesh: &tobj::Mesh, i: usize) -> Point3 {
@@ -247,67 +1380,373 @@ fn mesh_texture(mesh: &tobj::Mesh, i: usize) -> TextureCoords {
     TextureCoords::new(x, y)
 }
 
-fn convert_material(root: &Path, m: &tobj::Material) -> Result<Material, LoadError> {
-    let emissive = emissive_color(m);
+/// Loads the polylines from the line elements of an obj file as curves,
+/// tapering linearly from root to tip.
+fn load_hair(path: &Path, widths: (f32, f32), shape: CurveShape) -> Result<Vec<Curve>, LoadError> {
+    let mut vertices = Vec::new();
+    let mut curves = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let mut coords = tokens.map(|t| t.parse::<f32>());
+                match (coords.next(), coords.next(), coords.next()) {
+                    (Some(Ok(x)), Some(Ok(y)), Some(Ok(z))) => vertices.push(Point3::new(x, y, z)),
+                    _ => return Err(LoadError::Parse(format!("invalid vertex: {}", line))),
+                }
+            }
+            Some("l") => {
+                let polyline = tokens
+                    .map(|t| {
+                        // Indices are 1-based, or relative to the end if
+                        // negative, and may be followed by a texture index.
+                        let index = t.split('/').next().and_then(|i| i.parse::<isize>().ok());
+                        let index = match index {
+                            Some(i) if i < 0 => vertices.len() as isize + i,
+                            Some(i) => i - 1,
+                            None => -1,
+                        };
+                        usize::try_from(index)
+                            .ok()
+                            .and_then(|i| vertices.get(i).cloned())
+                            .ok_or_else(|| LoadError::Parse(format!("invalid line: {}", line)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                curves.extend(polyline_curves(&polyline, widths, shape));
+            }
+            _ => (),
+        }
+    }
+    Ok(curves)
+}
+
+/// Converts a polyline into a smooth Catmull-Rom spline, represented as one
+/// Bézier curve per line segment.
+fn polyline_curves(points: &[Point3], widths: (f32, f32), shape: CurveShape) -> Vec<Curve> {
+    let n = points.len();
+    let width = |i: usize| {
+        let t = i as f32 / (n - 1) as f32;
+        widths.0 * (1.0 - t) + widths.1 * t
+    };
+    (0..n.saturating_sub(1))
+        .map(|i| {
+            let prev = points[i.saturating_sub(1)];
+            let next = points[(i + 2).min(n - 1)];
+            let (p1, p2) = (points[i], points[i + 1]);
+            Curve::new(
+                [p1, p1 + (p2 - prev) / 6.0, p2 - (next - p1) / 6.0, p2],
+                (width(i), width(i + 1)),
+                shape,
+            )
+        })
+        .collect()
+}
+
+fn convert_material(
+    root: &Path,
+    m: &tobj::Material,
+    textures: TextureOptions,
+) -> Result<Material, LoadError> {
+    // Keys from the PBR extension to MTL, which tobj leaves unparsed.
+    let param = |key: &str| m.unknown_param.get(key).map(String::as_str);
+    let number = |key: &str| param(key).and_then(|value| value.trim().parse::<f32>().ok());
+    let emissive = m.emissive.unwrap_or_default();
+    if let Some(map) = param("map_Ke") {
+        let tint = m.emissive.unwrap_or([1.0; 3]);
+        let texture = TextureMap::parse(root, map, textures).load()?;
+        return Ok(Material::light(Color::texture(
+            texture.tinted(to_linsrgb(&tint)),
+        )));
+    }
     if color_power(&emissive) > 0.0 {
         return Ok(Material::light(to_color(&emissive)));
     }
 
     let specular = m.specular.unwrap_or_default();
     let diffuse = m.diffuse.unwrap_or_default();
+    let specular_color = match m.specular_texture {
+        Some(ref map) => Color::texture(
+            TextureMap::parse(root, map, textures)
+                .load()?
+                .tinted(to_linsrgb(&specular)),
+        ),
+        None => to_color(&specular),
+    };
+    let diffuse_color = match m.diffuse_texture {
+        Some(ref map) => Color::texture(TextureMap::parse(root, map, textures).load()?),
+        None => to_color(&diffuse),
+    };
+
+    // PBR roughness is perceptual, so is squared as in glTF. Otherwise it
+    // comes from the Phong exponent, which map_Ns scales.
+    let roughness = match (param("map_Pr"), number("Pr")) {
+        (Some(map), factor) => {
+            let factor = factor.unwrap_or(1.0);
+            load_scalar(root, map, textures, false, |value| (value * factor).powi(2))?
+        }
+        (None, Some(factor)) => Scalar::constant(factor.powi(2)),
+        (None, None) => {
+            let shininess = m.shininess.unwrap_or(0.0);
+            let phong = |exponent: f32| (2.0 / (2.0 + exponent)).sqrt();
+            match m.shininess_texture {
+                Some(ref map) => {
+                    load_scalar(root, map, textures, false, |value| phong(value * shininess))?
+                }
+                None => Scalar::constant(phong(shininess)),
+            }
+        }
+    };
 
-    let roughness = (2.0 / (2.0 + m.shininess.unwrap_or(0.0))).sqrt();
-    if m.illumination_model == Some(7) {
-        Ok(Material::transparent(
-            to_color(&m.specular.unwrap_or_default()),
-            m.optical_density.unwrap_or(1.0),
-            roughness,
-        ))
-    } else if let Some(ref texture) = m.diffuse_texture {
-        let texture_path = root.join(texture);
-        let img = image::open(texture_path)?.into_rgb8();
-        let color = Color::texture(Texture::from_image(img));
-        Ok(Material::glossy(
-            color,
-            m.optical_density.unwrap_or(1.0),
-            roughness,
-        ))
-    } else if color_power(&specular) > 5.0 * color_power(&diffuse) {
-        Ok(Material::metallic(
-            to_color(&specular),
-            m.optical_density.unwrap_or(1.0),
-            roughness,
-        ))
+    // Dissolve is opacity, and Tr its opposite.
+    let opacity = match (m.dissolve_texture.as_deref(), m.dissolve, number("Tr")) {
+        (Some(map), factor, _) => {
+            let factor = factor.unwrap_or(1.0);
+            load_scalar(root, map, textures, true, |value| value * factor)?
+        }
+        (None, Some(dissolve), _) => Scalar::constant(dissolve),
+        (None, None, Some(transparency)) => Scalar::constant(1.0 - transparency),
+        (None, None, None) => Scalar::constant(1.0),
+    };
+
+    let index = m.optical_density.unwrap_or(1.0);
+    let material = if m.illumination_model == Some(7) {
+        let filter = param("Tf").and_then(parse_color).unwrap_or([1.0; 3]);
+        Material::transparent(specular_color, index, 0.0).with_filter(to_linsrgb(&filter))
+    } else if param("Pm").is_some() || param("map_Pm").is_some() {
+        // PBR materials blend between glossy and metallic by metalness, both
+        // colored by their diffuse color.
+        let metalness = match (param("map_Pm"), number("Pm")) {
+            (Some(map), factor) => {
+                let factor = factor.unwrap_or(1.0);
+                load_scalar(root, map, textures, false, |value| value * factor)?
+            }
+            (None, factor) => Scalar::constant(factor.unwrap_or(0.0)),
+        };
+        let index = m.optical_density.unwrap_or(DIELECTRIC_INDEX);
+        Material::glossy(diffuse_color, index, 0.0).with_metalness(metalness)
+    } else if m.diffuse_texture.is_some() {
+        Material::glossy(diffuse_color, index, 0.0)
+    } else if color_power(&specular) > 5.0 * color_power(&diffuse) || m.specular_texture.is_some() {
+        Material::metallic(specular_color, index, 0.0)
     } else {
-        Ok(Material::glossy(
-            to_color(&diffuse),
-            m.optical_density.unwrap_or(1.0),
-            roughness,
-        ))
+        Material::glossy(diffuse_color, index, 0.0)
+    };
+    let material = material.with_roughness(roughness).with_opacity(opacity);
+
+    // Sheen is a color, or one value for grey, which map_Ps is tinted by.
+    let sheen = param("Ps").and_then(parse_color);
+    let material = match (param("map_Ps"), sheen) {
+        (Some(map), sheen) => {
+            let texture = TextureMap::parse(root, map, textures).load()?;
+            let tint = to_linsrgb(&sheen.unwrap_or([1.0; 3]));
+            material.with_sheen(Color::texture(texture.tinted(tint)))
+        }
+        (None, Some(sheen)) if color_power(&sheen) > 0.0 => material.with_sheen(to_color(&sheen)),
+        (None, _) => material,
+    };
+
+    // Exporters often write normal maps as bump maps rather than with "norm".
+    match (param("norm"), m.normal_texture.as_deref()) {
+        (Some(map), _) => Ok(material.with_bump(load_bump(root, map, textures, true)?)),
+        (None, Some(map)) => Ok(material.with_bump(load_bump(root, map, textures, false)?)),
+        (None, None) => Ok(material),
     }
 }
 
-fn emissive_color(material: &tobj::Material) -> [f32; 3] {
-    let black = [0.0; 3];
-    material
-        .unknown_param
-        .get("Ke")
-        .map_or(black, |ke| parse_triple(ke).unwrap_or(black))
+// The index of refraction PBR materials have unless they give one, as for
+// glTF.
+const DIELECTRIC_INDEX: f32 = 1.5;
+
+// How a texture is filtered and wrapped.
+#[derive(Copy, Clone, Debug, Default)]
+struct TextureOptions {
+    filter: Filter,
+    wrap: Wrap,
+}
+
+// A texture statement in an MTL file, such as "-imfchan r -clamp on rough.png".
+// Besides the standard options, "-filter" chooses nearest, bilinear or
+// trilinear filtering, and "-wrap" repeat, clamp or mirror wrapping. Options
+// the renderer has no use for are skipped.
+struct TextureMap {
+    file: PathBuf,
+    // Which channel scalar maps read: r, g, b, m (matte, taken as alpha), l
+    // (luminance) or z (depth, also taken as luminance).
+    channel: Option<char>,
+    // Scales the slopes of bump maps.
+    bump_multiplier: f32,
+    options: TextureOptions,
 }
 
-fn parse_triple<T: Default + FromStr>(s: &str) -> Option<[T; 3]> {
-    let mut result = [T::default(), T::default(), T::default()];
-    for (i, v) in s.split_whitespace().enumerate().take(3) {
-        match v.parse() {
-            Ok(f) => result[i] = f,
-            _ => return None,
+impl TextureMap {
+    // Options the statement leaves out are taken from the defaults.
+    fn parse(root: &Path, statement: &str, defaults: TextureOptions) -> TextureMap {
+        let mut channel = None;
+        let mut bump_multiplier = 1.0;
+        let mut options = defaults;
+        let mut tokens = statement.split_whitespace().peekable();
+        while let Some(option) = tokens.next_if(|token| token.starts_with('-')) {
+            match option {
+                "-imfchan" => channel = tokens.next().and_then(|value| value.chars().next()),
+                "-bm" => {
+                    if let Some(value) = tokens.next().and_then(|value| value.parse().ok()) {
+                        bump_multiplier = value;
+                    }
+                }
+                "-clamp" => match tokens.next() {
+                    Some("on") => options.wrap = Wrap::Clamp,
+                    Some("off") => options.wrap = Wrap::Repeat,
+                    _ => (),
+                },
+                "-filter" => match tokens.next() {
+                    Some("nearest") => options.filter = Filter::Nearest,
+                    Some("bilinear") => options.filter = Filter::Bilinear,
+                    Some("trilinear") => options.filter = Filter::Trilinear,
+                    _ => (),
+                },
+                "-wrap" => match tokens.next() {
+                    Some("repeat") => options.wrap = Wrap::Repeat,
+                    Some("clamp") => options.wrap = Wrap::Clamp,
+                    Some("mirror") => options.wrap = Wrap::Mirror,
+                    _ => (),
+                },
+                "-mm" => {
+                    tokens.next();
+                    tokens.next();
+                }
+                // Up to three numbers follow these.
+                "-o" | "-s" | "-t" => {
+                    for _ in 0..3 {
+                        tokens.next_if(|token| token.parse::<f32>().is_ok());
+                    }
+                }
+                _ => {
+                    tokens.next();
+                }
+            }
+        }
+        // File names may contain spaces.
+        let file = tokens.collect::<Vec<_>>().join(" ");
+        TextureMap {
+            file: root.join(file),
+            channel,
+            bump_multiplier,
+            options,
+        }
+    }
+
+    fn load(&self) -> Result<Texture, LoadError> {
+        Ok(self.sampled(load_texture(&self.file)?))
+    }
+
+    // Filters and wraps a texture loaded from the map as it asks.
+    fn sampled(&self, texture: Texture) -> Texture {
+        let TextureOptions { filter, wrap } = self.options;
+        texture.with_filter(filter).with_wrap(wrap, wrap)
+    }
+}
+
+fn load_texture(path: &Path) -> Result<Texture, LoadError> {
+    Ok(Texture::from_image(image::open(path)?.into_rgb8()))
+}
+
+// Loads a map of numbers, such as roughness, which aren't stored as sRGB. Maps
+// read luminance unless they choose a channel, but opacity maps read alpha if
+// the image has it.
+fn load_scalar(
+    root: &Path,
+    statement: &str,
+    textures: TextureOptions,
+    opacity: bool,
+    convert: impl Fn(f32) -> f32,
+) -> Result<Scalar, LoadError> {
+    let map = TextureMap::parse(root, statement, textures);
+    let image = image::open(&map.file)?;
+    let texture = load_values(image, map.channel, opacity, convert);
+    Ok(Scalar::texture(map.sampled(texture)))
+}
+
+fn load_values(
+    image: image::DynamicImage,
+    channel: Option<char>,
+    opacity: bool,
+    convert: impl Fn(f32) -> f32,
+) -> Texture {
+    let channel = match channel {
+        Some(channel) => channel,
+        None if opacity && image.color().has_alpha() => 'm',
+        None => 'l',
+    };
+    let image = image.into_rgba8();
+    let values = image
+        .pixels()
+        .map(|image::Rgba([r, g, b, a])| {
+            let value = match channel {
+                'r' => *r,
+                'g' => *g,
+                'b' => *b,
+                'm' => *a,
+                _ => ((*r as u32 + *g as u32 + *b as u32) / 3) as u8,
+            };
+            convert(value as f32 / 255.0)
+        })
+        .collect();
+    Texture::from_values(image.width() as usize, image.height() as usize, values)
+}
+
+// Loads a normal map, or a bump map which may really be a normal map.
+fn load_bump(
+    root: &Path,
+    statement: &str,
+    textures: TextureOptions,
+    normal: bool,
+) -> Result<Bump, LoadError> {
+    let map = TextureMap::parse(root, statement, textures);
+    let image = image::open(&map.file)?;
+    if normal || is_normal_map(&image.to_rgb8()) {
+        let texture = Texture::from_linear_image(image.into_rgb8());
+        Ok(Bump::normal(map.sampled(texture)))
+    } else {
+        let texture = load_values(image, map.channel, false, |value| value);
+        Ok(Bump::height(map.sampled(texture), map.bump_multiplier))
+    }
+}
+
+// Tangent-space normal maps are mostly pale blue, as their normals mostly point
+// straight out of the surface, whereas height maps are usually grey.
+fn is_normal_map(image: &image::RgbImage) -> bool {
+    let mut sums = [0.0; 3];
+    for image::Rgb(pixel) in image.pixels() {
+        for (sum, value) in sums.iter_mut().zip(pixel) {
+            *sum += *value as f64 / 255.0;
         }
     }
-    Some(result)
+    let [r, g, b] = sums.map(|sum| sum / image.pixels().len().max(1) as f64);
+    (r - 0.5).abs() < 0.1 && (g - 0.5).abs() < 0.1 && b > 0.75
+}
+
+// Parses a color, such as a transmission filter, given as "r g b", or one value
+// for grey. Colors given as spectra or CIE XYZ aren't supported.
+fn parse_color(value: &str) -> Option<[f32; 3]> {
+    let values = value
+        .split_whitespace()
+        .map(str::parse::<f32>)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    match values[..] {
+        [grey] => Some([grey; 3]),
+        [r, g, b] => Some([r, g, b]),
+        _ => None,
+    }
+}
+
+fn to_linsrgb(c: &[f32; 3]) -> LinSrgb {
+    LinSrgb::new(c[0], c[1], c[2])
 }
 
 fn to_color(c: &[f32; 3]) -> Color {
-    Color::solid(LinSrgb::new(c[0], c[1], c[2]))
+    Color::solid(to_linsrgb(c))
 }
 
 fn color_power(color: &[f32; 3]) -> f32 {
@@ -316,12 +1755,104 @@ fn color_power(color: &[f32; 3]) -> f32 {
 
 #[derive(Debug, thiserror::Error)]
 pub enum LoadError {
+    #[error("gltf error: {0}")]
+    Gltf(#[from] GltfError),
     #[error("image error: {0}")]
     ImageError(#[from] image::ImageError),
     #[error("io error: {0}")]
     Io(#[from] io::Error),
+    #[error("parse error: {0}")]
+    Parse(String),
     #[error("obj error: {0}")]
     Wavefront(#[from] tobj::LoadError),
+    #[error("ply error: {0}")]
+    Ply(#[from] PlyError),
+    #[error("stl error: {0}")]
+    Stl(#[from] StlError),
     #[error("yaml error: {0}")]
     Yaml(#[from] serde_yaml::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Converts the only material in an MTL file, whose textures are in a
+    // directory of their own.
+    fn material(name: &str, mtl: &str) -> Material {
+        let root = std::env::temp_dir().join(format!("rays-mtl-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        image::RgbImage::from_pixel(1, 1, image::Rgb([255, 255, 255]))
+            .save(root.join("white.png"))
+            .unwrap();
+        let mtl = format!("newmtl test\n{}", mtl);
+        let (materials, _) = tobj::load_mtl_buf(&mut mtl.as_bytes()).unwrap();
+        convert_material(&root, &materials[0], TextureOptions::default()).unwrap()
+    }
+
+    fn constant(scalar: &Scalar) -> f32 {
+        match scalar {
+            Scalar::Constant(value) => *value,
+            Scalar::Texture(_) => panic!("expected a constant"),
+        }
+    }
+
+    #[test]
+    fn dissolve_takes_precedence_over_transparency() {
+        let opacity = |mtl| constant(material("dissolve", mtl).opacity());
+        assert_eq!(opacity("Kd 1 1 1\n"), 1.0);
+        assert_eq!(opacity("Tr 0.25\n"), 0.75);
+        assert_eq!(opacity("Tr 0.9\nd 0.3\n"), 0.3);
+        let mapped = material("dissolve-map", "d 0.5\nTr 0.9\nmap_d white.png\n");
+        assert!(matches!(mapped.opacity(), Scalar::Texture(_)));
+        assert!(mapped.has_cutout());
+    }
+
+    #[test]
+    fn pbr_keys() {
+        let pbr = material("pbr", "Kd 1 0 0\nPr 0.5\nPm 0.75\n");
+        assert_eq!(constant(pbr.roughness().unwrap()), 0.25);
+        assert_eq!(constant(pbr.metalness().unwrap()), 0.75);
+        // Without Pr, roughness comes from the Phong exponent.
+        let phong = material("phong", "Kd 1 0 0\nNs 2\n");
+        assert_eq!(constant(phong.roughness().unwrap()), 0.5f32.sqrt());
+        assert_eq!(constant(phong.metalness().unwrap()), 0.0);
+        let textured = material("pbr-map", "Pr 0.5\nmap_Pr white.png\nmap_Pm white.png\n");
+        assert!(matches!(textured.roughness(), Some(Scalar::Texture(_))));
+        assert!(matches!(textured.metalness(), Some(Scalar::Texture(_))));
+    }
+
+    #[test]
+    fn sheen_keys() {
+        assert!(material("no-sheen", "Kd 1 1 1\nPs 0\n").sheen().is_none());
+        let sheen = material("sheen", "Kd 1 1 1\nPs 0.2 0.1 0\n");
+        assert!(
+            matches!(sheen.sheen(), Some(Color::Solid(c)) if *c == LinSrgb::new(0.2, 0.1, 0.0))
+        );
+        let mapped = material("sheen-map", "Kd 1 1 1\nmap_Ps white.png\n");
+        assert!(matches!(mapped.sheen(), Some(Color::Texture(_))));
+    }
+
+    #[test]
+    fn texture_map_options() {
+        let root = Path::new("scene");
+        let defaults = TextureOptions {
+            filter: Filter::Trilinear,
+            wrap: Wrap::Mirror,
+        };
+        let map = TextureMap::parse(root, "-bm 2 -clamp on -s 1 1 fine grain.png", defaults);
+        assert_eq!(map.file, root.join("fine grain.png"));
+        assert_eq!(map.bump_multiplier, 2.0);
+        assert_eq!(map.options.filter, Filter::Trilinear);
+        assert_eq!(map.options.wrap, Wrap::Clamp);
+
+        let statement = "-filter bilinear -wrap mirror -imfchan g rough.png";
+        let map = TextureMap::parse(root, statement, TextureOptions::default());
+        assert_eq!(map.channel, Some('g'));
+        assert_eq!(map.options.filter, Filter::Bilinear);
+        assert_eq!(map.options.wrap, Wrap::Mirror);
+
+        let map = TextureMap::parse(root, "-clamp off rough.png", defaults);
+        assert_eq!(map.options.wrap, Wrap::Repeat);
+    }
+}
@@ -85,7 +85,13 @@ struct App {
     /// Maximum number of reflections per sample
     #[structopt(long, default_value = "5")]
     reflections: u32,
-    /// The scene to render
+    /// Trace camera rays through each 8x8 tile of pixels as one packet
+    #[structopt(long)]
+    packets: bool,
+    /// Print a report on the quality of the scene's BVH
+    #[structopt(long)]
+    bvh_report: bool,
+    /// The scene directory, or glTF file, to render
     scene: String,
     /// The output image file
     output: String,
@@ -98,6 +104,7 @@ fn main() {
         height: app.height,
         samples_per_pixel: app.samples,
         max_reflections: app.reflections,
+        packets: app.packets,
     };
 
     let scene = {
@@ -107,6 +114,9 @@ fn main() {
             std::process::exit(1);
         })
     };
+    if app.bvh_report {
+        println!("{}", scene.objects.report());
+    }
 
     let img = {
         let progress = RenderProgress::new(renderer.width);
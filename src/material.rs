@@ -15,14 +15,40 @@ pub enum Color {
     Texture(Arc<Texture>),
 }
 
+/// A number which may vary across a surface, such as its roughness. Textures
+/// give it by their red channel.
+#[derive(Clone, Debug)]
+pub enum Scalar {
+    Constant(f32),
+    Texture(Arc<Texture>),
+}
+
+/// Fine detail which tilts a surface's shading normal without changing its
+/// shape.
+#[derive(Clone, Debug)]
+pub enum Bump {
+    /// A tangent-space normal map, whose red, green and blue channels give the
+    /// normal along the surface's tangent, its bitangent and its normal.
+    Normal(Arc<Texture>),
+    /// A height map, given by its red channel, whose slope from one texel to
+    /// the next is multiplied by a scale.
+    Height(Arc<Texture>, f32),
+}
+
 #[derive(Clone, Debug)]
 enum Kind {
     Emissive,
     Ggx {
         index: f32,
-        roughness: f32,
-        metallic: bool,
+        roughness: Scalar,
+        // Metals reflect their color, and absorb what they don't reflect.
+        // Anything between blends the two behaviours.
+        metalness: Scalar,
         transparent: bool,
+        // Tints the light refracted through transparent materials.
+        filter: LinSrgb,
+        // Added to the diffuse color where light grazes the surface.
+        sheen: Option<Color>,
     },
 }
 
@@ -30,8 +56,20 @@ enum Kind {
 pub struct Material {
     color: Color,
     kind: Kind,
+    // Where a surface is partly opaque, rays pass through it the rest of the
+    // time. Opacity maps instead cut away the surface wherever they fall below
+    // the cutoff, so that meshes are hit and cast shadows in their shape.
+    opacity: Scalar,
+    bump: Option<Bump>,
 }
 
+// The opacity below which opacity maps cut surfaces away.
+const ALPHA_CUTOFF: f32 = 0.5;
+
+// Limits how far footprints stretch where rays glance across surfaces, which
+// would otherwise blur textures away entirely.
+const GRAZING_COSINE: f32 = 0.1;
+
 impl Color {
     pub fn solid(color: LinSrgb) -> Self {
         Color::Solid(color)
@@ -41,10 +79,75 @@ impl Color {
         Color::Texture(Arc::new(texture))
     }
 
-    fn sample(&self, coords: TextureCoords) -> LinSrgb {
+    fn sample(&self, coords: TextureCoords, footprint: f32) -> LinSrgb {
         match self {
             Color::Solid(color) => *color,
-            Color::Texture(texture) => texture[coords],
+            Color::Texture(texture) => texture.sample(coords, footprint),
+        }
+    }
+}
+
+impl Scalar {
+    pub fn constant(value: f32) -> Self {
+        Scalar::Constant(value)
+    }
+
+    pub fn texture(texture: Texture) -> Self {
+        Scalar::Texture(Arc::new(texture))
+    }
+
+    fn sample(&self, coords: TextureCoords, footprint: f32) -> f32 {
+        match self {
+            Scalar::Constant(value) => *value,
+            Scalar::Texture(texture) => texture.sample(coords, footprint).red,
+        }
+    }
+}
+
+impl Bump {
+    pub fn normal(texture: Texture) -> Self {
+        Bump::Normal(Arc::new(texture))
+    }
+
+    pub fn height(texture: Texture, scale: f32) -> Self {
+        Bump::Height(Arc::new(texture), scale)
+    }
+
+    // Tilts the shading normal at an intersection. The bitangent is taken to
+    // be the normal crossed with the tangent, so texture coordinates mirrored
+    // across a seam flip the green channel of normal maps.
+    fn apply(&self, int: &Intersection, footprint: f32) -> Vector3 {
+        let normal = int.normal;
+        let tangent = (int.tangent - normal * normal.dot(int.tangent)).normalize();
+        let bitangent = normal.cross(tangent);
+        let local = match self {
+            Bump::Normal(texture) => {
+                let texel = texture.sample(int.texture_coords, footprint);
+                Vector3::new(
+                    2.0 * texel.red - 1.0,
+                    2.0 * texel.green - 1.0,
+                    2.0 * texel.blue - 1.0,
+                )
+            }
+            Bump::Height(texture, scale) => {
+                let (width, height) = texture.size();
+                let du = TextureCoords::new(1.0 / width as f32, 0.0);
+                let dv = TextureCoords::new(0.0, 1.0 / height as f32);
+                let coords = int.texture_coords;
+                // Slopes are measured between neighbouring pixels of the
+                // full-size texture, however wide the ray's footprint.
+                let height = |coords| texture.sample(coords, 0.0).red;
+                let slope = |step| (height(coords + step) - height(coords - step)) * 0.5 * scale;
+                Vector3::new(-slope(du), -slope(dv), 1.0)
+            }
+        };
+        let bumped = tangent * local.x() + bitangent * local.y() + normal * local.z();
+        // Normals which are degenerate, or tilt through the surface, are
+        // ignored.
+        if bumped.norm_squared() > 0.0 && bumped.dot(normal) > 0.0 {
+            bumped.normalize()
+        } else {
+            normal
         }
     }
 }
@@ -54,6 +157,8 @@ impl Material {
         Material {
             color,
             kind: Kind::Emissive,
+            opacity: Scalar::constant(1.0),
+            bump: None,
         }
     }
 
@@ -66,39 +171,93 @@ impl Material {
     }
 
     pub fn glossy(color: Color, index: f32, roughness: f32) -> Self {
-        Material {
-            color,
-            kind: Kind::Ggx {
-                index,
-                roughness,
-                metallic: false,
-                transparent: false,
-            },
-        }
+        Material::ggx(color, index, roughness, 0.0, false)
     }
 
     pub fn metallic(color: Color, index: f32, roughness: f32) -> Self {
-        Material {
-            color,
-            kind: Kind::Ggx {
-                index,
-                roughness,
-                metallic: true,
-                transparent: false,
-            },
-        }
+        Material::ggx(color, index, roughness, 1.0, false)
     }
 
     pub fn transparent(color: Color, index: f32, roughness: f32) -> Self {
+        Material::ggx(color, index, roughness, 0.0, true)
+    }
+
+    fn ggx(color: Color, index: f32, roughness: f32, metalness: f32, transparent: bool) -> Self {
         Material {
             color,
             kind: Kind::Ggx {
                 index,
-                roughness,
-                metallic: false,
-                transparent: true,
+                roughness: Scalar::constant(roughness),
+                metalness: Scalar::constant(metalness),
+                transparent,
+                filter: LinSrgb::new(1.0, 1.0, 1.0),
+                sheen: None,
             },
+            opacity: Scalar::constant(1.0),
+            bump: None,
+        }
+    }
+
+    /// Replaces the roughness of a glossy, metallic or transparent material.
+    pub fn with_roughness(mut self, roughness: Scalar) -> Self {
+        if let Kind::Ggx { roughness: r, .. } = &mut self.kind {
+            *r = roughness;
+        }
+        self
+    }
+
+    /// Replaces the metalness of a glossy or metallic material, from zero for
+    /// glossy to one for metallic.
+    pub fn with_metalness(mut self, metalness: Scalar) -> Self {
+        if let Kind::Ggx { metalness: m, .. } = &mut self.kind {
+            *m = metalness;
+        }
+        self
+    }
+
+    /// Tints the light refracted through a transparent material.
+    pub fn with_filter(mut self, filter: LinSrgb) -> Self {
+        if let Kind::Ggx { filter: f, .. } = &mut self.kind {
+            *f = filter;
+        }
+        self
+    }
+
+    /// Gives a glossy or metallic material a sheen, like the fuzz on cloth,
+    /// which is added to its diffuse color where light grazes the surface. It
+    /// grows towards grazing angles as the sheen of the Disney BRDF does,
+    /// rather than with a lobe of its own.
+    pub fn with_sheen(mut self, sheen: Color) -> Self {
+        if let Kind::Ggx { sheen: s, .. } = &mut self.kind {
+            *s = Some(sheen);
         }
+        self
+    }
+
+    /// Makes the material partly opaque, from zero for invisible to one for
+    /// solid. Textures cut out the surface where they're less than half
+    /// opaque, and leave it solid elsewhere.
+    pub fn with_opacity(mut self, opacity: Scalar) -> Self {
+        self.opacity = opacity;
+        self
+    }
+
+    /// Tilts the shading normal of glossy, metallic and transparent materials
+    /// by a normal or height map.
+    pub fn with_bump(mut self, bump: Bump) -> Self {
+        self.bump = Some(bump);
+        self
+    }
+
+    /// Whether the material has an opacity map, which intersection tests must
+    /// check with `is_cut_out`.
+    pub fn has_cutout(&self) -> bool {
+        matches!(self.opacity, Scalar::Texture(_))
+    }
+
+    /// Whether the material's opacity map cuts the surface away at a point.
+    pub fn is_cut_out(&self, coords: TextureCoords) -> bool {
+        self.has_cutout() && self.opacity.sample(coords, 0.0) < ALPHA_CUTOFF
     }
 
     pub fn sample<R: Rng + ?Sized>(
@@ -106,23 +265,59 @@ impl Material {
         tracer: &mut PathTracer<R>,
         int: &Intersection,
     ) -> LinSrgb {
-        let color = self.color.sample(int.texture_coords);
-        match self.kind {
+        let coords = int.texture_coords;
+        // The width of the ray's footprint in texture coordinates, stretched
+        // where it glances across the surface. The tracer approximates the
+        // footprint with a cone rather than with ray differentials.
+        let cosine = int.geometric_normal.dot(int.incident).abs();
+        let footprint = tracer.footprint() * int.texture_density / cosine.max(GRAZING_COSINE);
+        let dissolve = match self.opacity {
+            Scalar::Constant(opacity) => 1.0 - opacity,
+            Scalar::Texture(_) => 0.0,
+        };
+        if dissolve > 0.0 && tracer.rng().gen::<f32>() < dissolve {
+            // Pass straight through the surface.
+            return tracer.trace(Ray::leaving(
+                int.position,
+                int.geometric_normal,
+                int.incident,
+            ));
+        }
+
+        let mut color = self.color.sample(coords, footprint);
+        if let Some(tint) = int.vertex_color {
+            color *= tint;
+        }
+        match &self.kind {
             Kind::Emissive => color,
             Kind::Ggx {
                 index,
                 roughness,
-                metallic,
+                metalness,
                 transparent,
+                filter,
+                sheen,
             } => {
+                let bumped;
+                let int = match self.bump {
+                    Some(ref bump) => {
+                        bumped = Intersection {
+                            normal: bump.apply(int, footprint),
+                            ..*int
+                        };
+                        &bumped
+                    }
+                    None => int,
+                };
+                let index = *index;
+                let roughness = roughness.sample(coords, footprint);
+                let metalness = metalness.sample(coords, footprint);
                 // Importance sample a GGX microfacet, then estimate the Fresnel
                 // coefficient.
                 let microfacet = sample_ggx(tracer.rng(), int.normal, roughness);
-                let f0 = if metallic {
-                    (color.red + color.green + color.blue) / 3.0
-                } else {
-                    ((1.0 - index) / (1.0 + index)).powi(2)
-                };
+                let dielectric_f0 = ((1.0 - index) / (1.0 + index)).powi(2);
+                let metal_f0 = (color.red + color.green + color.blue) / 3.0;
+                let f0 = dielectric_f0 + (metal_f0 - dielectric_f0) * metalness;
                 let m_dot_i = microfacet.dot(int.incident).abs();
                 let fresnel = f0 + (1.0 - f0) * (1.0 - m_dot_i).powi(5);
 
@@ -152,8 +347,11 @@ impl Material {
                     let geometry = 2.0 * n_dot_i * n_dot_o / (left + right);
 
                     // Cook-Torrance BRDF:
-                    color * weight * geometry * tracer.trace(Ray::new(int.position, outgoing))
-                } else if transparent {
+                    color
+                        * weight
+                        * geometry
+                        * tracer.trace(Ray::leaving(int.position, int.geometric_normal, outgoing))
+                } else if *transparent {
                     // Refraction:
                     let (ni, no) = if int.normal.dot(int.incident) < 0.0 {
                         (1.0, index)
@@ -182,20 +380,59 @@ impl Material {
                     let geometry = 2.0 * n_dot_i * n_dot_o / (left + right);
 
                     // Cook-Torrance BRDF:
-                    tracer.trace(Ray::new(int.position, outgoing)) * weight * geometry
-                } else if metallic {
+                    *filter
+                        * tracer.trace(Ray::leaving(int.position, int.geometric_normal, outgoing))
+                        * weight
+                        * geometry
+                } else if tracer.rng().gen::<f32>() < metalness {
                     // Absorb the light.
                     LinSrgb::default()
                 } else {
                     // Diffuse: Lambert BRDF with cosine sampling.
                     let dir = sample_hemisphere(tracer.rng(), int.normal, 1.0);
-                    color * tracer.trace(Ray::new(int.position, dir))
+                    let mut diffuse = color;
+                    if let Some(sheen) = sheen {
+                        // Sheen grows as the light and the view direction
+                        // part, towards the edges of the surface.
+                        let half = (dir - int.incident).normalize();
+                        diffuse += sheen.sample(coords, footprint) * (1.0 - half.dot(dir)).powi(5);
+                    }
+                    diffuse * tracer.trace(Ray::leaving(int.position, int.geometric_normal, dir))
                 }
             }
         }
     }
 }
 
+// What materials were loaded with, for checking loaders.
+#[cfg(test)]
+impl Material {
+    pub(crate) fn opacity(&self) -> &Scalar {
+        &self.opacity
+    }
+
+    pub(crate) fn roughness(&self) -> Option<&Scalar> {
+        match &self.kind {
+            Kind::Ggx { roughness, .. } => Some(roughness),
+            Kind::Emissive => None,
+        }
+    }
+
+    pub(crate) fn metalness(&self) -> Option<&Scalar> {
+        match &self.kind {
+            Kind::Ggx { metalness, .. } => Some(metalness),
+            Kind::Emissive => None,
+        }
+    }
+
+    pub(crate) fn sheen(&self) -> Option<&Color> {
+        match &self.kind {
+            Kind::Ggx { sheen, .. } => sheen.as_ref(),
+            Kind::Emissive => None,
+        }
+    }
+}
+
 fn sample_hemisphere<R: Rng + ?Sized>(rng: &mut R, normal: Vector3, alpha: f32) -> Vector3 {
     // Sample a hemisphere, then project about the normal vector.
     let z = rng.gen::<f32>().powf(1.0 / (alpha + 1.0));
@@ -0,0 +1,176 @@
+// Triangle meshes which share vertices between their faces. Big models take
+// a fraction of the memory they would as separate triangles, and the BVH
+// refers to each face by its index in the mesh.
+
+use bytemuck::{Pod, Zeroable};
+use palette::LinSrgb;
+
+use crate::bounds::BoundingBox;
+use crate::cache::MappedVec;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::surface::*;
+use crate::texture::TextureCoords;
+use crate::types::{Point3, Vector3};
+
+/// A face of a mesh, given by the indices of its vertices and its material.
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct Face {
+    pub vertices: [u32; 3],
+    pub material: u32,
+}
+
+#[derive(Debug)]
+pub struct TriangleMesh {
+    positions: MappedVec<Point3>,
+    // Either empty, or one per vertex. Faces whose vertices all have zero
+    // normals are shaded flat.
+    normals: MappedVec<Vector3>,
+    // Either empty, or one per vertex.
+    texture_coords: MappedVec<TextureCoords>,
+    // Either empty, or one per vertex.
+    colors: MappedVec<LinSrgb>,
+    faces: MappedVec<Face>,
+    // Faces whose material is past the end of these get the last one.
+    materials: Vec<Material>,
+}
+
+impl TriangleMesh {
+    /// Creates a mesh from its vertex attributes, which are indexed by its
+    /// faces. Normals, texture coordinates and colors may be left out by
+    /// passing no values for them. Faces whose material is past the end of
+    /// `materials` get the last one.
+    ///
+    /// Panics if the attributes differ in length, if a face refers to a
+    /// missing vertex, or if there are no materials.
+    pub fn new(
+        positions: impl Into<MappedVec<Point3>>,
+        normals: impl Into<MappedVec<Vector3>>,
+        texture_coords: impl Into<MappedVec<TextureCoords>>,
+        colors: impl Into<MappedVec<LinSrgb>>,
+        faces: impl Into<MappedVec<Face>>,
+        materials: Vec<Material>,
+    ) -> Self {
+        let (positions, normals, texture_coords, colors, faces) = (
+            positions.into(),
+            normals.into(),
+            texture_coords.into(),
+            colors.into(),
+            faces.into(),
+        );
+        assert!(!materials.is_empty());
+        assert!(normals.is_empty() || normals.len() == positions.len());
+        assert!(texture_coords.is_empty() || texture_coords.len() == positions.len());
+        assert!(colors.is_empty() || colors.len() == positions.len());
+        assert!(faces.iter().all(|face| {
+            face.vertices
+                .iter()
+                .all(|&i| (i as usize) < positions.len())
+        }));
+        TriangleMesh {
+            positions,
+            normals,
+            texture_coords,
+            colors,
+            faces,
+            materials,
+        }
+    }
+
+    /// The number of faces in the mesh.
+    pub fn len(&self) -> usize {
+        self.faces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faces.is_empty()
+    }
+
+    /// The positions of the mesh's vertices. After moving any of them, the
+    /// hierarchy the mesh is in must be refit before it is used.
+    pub fn positions_mut(&mut self) -> &mut [Point3] {
+        &mut self.positions
+    }
+
+    pub fn material(&self, face: usize) -> &Material {
+        let material = self.faces[face].material as usize;
+        &self.materials[material.min(self.materials.len() - 1)]
+    }
+
+    pub fn bounding_box(&self, face: usize) -> BoundingBox {
+        triangle_bounds(&self.vertices(face))
+    }
+
+    /// Bounds the part of a face which lies within a box.
+    pub fn clip_bounds(&self, face: usize, bounds: &BoundingBox) -> BoundingBox {
+        clip_triangle(&self.vertices(face), bounds)
+    }
+
+    /// Intersects a ray with a face, if it hits before `t_max`. Shading a hit
+    /// takes more work than for a standalone triangle, so hits beyond the
+    /// closest found so far are skipped.
+    pub fn intersect(&self, face: usize, ray: Ray, t_max: f32) -> Option<Intersection> {
+        let vertices = self.vertices(face);
+        let (distance, u, v) = hit_triangle(&vertices, ray)?;
+        let [v0, v1, v2] = vertices;
+        let (edge1, edge2) = (v1 - v0, v2 - v0);
+        if distance >= t_max || self.is_cut_out(face, u, v) {
+            return None;
+        }
+
+        let normals = match self.attributes(&self.normals, face) {
+            Some(normals) if normals.iter().any(|n| n.norm_squared() > 0.0) => normals,
+            _ => [edge1.cross(edge2).normalize(); 3],
+        };
+        let texture_coords = self
+            .attributes(&self.texture_coords, face)
+            .unwrap_or([TextureCoords::default(); 3]);
+        let tangent = triangle_tangent(edge1, edge2, &texture_coords);
+        Some(triangle_intersection(
+            ray,
+            (v0, edge1, edge2),
+            (distance, u, v),
+            &normals,
+            tangent,
+            &texture_coords,
+            self.attributes(&self.colors, face).as_ref(),
+        ))
+    }
+
+    pub fn occluded(&self, face: usize, ray: Ray, t_max: f32) -> bool {
+        hit_triangle(&self.vertices(face), ray)
+            .is_some_and(|(distance, u, v)| distance < t_max && !self.is_cut_out(face, u, v))
+    }
+
+    // Whether the face's material cuts it away at a hit, given by its
+    // barycentric coordinates.
+    fn is_cut_out(&self, face: usize, u: f32, v: f32) -> bool {
+        let material = self.material(face);
+        if !material.has_cutout() {
+            return false;
+        }
+        let coords = match self.attributes(&self.texture_coords, face) {
+            Some([c0, c1, c2]) => (1.0 - u - v) * c0 + u * c1 + v * c2,
+            None => TextureCoords::default(),
+        };
+        material.is_cut_out(coords)
+    }
+
+    fn vertices(&self, face: usize) -> [Point3; 3] {
+        self.faces[face]
+            .vertices
+            .map(|vertex| self.positions[vertex as usize])
+    }
+
+    fn attributes<T: Copy>(&self, values: &[T], face: usize) -> Option<[T; 3]> {
+        if values.is_empty() {
+            return None;
+        }
+        Some(
+            self.faces[face]
+                .vertices
+                .map(|vertex| values[vertex as usize]),
+        )
+    }
+}
@@ -25,10 +25,14 @@ impl Object {
         }
     }
 
-    pub fn sample(&self, ray: Ray) -> Option<Sample> {
+    pub fn sample(&self, ray: Ray) -> Option<Sample<'_>> {
         self.surface.intersect(ray).map(|intersection| Sample {
             intersection,
             material: &self.material,
         })
     }
+
+    pub fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        self.surface.occluded(ray, t_max)
+    }
 }
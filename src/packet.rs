@@ -0,0 +1,165 @@
+// Packets of coherent rays, such as camera rays through a tile of pixels,
+// which traverse the BVH together. The frustum bounding the packet's rays
+// culls nodes which none of them can hit before any ray is tested on its own,
+// and rays are only tested until each child is known to be entered.
+
+use crate::ray::Ray;
+use crate::simd::{Lanes, SimdRay, LANES};
+
+/// The most rays a packet can hold, one per bit of a ray mask.
+pub const PACKET_SIZE: usize = 64;
+
+/// The width and height of the square tiles of pixels whose camera rays are
+/// traced as one packet.
+pub const TILE_SIZE: u32 = 8;
+
+// The relative error allowed for in frustum tests.
+const SLACK: f32 = 1e-5;
+
+#[derive(Clone, Debug)]
+pub struct RayPacket {
+    rays: Vec<Ray>,
+    simd_rays: Vec<SimdRay>,
+    frustum: Option<Frustum>,
+}
+
+impl RayPacket {
+    /// Groups up to `PACKET_SIZE` rays into a packet. Any rays can be traced
+    /// together, but only rays with similar origins and directions benefit.
+    pub fn new(rays: Vec<Ray>) -> Self {
+        assert!(rays.len() <= PACKET_SIZE);
+        RayPacket {
+            simd_rays: rays.iter().map(|&ray| SimdRay::new(ray)).collect(),
+            frustum: Frustum::new(&rays),
+            rays,
+        }
+    }
+
+    pub fn rays(&self) -> &[Ray] {
+        &self.rays
+    }
+
+    pub fn len(&self) -> usize {
+        self.rays.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rays.is_empty()
+    }
+
+    /// A mask with a bit set for every ray in the packet.
+    pub(crate) fn all(&self) -> u64 {
+        u64::MAX
+            .checked_shr((PACKET_SIZE - self.rays.len()) as u32)
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn simd_ray(&self, ray: usize) -> &SimdRay {
+        &self.simd_rays[ray]
+    }
+
+    /// Bounds the distances at which the packet's rays enter each of the
+    /// boxes, with boxes which none of them enter before `t_max` given as
+    /// infinitely far away. Returns `None` if the packet's rays point too many
+    /// ways to bound.
+    pub(crate) fn frustum_distances(
+        &self,
+        min: &[Lanes; 3],
+        max: &[Lanes; 3],
+        t_max: f32,
+    ) -> Option<Lanes> {
+        let frustum = self.frustum.as_ref()?;
+        let mut distances = [f32::INFINITY; LANES];
+        for (lane, distance) in distances.iter_mut().enumerate() {
+            if let Some(near) = frustum.enter(min, max, lane, t_max) {
+                *distance = near;
+            }
+        }
+        Some(distances)
+    }
+}
+
+/// Iterates over the rays in a mask.
+pub(crate) fn rays_in(mut mask: u64) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if mask == 0 {
+            return None;
+        }
+        let ray = mask.trailing_zeros() as usize;
+        mask &= mask - 1;
+        Some(ray)
+    })
+}
+
+// The range of origins and inverse directions of a packet's rays along each
+// axis. A box test with these ranges in place of single values finds bounds
+// on where every ray in the packet enters and exits the box.
+#[derive(Clone, Debug)]
+struct Frustum {
+    origin_min: [f32; 3],
+    origin_max: [f32; 3],
+    inv_dir_min: [f32; 3],
+    inv_dir_max: [f32; 3],
+}
+
+impl Frustum {
+    // Packets whose rays point to both sides of an axis have no frustum, as
+    // the range of their inverse directions would be unbounded.
+    fn new(rays: &[Ray]) -> Option<Self> {
+        let mut frustum = Frustum {
+            origin_min: [f32::INFINITY; 3],
+            origin_max: [f32::NEG_INFINITY; 3],
+            inv_dir_min: [f32::INFINITY; 3],
+            inv_dir_max: [f32::NEG_INFINITY; 3],
+        };
+        for axis in 0..3 {
+            let positive = rays.first()?.dir[axis] > 0.0;
+            for ray in rays {
+                let dir = ray.dir[axis];
+                if dir == 0.0 || (dir > 0.0) != positive {
+                    return None;
+                }
+                frustum.origin_min[axis] = frustum.origin_min[axis].min(ray.origin[axis]);
+                frustum.origin_max[axis] = frustum.origin_max[axis].max(ray.origin[axis]);
+                frustum.inv_dir_min[axis] = frustum.inv_dir_min[axis].min(1.0 / dir);
+                frustum.inv_dir_max[axis] = frustum.inv_dir_max[axis].max(1.0 / dir);
+            }
+        }
+        Some(frustum)
+    }
+
+    fn enter(&self, min: &[Lanes; 3], max: &[Lanes; 3], lane: usize, t_max: f32) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = t_max;
+        for axis in 0..3 {
+            let (low, high) = (min[axis][lane], max[axis][lane]);
+            if low > high {
+                // An unused slot.
+                return None;
+            }
+            // Whichever side of the box faces the rays, every ray enters it
+            // after the earliest time either side could be reached, and exits
+            // before the latest.
+            let low = self.slab(low, axis);
+            let high = self.slab(high, axis);
+            near = near.max(low.0.min(high.0));
+            far = far.min(low.1.max(high.1));
+        }
+        // Leave some room for rounding error, so that no box a single ray
+        // test would find is culled.
+        (near <= far * (1.0 + SLACK)).then_some(near * (1.0 - SLACK))
+    }
+
+    // Bounds the distance along the packet's rays to a plane across an axis.
+    fn slab(&self, plane: f32, axis: usize) -> (f32, f32) {
+        let offsets = [plane - self.origin_max[axis], plane - self.origin_min[axis]];
+        let inv_dirs = [self.inv_dir_min[axis], self.inv_dir_max[axis]];
+        let products = offsets.map(|offset| inv_dirs.map(|inv_dir| offset * inv_dir));
+        products
+            .iter()
+            .flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &t| {
+                (min.min(t), max.max(t))
+            })
+    }
+}
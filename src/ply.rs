@@ -0,0 +1,535 @@
+// A reader for triangle meshes in the PLY format, as written by most scanning
+// software, in ASCII or binary of either byte order. Polygons are split into
+// triangle fans, and any elements besides vertices and faces are skipped.
+
+use std::io;
+use std::path::Path;
+use std::str::SplitAsciiWhitespace;
+
+use palette::{LinSrgb, Srgb};
+
+use crate::texture::TextureCoords;
+use crate::types::{Point3, Vector3};
+
+/// The vertex attributes of a PLY file, and the triangles which index them.
+/// Attributes besides positions are empty unless every vertex has them.
+#[derive(Debug, Default)]
+pub struct PlyMesh {
+    pub positions: Vec<Point3>,
+    pub normals: Vec<Vector3>,
+    pub texture_coords: Vec<TextureCoords>,
+    pub colors: Vec<LinSrgb>,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlyError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid header: {0}")]
+    Header(String),
+    #[error("invalid data: {0}")]
+    Data(String),
+}
+
+pub fn load_ply(path: &Path) -> Result<PlyMesh, PlyError> {
+    parse_ply(&std::fs::read(path)?)
+}
+
+fn parse_ply(bytes: &[u8]) -> Result<PlyMesh, PlyError> {
+    let (header, body) = Header::parse(bytes)?;
+    let mut body = match header.format {
+        Format::Ascii => {
+            let text = std::str::from_utf8(body)
+                .map_err(|_| PlyError::Data("ascii body is not text".to_string()))?;
+            Body::Ascii(text.split_ascii_whitespace())
+        }
+        Format::BinaryLittleEndian => Body::Binary {
+            bytes: body,
+            big_endian: false,
+        },
+        Format::BinaryBigEndian => Body::Binary {
+            bytes: body,
+            big_endian: true,
+        },
+    };
+
+    let mut mesh = PlyMesh::default();
+    for element in &header.elements {
+        match element.name.as_str() {
+            "vertex" => read_vertices(element, &mut body, &mut mesh)?,
+            "face" => read_faces(element, &mut body, &mut mesh)?,
+            _ => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        property.skip(&mut body)?;
+                    }
+                }
+            }
+        }
+    }
+
+    let vertices = mesh.positions.len() as u32;
+    if let Some(triangle) = mesh
+        .triangles
+        .iter()
+        .find(|t| t.iter().any(|&i| i >= vertices))
+    {
+        return Err(PlyError::Data(format!(
+            "face refers to missing vertex: {:?}",
+            triangle
+        )));
+    }
+    Ok(mesh)
+}
+
+fn read_vertices(element: &Element, body: &mut Body, mesh: &mut PlyMesh) -> Result<(), PlyError> {
+    let find = |names: &[&str]| {
+        element
+            .properties
+            .iter()
+            .position(|property| names.contains(&property.name.as_str()))
+    };
+    let find_all = |names: &[&[&str]]| {
+        names
+            .iter()
+            .map(|names| find(names))
+            .collect::<Option<Vec<_>>>()
+    };
+    let positions = find_all(&[&["x"], &["y"], &["z"]])
+        .ok_or_else(|| PlyError::Header("vertices have no position".to_string()))?;
+    let normals = find_all(&[&["nx"], &["ny"], &["nz"]]);
+    let texture_coords = find_all(&[
+        &["u", "s", "texture_u", "texture_s"],
+        &["v", "t", "texture_v", "texture_t"],
+    ]);
+    let colors = find_all(&[&["red", "r"], &["green", "g"], &["blue", "b"]]);
+    // Integer colors span their type's range, and float colors span zero to
+    // one.
+    let color_scale = match &colors {
+        Some(colors) => match element.properties[colors[0]].kind {
+            PropertyKind::Scalar(ty) => ty.max_color(),
+            PropertyKind::List { .. } => {
+                return Err(PlyError::Header("vertex colors are a list".to_string()))
+            }
+        },
+        None => 1.0,
+    };
+
+    let mut values = vec![0.0; element.properties.len()];
+    for _ in 0..element.count {
+        for (value, property) in values.iter_mut().zip(&element.properties) {
+            *value = match property.kind {
+                PropertyKind::Scalar(ty) => body.read(ty)? as f32,
+                PropertyKind::List { .. } => {
+                    property.skip(body)?;
+                    0.0
+                }
+            };
+        }
+        let get = |indices: &[usize]| [0, 1, 2].map(|i| indices.get(i).map_or(0.0, |&j| values[j]));
+        let [x, y, z] = get(&positions);
+        mesh.positions.push(Point3::new(x, y, z));
+        if let Some(normals) = &normals {
+            let [x, y, z] = get(normals);
+            mesh.normals.push(Vector3::new(x, y, z));
+        }
+        if let Some(texture_coords) = &texture_coords {
+            let [u, v, _] = get(texture_coords);
+            mesh.texture_coords.push(TextureCoords::new(u, v));
+        }
+        if let Some(colors) = &colors {
+            let [r, g, b] = get(colors).map(|c| c / color_scale);
+            mesh.colors.push(Srgb::new(r, g, b).into_linear());
+        }
+    }
+    Ok(())
+}
+
+fn read_faces(element: &Element, body: &mut Body, mesh: &mut PlyMesh) -> Result<(), PlyError> {
+    let indices = element
+        .properties
+        .iter()
+        .position(|property| {
+            matches!(property.kind, PropertyKind::List { .. })
+                && ["vertex_indices", "vertex_index"].contains(&property.name.as_str())
+        })
+        .ok_or_else(|| PlyError::Header("faces have no vertex indices".to_string()))?;
+
+    let mut polygon = Vec::new();
+    for _ in 0..element.count {
+        for (i, property) in element.properties.iter().enumerate() {
+            match property.kind {
+                PropertyKind::List { count, item } if i == indices => {
+                    let len = body.read(count)? as usize;
+                    polygon.clear();
+                    for _ in 0..len {
+                        let index = body.read(item)?;
+                        if index < 0.0 {
+                            return Err(PlyError::Data(format!("negative vertex index {}", index)));
+                        }
+                        polygon.push(index as u32);
+                    }
+                    for k in 1..len.saturating_sub(1) {
+                        mesh.triangles
+                            .push([polygon[0], polygon[k], polygon[k + 1]]);
+                    }
+                }
+                _ => property.skip(body)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+}
+
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+struct Property {
+    name: String,
+    kind: PropertyKind,
+}
+
+enum PropertyKind {
+    Scalar(Type),
+    List { count: Type, item: Type },
+}
+
+#[derive(Copy, Clone)]
+enum Type {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Header {
+    /// Parses the header at the start of a file, returning it along with the
+    /// rest of the file.
+    fn parse(bytes: &[u8]) -> Result<(Header, &[u8]), PlyError> {
+        let mut format = None;
+        let mut elements: Vec<Element> = Vec::new();
+        let mut rest = bytes;
+        let mut first = true;
+        loop {
+            let end = rest
+                .iter()
+                .position(|&b| b == b'\n')
+                .ok_or_else(|| PlyError::Header("missing end_header".to_string()))?;
+            let line = std::str::from_utf8(&rest[..end])
+                .map_err(|_| PlyError::Header("header is not text".to_string()))?;
+            rest = &rest[end + 1..];
+            let mut tokens = line.split_ascii_whitespace();
+            let keyword = tokens.next();
+            if first {
+                if keyword != Some("ply") {
+                    return Err(PlyError::Header("not a ply file".to_string()));
+                }
+                first = false;
+                continue;
+            }
+
+            let invalid = || PlyError::Header(format!("invalid line: {}", line.trim_end()));
+            match keyword {
+                Some("format") => {
+                    format = Some(match tokens.next() {
+                        Some("ascii") => Format::Ascii,
+                        Some("binary_little_endian") => Format::BinaryLittleEndian,
+                        Some("binary_big_endian") => Format::BinaryBigEndian,
+                        _ => return Err(invalid()),
+                    });
+                }
+                Some("element") => {
+                    let name = tokens.next().ok_or_else(invalid)?;
+                    let count = tokens
+                        .next()
+                        .and_then(|c| c.parse().ok())
+                        .ok_or_else(invalid)?;
+                    elements.push(Element {
+                        name: name.to_string(),
+                        count,
+                        properties: Vec::new(),
+                    });
+                }
+                Some("property") => {
+                    let element = elements.last_mut().ok_or_else(invalid)?;
+                    let kind = match tokens.next().ok_or_else(invalid)? {
+                        "list" => PropertyKind::List {
+                            count: Type::parse(tokens.next()).ok_or_else(invalid)?,
+                            item: Type::parse(tokens.next()).ok_or_else(invalid)?,
+                        },
+                        ty => PropertyKind::Scalar(Type::parse(Some(ty)).ok_or_else(invalid)?),
+                    };
+                    let name = tokens.next().ok_or_else(invalid)?;
+                    element.properties.push(Property {
+                        name: name.to_string(),
+                        kind,
+                    });
+                }
+                Some("end_header") => break,
+                Some("comment" | "obj_info") | None => (),
+                Some(_) => return Err(invalid()),
+            }
+        }
+        let format = format.ok_or_else(|| PlyError::Header("missing format".to_string()))?;
+        Ok((Header { format, elements }, rest))
+    }
+}
+
+impl Property {
+    fn skip(&self, body: &mut Body) -> Result<(), PlyError> {
+        match self.kind {
+            PropertyKind::Scalar(ty) => {
+                body.read(ty)?;
+            }
+            PropertyKind::List { count, item } => {
+                for _ in 0..body.read(count)? as usize {
+                    body.read(item)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Type {
+    fn parse(name: Option<&str>) -> Option<Type> {
+        Some(match name? {
+            "char" | "int8" => Type::I8,
+            "uchar" | "uint8" => Type::U8,
+            "short" | "int16" => Type::I16,
+            "ushort" | "uint16" => Type::U16,
+            "int" | "int32" => Type::I32,
+            "uint" | "uint32" => Type::U32,
+            "float" | "float32" => Type::F32,
+            "double" | "float64" => Type::F64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Type::I8 | Type::U8 => 1,
+            Type::I16 | Type::U16 => 2,
+            Type::I32 | Type::U32 | Type::F32 => 4,
+            Type::F64 => 8,
+        }
+    }
+
+    fn max_color(self) -> f32 {
+        match self {
+            Type::I8 => i8::MAX as f32,
+            Type::U8 => u8::MAX as f32,
+            Type::I16 => i16::MAX as f32,
+            Type::U16 => u16::MAX as f32,
+            Type::I32 => i32::MAX as f32,
+            Type::U32 => u32::MAX as f32,
+            Type::F32 | Type::F64 => 1.0,
+        }
+    }
+}
+
+/// The values after the header, read one at a time in order.
+enum Body<'a> {
+    Ascii(SplitAsciiWhitespace<'a>),
+    Binary { bytes: &'a [u8], big_endian: bool },
+}
+
+impl Body<'_> {
+    /// Reads the next value, which every type converts to exactly.
+    fn read(&mut self, ty: Type) -> Result<f64, PlyError> {
+        match self {
+            Body::Ascii(tokens) => {
+                let token = tokens
+                    .next()
+                    .ok_or_else(|| PlyError::Data("unexpected end of file".to_string()))?;
+                token
+                    .parse()
+                    .map_err(|_| PlyError::Data(format!("invalid number: {}", token)))
+            }
+            Body::Binary { bytes, big_endian } => {
+                if bytes.len() < ty.size() {
+                    return Err(PlyError::Data("unexpected end of file".to_string()));
+                }
+                let (value, rest) = bytes.split_at(ty.size());
+                *bytes = rest;
+                let mut buf = [0; 8];
+                buf[..value.len()].copy_from_slice(value);
+                if *big_endian {
+                    buf[..value.len()].reverse();
+                }
+                let value = match ty {
+                    Type::I8 => buf[0] as i8 as f64,
+                    Type::U8 => buf[0] as f64,
+                    Type::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    Type::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    Type::I32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Type::U32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Type::F32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Type::F64 => f64::from_le_bytes(buf),
+                };
+                Ok(value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property float s
+property float t
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    // The corners of a unit square facing up the z axis, with a color each.
+    const CORNERS: [([f32; 2], [u8; 3]); 4] = [
+        ([0.0, 0.0], [255, 0, 0]),
+        ([1.0, 0.0], [0, 255, 0]),
+        ([1.0, 1.0], [0, 0, 255]),
+        ([0.0, 1.0], [255, 255, 255]),
+    ];
+
+    fn ascii() -> Vec<u8> {
+        let mut text = format!("ply\nformat ascii 1.0\n{}", HEADER);
+        for ([x, y], [r, g, b]) in CORNERS {
+            text += &format!("{x} {y} 0 0 0 1 {x} {y} {r} {g} {b}\n");
+        }
+        text += "4 0 1 2 3\n";
+        text.into_bytes()
+    }
+
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let format = match big_endian {
+            false => "binary_little_endian",
+            true => "binary_big_endian",
+        };
+        let mut bytes = format!("ply\nformat {} 1.0\n{}", format, HEADER).into_bytes();
+        let float = |bytes: &mut Vec<u8>, value: f32| match big_endian {
+            false => bytes.extend(value.to_le_bytes()),
+            true => bytes.extend(value.to_be_bytes()),
+        };
+        for ([x, y], color) in CORNERS {
+            for value in [x, y, 0.0, 0.0, 0.0, 1.0, x, y] {
+                float(&mut bytes, value);
+            }
+            bytes.extend(color);
+        }
+        bytes.push(4);
+        for index in 0..4i32 {
+            match big_endian {
+                false => bytes.extend(index.to_le_bytes()),
+                true => bytes.extend(index.to_be_bytes()),
+            }
+        }
+        bytes
+    }
+
+    fn check_square(mesh: &PlyMesh) {
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.normals.len(), 4);
+        assert_eq!(mesh.texture_coords.len(), 4);
+        assert_eq!(mesh.colors.len(), 4);
+        for (i, ([x, y], [r, g, b])) in CORNERS.into_iter().enumerate() {
+            let position = mesh.positions[i];
+            assert_eq!([position.x(), position.y(), position.z()], [x, y, 0.0]);
+            let normal = mesh.normals[i];
+            assert_eq!([normal.x(), normal.y(), normal.z()], [0.0, 0.0, 1.0]);
+            let coords = mesh.texture_coords[i];
+            assert_eq!([coords.x(), coords.y()], [x, y]);
+            let color = mesh.colors[i];
+            let expected = [r, g, b].map(|c| c as f32 / 255.0);
+            assert_eq!([color.red, color.green, color.blue], expected);
+        }
+        // The quad is split into a fan around its first vertex.
+        assert_eq!(mesh.triangles, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn reads_ascii() {
+        check_square(&parse_ply(&ascii()).unwrap());
+    }
+
+    #[test]
+    fn reads_binary_little_endian() {
+        check_square(&parse_ply(&binary(false)).unwrap());
+    }
+
+    #[test]
+    fn reads_binary_big_endian() {
+        check_square(&parse_ply(&binary(true)).unwrap());
+    }
+
+    #[test]
+    fn splits_polygons_into_fans() {
+        let text = "ply
+format ascii 1.0
+element vertex 5
+property float x
+property float y
+property float z
+element face 2
+property list uchar int vertex_indices
+end_header
+0 0 0
+1 0 0
+2 1 0
+1 2 0
+0 1 0
+5 0 1 2 3 4
+3 4 3 2
+";
+        let mesh = parse_ply(text.as_bytes()).unwrap();
+        let fan = vec![[0, 1, 2], [0, 2, 3], [0, 3, 4], [4, 3, 2]];
+        assert_eq!(mesh.triangles, fan);
+        assert!(mesh.normals.is_empty() && mesh.colors.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        for bytes in [ascii(), binary(false), binary(true)] {
+            // Cut the file off anywhere after the header, short of the
+            // newline which ends the ASCII file.
+            let header = bytes
+                .windows(11)
+                .position(|w| w == b"end_header\n")
+                .unwrap()
+                + 11;
+            for len in header..bytes.len() - 1 {
+                let result = parse_ply(&bytes[..len]);
+                assert!(matches!(result, Err(PlyError::Data(_))), "{}", len);
+            }
+        }
+    }
+}
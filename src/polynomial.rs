@@ -0,0 +1,197 @@
+// Closed form polynomial root finding, following Jochen Schwarze's solvers
+// from Graphics Gems I. Coefficients are given in increasing order of degree.
+
+const EPSILON: f64 = 1e-9;
+
+fn is_zero(x: f64) -> bool {
+    x.abs() < EPSILON
+}
+
+/// Solves `c[2]x^2 + c[1]x + c[0] = 0`, returning the real roots.
+pub fn solve_quadratic(c: [f64; 3]) -> Vec<f64> {
+    if c[2] == 0.0 {
+        // The equation is linear, or has no unknown at all.
+        return if c[1] == 0.0 {
+            vec![]
+        } else {
+            vec![-c[0] / c[1]]
+        };
+    }
+    let p = c[1] / (2.0 * c[2]);
+    let q = c[0] / c[2];
+    let d = p * p - q;
+
+    if is_zero(d) {
+        vec![-p]
+    } else if d < 0.0 {
+        vec![]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![sqrt_d - p, -sqrt_d - p]
+    }
+}
+
+/// Solves `c[3]x^3 + c[2]x^2 + c[1]x + c[0] = 0`, returning the real roots.
+pub fn solve_cubic(c: [f64; 4]) -> Vec<f64> {
+    // Normalize to x^3 + Ax^2 + Bx + C = 0, then substitute x = y - A/3 to
+    // eliminate the quadratic term: y^3 + 3py + 2q = 0.
+    let a = c[2] / c[3];
+    let b = c[1] / c[3];
+    let c = c[0] / c[3];
+
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + c) / 2.0;
+
+    let cb_p = p * p * p;
+    let d = q * q + cb_p;
+
+    let mut roots = if is_zero(d) {
+        if is_zero(q) {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if d < 0.0 {
+        // Casus irreducibilis: three real roots.
+        let phi = (-q / (-cb_p).sqrt()).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        vec![
+            t * phi.cos(),
+            -t * (phi + std::f64::consts::FRAC_PI_3).cos(),
+            -t * (phi - std::f64::consts::FRAC_PI_3).cos(),
+        ]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+    };
+
+    for root in roots.iter_mut() {
+        *root -= a / 3.0;
+    }
+    roots
+}
+
+/// Solves `c[4]x^4 + c[3]x^3 + c[2]x^2 + c[1]x + c[0] = 0`, returning the
+/// real roots.
+pub fn solve_quartic(coeffs: [f64; 5]) -> Vec<f64> {
+    // Normalize to x^4 + Ax^3 + Bx^2 + Cx + D = 0, then substitute
+    // x = y - A/4 to eliminate the cubic term: y^4 + py^2 + qy + r = 0.
+    let a = coeffs[3] / coeffs[4];
+    let b = coeffs[2] / coeffs[4];
+    let c = coeffs[1] / coeffs[4];
+    let d = coeffs[0] / coeffs[4];
+
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + c;
+    let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * c / 4.0 + d;
+
+    let mut roots = if is_zero(r) {
+        // No absolute term: y(y^3 + py + q) = 0
+        let mut roots = solve_cubic([q, p, 0.0, 1.0]);
+        roots.push(0.0);
+        roots
+    } else {
+        // Solve the resolvent cubic, and use one of its roots to factor the
+        // quartic into two quadratics.
+        let z = solve_cubic([r * p / 2.0 - q * q / 8.0, -r, -p / 2.0, 1.0])[0];
+
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        let u = if is_zero(u) {
+            0.0
+        } else if u > 0.0 {
+            u.sqrt()
+        } else {
+            return vec![];
+        };
+        let v = if is_zero(v) {
+            0.0
+        } else if v > 0.0 {
+            v.sqrt()
+        } else {
+            return vec![];
+        };
+
+        let v = if q < 0.0 { -v } else { v };
+        let mut roots = solve_quadratic([z - u, v, 1.0]);
+        roots.extend(solve_quadratic([z + u, -v, 1.0]));
+        roots
+    };
+
+    for root in roots.iter_mut() {
+        *root -= a / 4.0;
+        *root = polish_root(&coeffs, *root);
+    }
+    roots
+}
+
+// The closed form solution loses a lot of precision, so refine each root with
+// a few iterations of Newton's method.
+fn polish_root(coeffs: &[f64], mut x: f64) -> f64 {
+    for _ in 0..2 {
+        let mut value = 0.0;
+        let mut derivative = 0.0;
+        for &c in coeffs.iter().rev() {
+            derivative = derivative * x + value;
+            value = value * x + c;
+        }
+        if derivative == 0.0 {
+            break;
+        }
+        x -= value / derivative;
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks that the roots are the expected ones, in any order.
+    fn assert_roots(mut roots: Vec<f64>, mut expected: Vec<f64>) {
+        roots.sort_by(f64::total_cmp);
+        expected.sort_by(f64::total_cmp);
+        assert_eq!(roots.len(), expected.len(), "{:?}", roots);
+        for (root, expected) in roots.iter().zip(&expected) {
+            assert!((root - expected).abs() < 1e-6, "{:?}", roots);
+        }
+    }
+
+    #[test]
+    fn quadratic() {
+        // (x - 2)(x + 3)
+        assert_roots(solve_quadratic([-6.0, 1.0, 1.0]), vec![2.0, -3.0]);
+        // 2(x - 1)^2
+        assert_roots(solve_quadratic([2.0, -4.0, 2.0]), vec![1.0]);
+        assert_roots(solve_quadratic([1.0, 0.0, 1.0]), vec![]);
+    }
+
+    #[test]
+    fn quadratic_without_square_term() {
+        assert_roots(solve_quadratic([-4.0, 2.0, 0.0]), vec![2.0]);
+        assert_roots(solve_quadratic([1.0, 0.0, 0.0]), vec![]);
+    }
+
+    #[test]
+    fn cubic() {
+        // (x - 1)(x - 2)(x + 3)
+        assert_roots(solve_cubic([6.0, -7.0, 0.0, 1.0]), vec![1.0, 2.0, -3.0]);
+        // x^3 - 8
+        assert_roots(solve_cubic([-8.0, 0.0, 0.0, 1.0]), vec![2.0]);
+        // (x - 1)^2 (x + 2)
+        assert_roots(solve_cubic([2.0, -3.0, 0.0, 1.0]), vec![1.0, -2.0]);
+    }
+
+    #[test]
+    fn quartic() {
+        // (x - 1)(x + 1)(x - 2)(x + 3)
+        let roots = solve_quartic([6.0, -1.0, -7.0, 1.0, 1.0]);
+        assert_roots(roots, vec![1.0, -1.0, 2.0, -3.0]);
+        // x^4 - 16
+        assert_roots(solve_quartic([-16.0, 0.0, 0.0, 0.0, 1.0]), vec![2.0, -2.0]);
+        assert_roots(solve_quartic([1.0, 0.0, 0.0, 0.0, 1.0]), vec![]);
+    }
+}
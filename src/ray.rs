@@ -21,7 +21,48 @@ impl Ray {
         }
     }
 
+    /// Starts a ray which leaves a surface at `position`. The origin is nudged
+    /// off the surface along its geometric normal, to whichever side `dir`
+    /// points, so that rounding error can't make the ray hit the surface it
+    /// left.
+    pub fn leaving(position: Point3, normal: Vector3, dir: Vector3) -> Self {
+        let normal = if normal.dot(dir) < 0.0 {
+            -normal
+        } else {
+            normal
+        };
+        Ray::new(offset_origin(position, normal), dir)
+    }
+
     pub fn along(&self, distance: f32) -> Point3 {
         self.origin + self.dir * distance
     }
 }
+
+// Offsets a point along a normal by a number of ulps proportional to its
+// coordinates, so that the offset grows with their rounding error. Near the
+// origin, where ulps are tiny, fixed offsets are used instead. See "A Fast and
+// Robust Method for Avoiding Self-Intersection" (Wächter and Binder, Ray
+// Tracing Gems).
+fn offset_origin(position: Point3, normal: Vector3) -> Point3 {
+    const ORIGIN: f32 = 1.0 / 32.0;
+    const FLOAT_SCALE: f32 = 1.0 / 65536.0;
+    const INT_SCALE: f32 = 256.0;
+
+    let offset = |p: f32, n: f32| {
+        if p.abs() < ORIGIN {
+            p + FLOAT_SCALE * n
+        } else {
+            // Stepping the bits of a float away from zero increases its
+            // magnitude, so step them the other way for negative values.
+            let ulps = (INT_SCALE * n) as i32;
+            let ulps = if p < 0.0 { -ulps } else { ulps };
+            f32::from_bits((p.to_bits() as i32 + ulps) as u32)
+        }
+    };
+    Point3::new(
+        offset(position.x(), normal.x()),
+        offset(position.y(), normal.y()),
+        offset(position.z(), normal.z()),
+    )
+}
@@ -3,6 +3,7 @@ use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use std::f32;
 
+use crate::packet::{RayPacket, TILE_SIZE};
 use crate::profile;
 use crate::scene::Scene;
 use crate::tracer::PathTracer;
@@ -13,6 +14,9 @@ pub struct Renderer {
     pub height: u32,
     pub samples_per_pixel: u32,
     pub max_reflections: u32,
+    /// Whether to trace the camera rays through each tile of pixels as a
+    /// packet, rather than one at a time.
+    pub packets: bool,
 }
 
 impl Renderer {
@@ -21,32 +25,63 @@ impl Renderer {
         F: Fn() + Sync,
     {
         profile::start("render.prof");
-        let pixels: Vec<Vec<_>> = (0..self.width)
+        let spread = scene.camera.pixel_spread(self.width);
+        // Each thread renders a strip of tiles of pixels, one tile wide.
+        let pixels: Vec<Vec<_>> = (0..self.width.div_ceil(TILE_SIZE))
             .into_par_iter()
-            .map(|i| {
+            .flat_map_iter(|strip| {
                 let mut rng = rand::rngs::SmallRng::from_entropy();
-                let col = (0..self.height)
-                    .map(|j| {
-                        let x = i as f32 - (self.width / 2) as f32;
-                        let y = (self.height / 2) as f32 - j as f32;
+                let left = strip * TILE_SIZE;
+                let right = (left + TILE_SIZE).min(self.width);
+                let mut cols =
+                    vec![Vec::with_capacity(self.height as usize); (right - left) as usize];
+                for top in (0..self.height).step_by(TILE_SIZE as usize) {
+                    let bottom = (top + TILE_SIZE).min(self.height);
+                    let tile: Vec<_> = (top..bottom)
+                        .flat_map(|j| (left..right).map(move |i| (i, j)))
+                        .collect();
 
-                        let mut color = palette::LinSrgb::new(0.0, 0.0, 0.0);
-                        for _ in 0..self.samples_per_pixel {
-                            let dx = rng.gen::<f32>() - 0.5;
-                            let dy = rng.gen::<f32>() - 0.5;
-                            let xnorm = (x + dx) / self.width as f32;
-                            let ynorm = (y + dy) / self.width as f32;
-                            let ray = scene.camera.get_ray(xnorm, ynorm);
-
-                            let mut tracer = PathTracer::new(scene, &mut rng, self.max_reflections);
-                            color += tracer.trace(ray);
+                    let mut colors = vec![palette::LinSrgb::new(0.0, 0.0, 0.0); tile.len()];
+                    for _ in 0..self.samples_per_pixel {
+                        let rays: Vec<_> = tile
+                            .iter()
+                            .map(|&(i, j)| {
+                                let x = i as f32 - (self.width / 2) as f32;
+                                let y = (self.height / 2) as f32 - j as f32;
+                                let dx = rng.gen::<f32>() - 0.5;
+                                let dy = rng.gen::<f32>() - 0.5;
+                                let xnorm = (x + dx) / self.width as f32;
+                                let ynorm = (y + dy) / self.width as f32;
+                                scene.camera.get_ray(xnorm, ynorm)
+                            })
+                            .collect();
+                        if self.packets {
+                            let samples = scene.objects.sample_packet(&RayPacket::new(rays));
+                            // Only camera rays are coherent, so each path
+                            // carries on alone after its first bounce.
+                            for (color, sample) in colors.iter_mut().zip(samples) {
+                                let mut tracer =
+                                    PathTracer::new(scene, &mut rng, self.max_reflections, spread);
+                                *color += tracer.trace_sample(sample);
+                            }
+                        } else {
+                            for (color, ray) in colors.iter_mut().zip(rays) {
+                                let mut tracer =
+                                    PathTracer::new(scene, &mut rng, self.max_reflections, spread);
+                                *color += tracer.trace(ray);
+                            }
                         }
-                        color = (color / self.samples_per_pixel as f32).clamp();
-                        palette::Srgb::from_linear(color).into()
-                    })
-                    .collect();
-                on_col_done();
-                col
+                    }
+
+                    for (&(i, _), color) in tile.iter().zip(colors) {
+                        let color = (color / self.samples_per_pixel as f32).clamp();
+                        cols[(i - left) as usize].push(palette::Srgb::from_linear(color).into());
+                    }
+                }
+                for _ in left..right {
+                    on_col_done();
+                }
+                cols
             })
             .collect();
         profile::end();
@@ -0,0 +1,289 @@
+use crate::bounds::BoundingBox;
+use crate::float;
+use crate::ray::Ray;
+use crate::surface::{Intersection, Surface};
+use crate::texture::TextureCoords;
+use crate::types::{Point3, Vector3};
+
+// Sphere tracing stops once it gets this close to the surface.
+const HIT_DISTANCE: f32 = 1e-4;
+// Hits closer than this to the ray origin are ignored, to prevent secondary
+// rays from intersecting the surface they were cast from.
+const MIN_DISTANCE: f32 = 10.0 * HIT_DISTANCE;
+const MAX_STEPS: usize = 512;
+// Step size for estimating normals with central differences.
+const NORMAL_DELTA: f32 = 1e-4;
+
+/// A signed distance function, defined as a tree of primitives centered on
+/// the origin and the operations combining them.
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: Vector3,
+    },
+    RoundBox {
+        half_extents: Vector3,
+        radius: f32,
+    },
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Capsule {
+        start: Point3,
+        end: Point3,
+        radius: f32,
+    },
+    Translate {
+        offset: Vector3,
+        shape: Box<Sdf>,
+    },
+    Union(Vec<Sdf>),
+    Intersection(Vec<Sdf>),
+    /// The first shape, minus all of the others.
+    Subtraction(Vec<Sdf>),
+    SmoothUnion {
+        smoothness: f32,
+        shapes: Vec<Sdf>,
+    },
+    /// Infinitely repeats a shape with the given period along each axis. An
+    /// axis with a period of zero is not repeated.
+    Repeat {
+        period: Vector3,
+        shape: Box<Sdf>,
+    },
+    /// Twists a shape about the y axis, by `rate` radians per unit.
+    Twist {
+        rate: f32,
+        shape: Box<Sdf>,
+    },
+}
+
+impl Sdf {
+    pub fn distance(&self, p: Point3) -> f32 {
+        match self {
+            Sdf::Sphere { radius } => (p - Point3::origin()).norm() - radius,
+            Sdf::Box { half_extents } => box_distance(p, *half_extents),
+            Sdf::RoundBox {
+                half_extents,
+                radius,
+            } => {
+                let inner = *half_extents - Vector3::new(*radius, *radius, *radius);
+                box_distance(p, inner) - radius
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let radial = (p.x() * p.x() + p.z() * p.z()).sqrt() - major_radius;
+                (radial * radial + p.y() * p.y()).sqrt() - minor_radius
+            }
+            Sdf::Capsule { start, end, radius } => {
+                let pa = p - *start;
+                let ba = *end - *start;
+                // A capsule whose ends meet is a sphere around them.
+                let h = if ba.norm_squared() > 0.0 {
+                    (pa.dot(ba) / ba.norm_squared()).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                (pa - ba * h).norm() - radius
+            }
+            Sdf::Translate { offset, shape } => shape.distance(p - *offset),
+            Sdf::Union(shapes) => shapes
+                .iter()
+                .map(|shape| shape.distance(p))
+                .fold(f32::INFINITY, float::min),
+            Sdf::Intersection(shapes) => shapes
+                .iter()
+                .map(|shape| shape.distance(p))
+                .fold(f32::NEG_INFINITY, float::max),
+            Sdf::Subtraction(shapes) => {
+                let mut shapes = shapes.iter().map(|shape| shape.distance(p));
+                let first = shapes.next().unwrap_or(f32::INFINITY);
+                shapes.fold(first, |d, other| float::max(d, -other))
+            }
+            Sdf::SmoothUnion { smoothness, shapes } => shapes
+                .iter()
+                .map(|shape| shape.distance(p))
+                .reduce(|a, b| smooth_min(a, b, *smoothness))
+                .unwrap_or(f32::INFINITY),
+            Sdf::Repeat { period, shape } => {
+                let wrap = |x: f32, period: f32| {
+                    if period > 0.0 {
+                        x - period * (x / period).round()
+                    } else {
+                        x
+                    }
+                };
+                shape.distance(Point3::new(
+                    wrap(p.x(), period.x()),
+                    wrap(p.y(), period.y()),
+                    wrap(p.z(), period.z()),
+                ))
+            }
+            Sdf::Twist { rate, shape } => {
+                let (sin, cos) = (rate * p.y()).sin_cos();
+                shape.distance(Point3::new(
+                    cos * p.x() - sin * p.z(),
+                    p.y(),
+                    sin * p.x() + cos * p.z(),
+                ))
+            }
+        }
+    }
+
+    /// An upper bound on how quickly the distance function changes, for
+    /// points within `radius` of the y axis. Twisting stretches space, so the
+    /// distance it reports overestimates the true distance.
+    fn lipschitz(&self, radius: f32) -> f32 {
+        match self {
+            // Moving a shape off the axis moves the axis away from it, in the
+            // shape's own space, by as much.
+            Sdf::Translate { offset, shape } => {
+                let off_axis = Vector3::new(offset.x(), 0.0, offset.z()).norm();
+                shape.lipschitz(radius + off_axis)
+            }
+            Sdf::Repeat { shape, .. } => shape.lipschitz(radius),
+            Sdf::Union(shapes)
+            | Sdf::Intersection(shapes)
+            | Sdf::Subtraction(shapes)
+            | Sdf::SmoothUnion { shapes, .. } => shapes
+                .iter()
+                .map(|shape| shape.lipschitz(radius))
+                .fold(1.0, float::max),
+            Sdf::Twist { rate, shape } => {
+                shape.lipschitz(radius) * (1.0 + (rate * radius).powi(2)).sqrt()
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+fn box_distance(p: Point3, half_extents: Vector3) -> f32 {
+    let q = Vector3::new(
+        p.x().abs() - half_extents.x(),
+        p.y().abs() - half_extents.y(),
+        p.z().abs() - half_extents.z(),
+    );
+    let outside = Vector3::new(q.x().max(0.0), q.y().max(0.0), q.z().max(0.0)).norm();
+    let inside = float::min(float::max(q.x(), float::max(q.y(), q.z())), 0.0);
+    outside + inside
+}
+
+/// A polynomial smooth minimum, which blends the two distances when they are
+/// within `k` of each other.
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+/// A surface implicitly defined by a signed distance function, and rendered
+/// by sphere tracing within a bounding box.
+#[derive(Debug)]
+pub struct SignedDistanceField {
+    sdf: Sdf,
+    bounds: BoundingBox,
+    lipschitz: f32,
+}
+
+impl SignedDistanceField {
+    pub fn new(sdf: Sdf, bounds: BoundingBox) -> Self {
+        let radius = (0..3)
+            .map(|i| float::max(bounds.min[i].abs(), bounds.max[i].abs()))
+            .fold(0.0, float::max)
+            * 2f32.sqrt();
+        let lipschitz = sdf.lipschitz(radius);
+        SignedDistanceField {
+            sdf,
+            bounds,
+            lipschitz,
+        }
+    }
+
+    fn normal(&self, p: Point3) -> Vector3 {
+        let d = |dx, dy, dz| self.sdf.distance(p + Vector3::new(dx, dy, dz));
+        let h = NORMAL_DELTA;
+        Vector3::new(
+            d(h, 0.0, 0.0) - d(-h, 0.0, 0.0),
+            d(0.0, h, 0.0) - d(0.0, -h, 0.0),
+            d(0.0, 0.0, h) - d(0.0, 0.0, -h),
+        )
+        .normalize()
+    }
+}
+
+impl Surface for SignedDistanceField {
+    fn bounding_box(&self) -> BoundingBox {
+        self.bounds.clone()
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        let (tmin, tmax) = self.bounds.clip(ray)?;
+        let mut distance = float::max(tmin, MIN_DISTANCE);
+        for _ in 0..MAX_STEPS {
+            if distance > tmax {
+                return None;
+            }
+
+            // Step by the distance to the nearest surface, which can never
+            // overshoot it. Rays starting inside the surface march towards its
+            // boundary the same way.
+            let step = self.sdf.distance(ray.along(distance)).abs() / self.lipschitz;
+            if step < HIT_DISTANCE {
+                let position = ray.along(distance);
+                let normal = self.normal(position);
+                let frame = normal.tangent_space();
+                return Some(Intersection {
+                    distance,
+                    position,
+                    incident: ray.dir,
+                    normal,
+                    geometric_normal: normal,
+                    tangent: Vector3::new(frame[(0, 0)], frame[(1, 0)], frame[(2, 0)]),
+                    texture_coords: TextureCoords::default(),
+                    texture_density: 0.0,
+                    vertex_color: None,
+                });
+            }
+            distance += step;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capsule_with_meeting_ends_is_a_sphere() {
+        let end = Point3::new(1.0, 2.0, 3.0);
+        let capsule = Sdf::Capsule {
+            start: end,
+            end,
+            radius: 0.5,
+        };
+        let distance = capsule.distance(Point3::new(1.0, 2.0, 5.0));
+        assert!((distance - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn translated_twist_is_bounded_in_its_own_space() {
+        let twist = Sdf::Twist {
+            rate: 1.0,
+            shape: Box::new(Sdf::Sphere { radius: 1.0 }),
+        };
+        let moved = Sdf::Translate {
+            offset: Vector3::new(3.0, 0.0, 4.0),
+            shape: Box::new(twist.clone()),
+        };
+        // Within one unit of the axis, the twisted shape may be five units
+        // further out in its own space.
+        assert_eq!(moved.lipschitz(1.0), twist.lipschitz(6.0));
+        assert!(moved.lipschitz(1.0) > twist.lipschitz(1.0));
+    }
+}
@@ -0,0 +1,181 @@
+// Tests a ray against several bounding boxes at once, one per SIMD lane. Wide
+// BVH nodes hold four boxes, or eight with the `bvh8` feature. The vectorized
+// versions use SSE, or AVX when the target supports it (for example with
+// `RUSTFLAGS="-C target-cpu=native"`); elsewhere each lane is tested in turn.
+
+use crate::ray::Ray;
+
+#[cfg(not(feature = "bvh8"))]
+pub const LANES: usize = 4;
+#[cfg(feature = "bvh8")]
+pub const LANES: usize = 8;
+
+pub type Lanes = [f32; LANES];
+
+/// A ray, with the values box tests need precomputed.
+#[derive(Copy, Clone, Debug)]
+pub struct SimdRay {
+    origin: [f32; 3],
+    inv_dir: [f32; 3],
+    dir_is_neg: [bool; 3],
+}
+
+impl SimdRay {
+    pub fn new(ray: Ray) -> Self {
+        let inv_dir = [1.0 / ray.dir.x(), 1.0 / ray.dir.y(), 1.0 / ray.dir.z()];
+        SimdRay {
+            origin: [ray.origin.x(), ray.origin.y(), ray.origin.z()],
+            inv_dir,
+            dir_is_neg: inv_dir.map(|inv| inv < 0.0),
+        }
+    }
+
+    // The sides of the boxes along an axis which the ray reaches first and
+    // last. Empty boxes, with min above max, are therefore always missed.
+    fn slabs<'a>(&self, min: &'a Lanes, max: &'a Lanes, axis: usize) -> (&'a Lanes, &'a Lanes) {
+        if self.dir_is_neg[axis] {
+            (max, min)
+        } else {
+            (min, max)
+        }
+    }
+}
+
+/// Finds the distance along the ray at which it enters each box, given as the
+/// minimum and maximum corners per axis. Boxes which the ray misses, or only
+/// enters beyond `t_max`, are reported as infinitely far away.
+#[cfg(all(
+    target_arch = "x86_64",
+    any(not(feature = "bvh8"), target_feature = "avx")
+))]
+pub fn intersect_boxes(ray: &SimdRay, min: &[Lanes; 3], max: &[Lanes; 3], t_max: f32) -> Lanes {
+    #[cfg(feature = "bvh8")]
+    use self::avx::*;
+    #[cfg(not(feature = "bvh8"))]
+    use self::sse::*;
+
+    // SAFETY: the instructions used are supported by the target, and each load
+    // and store covers exactly one array of lanes.
+    unsafe {
+        let mut tmin = splat(0.0);
+        let mut tmax = splat(t_max);
+        for axis in 0..3 {
+            let (near, far) = ray.slabs(&min[axis], &max[axis], axis);
+            let origin = splat(ray.origin[axis]);
+            let inv_dir = splat(ray.inv_dir[axis]);
+            let tnear = mul(sub(load(near), origin), inv_dir);
+            let tfar = mul(sub(load(far), origin), inv_dir);
+            // A NaN, from an axis parallel ray starting on a slab, leaves the
+            // range unchanged since min and max return their second operand.
+            tmin = max_lanes(tnear, tmin);
+            tmax = min_lanes(tfar, tmax);
+        }
+        let mut result = [0.0; LANES];
+        store(&mut result, select_le(tmin, tmax, splat(f32::INFINITY)));
+        result
+    }
+}
+
+#[cfg(not(all(
+    target_arch = "x86_64",
+    any(not(feature = "bvh8"), target_feature = "avx")
+)))]
+pub fn intersect_boxes(ray: &SimdRay, min: &[Lanes; 3], max: &[Lanes; 3], t_max: f32) -> Lanes {
+    let mut tmin = [0.0; LANES];
+    let mut tmax = [t_max; LANES];
+    for axis in 0..3 {
+        let (near, far) = ray.slabs(&min[axis], &max[axis], axis);
+        for lane in 0..LANES {
+            let tnear = (near[lane] - ray.origin[axis]) * ray.inv_dir[axis];
+            let tfar = (far[lane] - ray.origin[axis]) * ray.inv_dir[axis];
+            tmin[lane] = tnear.max(tmin[lane]);
+            tmax[lane] = tfar.min(tmax[lane]);
+        }
+    }
+    std::array::from_fn(|lane| {
+        if tmin[lane] <= tmax[lane] {
+            tmin[lane]
+        } else {
+            f32::INFINITY
+        }
+    })
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "bvh8")))]
+mod sse {
+    use super::Lanes;
+    use std::arch::x86_64::*;
+
+    pub unsafe fn splat(x: f32) -> __m128 {
+        _mm_set1_ps(x)
+    }
+
+    pub unsafe fn load(lanes: &Lanes) -> __m128 {
+        _mm_loadu_ps(lanes.as_ptr())
+    }
+
+    pub unsafe fn store(lanes: &mut Lanes, x: __m128) {
+        _mm_storeu_ps(lanes.as_mut_ptr(), x)
+    }
+
+    pub unsafe fn sub(a: __m128, b: __m128) -> __m128 {
+        _mm_sub_ps(a, b)
+    }
+
+    pub unsafe fn mul(a: __m128, b: __m128) -> __m128 {
+        _mm_mul_ps(a, b)
+    }
+
+    pub unsafe fn min_lanes(a: __m128, b: __m128) -> __m128 {
+        _mm_min_ps(a, b)
+    }
+
+    pub unsafe fn max_lanes(a: __m128, b: __m128) -> __m128 {
+        _mm_max_ps(a, b)
+    }
+
+    /// Picks `a` in lanes where `a <= b`, and `otherwise` in the rest.
+    pub unsafe fn select_le(a: __m128, b: __m128, otherwise: __m128) -> __m128 {
+        let mask = _mm_cmple_ps(a, b);
+        _mm_or_ps(_mm_and_ps(mask, a), _mm_andnot_ps(mask, otherwise))
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "avx", feature = "bvh8"))]
+mod avx {
+    use super::Lanes;
+    use std::arch::x86_64::*;
+
+    pub unsafe fn splat(x: f32) -> __m256 {
+        _mm256_set1_ps(x)
+    }
+
+    pub unsafe fn load(lanes: &Lanes) -> __m256 {
+        _mm256_loadu_ps(lanes.as_ptr())
+    }
+
+    pub unsafe fn store(lanes: &mut Lanes, x: __m256) {
+        _mm256_storeu_ps(lanes.as_mut_ptr(), x)
+    }
+
+    pub unsafe fn sub(a: __m256, b: __m256) -> __m256 {
+        _mm256_sub_ps(a, b)
+    }
+
+    pub unsafe fn mul(a: __m256, b: __m256) -> __m256 {
+        _mm256_mul_ps(a, b)
+    }
+
+    pub unsafe fn min_lanes(a: __m256, b: __m256) -> __m256 {
+        _mm256_min_ps(a, b)
+    }
+
+    pub unsafe fn max_lanes(a: __m256, b: __m256) -> __m256 {
+        _mm256_max_ps(a, b)
+    }
+
+    /// Picks `a` in lanes where `a <= b`, and `otherwise` in the rest.
+    pub unsafe fn select_le(a: __m256, b: __m256, otherwise: __m256) -> __m256 {
+        _mm256_blendv_ps(otherwise, a, _mm256_cmp_ps::<_CMP_LE_OQ>(a, b))
+    }
+}
@@ -1,8 +1,11 @@
 use std::sync::atomic::AtomicUsize;
 
 pub static RAYS_CAST: AtomicUsize = AtomicUsize::new(0);
+pub static OCCLUSION_RAYS_CAST: AtomicUsize = AtomicUsize::new(0);
+pub static PACKETS_CAST: AtomicUsize = AtomicUsize::new(0);
 pub static BOUNDING_BOX_TESTS: AtomicUsize = AtomicUsize::new(0);
 pub static TRIANGLE_TESTS: AtomicUsize = AtomicUsize::new(0);
+pub static CURVE_TESTS: AtomicUsize = AtomicUsize::new(0);
 
 #[macro_export]
 macro_rules! increment_statistic {
@@ -25,7 +28,10 @@ pub fn report_statistics() {
         println!();
         println!("Statistics:");
         print_statistic!("Rays cast:", RAYS_CAST);
+        print_statistic!("Occlusion rays cast:", OCCLUSION_RAYS_CAST);
+        print_statistic!("Ray packets cast:", PACKETS_CAST);
         print_statistic!("Bounding box tests:", BOUNDING_BOX_TESTS);
         print_statistic!("Triangle tests:", TRIANGLE_TESTS);
+        print_statistic!("Curve tests:", CURVE_TESTS);
     }
 }
@@ -0,0 +1,252 @@
+// A reader for triangle meshes in the STL format, as exported by CAD software,
+// in either its ASCII or its binary form. STL stores each facet's corners
+// separately, so facets only share vertices once they are welded together.
+// The facet normals in the file are ignored, as exporters often leave them out
+// and the winding order gives the same normal.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::types::Point3;
+
+// Binary files start with a header of this many bytes, then a facet count.
+const BINARY_HEADER: usize = 80;
+// Each binary facet is a normal and three corners of three floats, then two
+// bytes of attributes.
+const BINARY_FACET: usize = 50;
+
+/// The corners of an STL file's facets, and the triangles which index them.
+#[derive(Debug, Default)]
+pub struct StlMesh {
+    pub positions: Vec<Point3>,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StlError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid data: {0}")]
+    Data(String),
+}
+
+/// Loads an STL file. If `weld` is set, corners at exactly the same position
+/// become one vertex, and facets which collapse as a result are dropped.
+pub fn load_stl(path: &Path, weld: bool) -> Result<StlMesh, StlError> {
+    parse_stl(&std::fs::read(path)?, weld)
+}
+
+fn parse_stl(bytes: &[u8], weld: bool) -> Result<StlMesh, StlError> {
+    let corners = if is_binary(bytes) {
+        read_binary(bytes)?
+    } else {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|_| StlError::Data("file is neither binary nor text".to_string()))?;
+        read_ascii(text)?
+    };
+
+    let mut mesh = StlMesh::default();
+    if !weld {
+        mesh.positions = corners;
+        mesh.triangles = (0..mesh.positions.len() as u32 / 3)
+            .map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+            .collect();
+        return Ok(mesh);
+    }
+
+    let mut vertices = HashMap::new();
+    for facet in corners.chunks_exact(3) {
+        let triangle = [0, 1, 2].map(|i| {
+            let position = facet[i];
+            // Adding zero turns negative zeros positive, so that they weld.
+            let key = [0, 1, 2].map(|axis| (position[axis] + 0.0).to_bits());
+            *vertices.entry(key).or_insert_with(|| {
+                mesh.positions.push(position);
+                mesh.positions.len() as u32 - 1
+            })
+        });
+        let [a, b, c] = triangle;
+        if a != b && b != c && c != a {
+            mesh.triangles.push(triangle);
+        }
+    }
+    Ok(mesh)
+}
+
+// ASCII files start with "solid", but so do some binary headers, so a file is
+// taken to be binary if its length matches the facet count it would have.
+fn is_binary(bytes: &[u8]) -> bool {
+    match bytes.get(BINARY_HEADER..BINARY_HEADER + 4) {
+        Some(count) => {
+            let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+            let expected = BINARY_HEADER + 4 + count * BINARY_FACET;
+            bytes.len() == expected || !bytes.starts_with(b"solid")
+        }
+        None => false,
+    }
+}
+
+fn read_binary(bytes: &[u8]) -> Result<Vec<Point3>, StlError> {
+    let count = u32::from_le_bytes(bytes[BINARY_HEADER..BINARY_HEADER + 4].try_into().unwrap());
+    let facets = &bytes[BINARY_HEADER + 4..];
+    if facets.len() < count as usize * BINARY_FACET {
+        return Err(StlError::Data(format!(
+            "expected {} facets, found {}",
+            count,
+            facets.len() / BINARY_FACET
+        )));
+    }
+
+    let float = |bytes: &[u8]| f32::from_le_bytes(bytes.try_into().unwrap());
+    let mut corners = Vec::with_capacity(3 * count as usize);
+    for facet in facets.chunks_exact(BINARY_FACET).take(count as usize) {
+        // Skip the normal at the start.
+        for corner in facet[12..48].chunks_exact(12) {
+            corners.push(Point3::new(
+                float(&corner[0..4]),
+                float(&corner[4..8]),
+                float(&corner[8..12]),
+            ));
+        }
+    }
+    Ok(corners)
+}
+
+fn read_ascii(text: &str) -> Result<Vec<Point3>, StlError> {
+    let mut tokens = text.split_ascii_whitespace();
+    let number = |tokens: &mut std::str::SplitAsciiWhitespace| {
+        let token = tokens
+            .next()
+            .ok_or_else(|| StlError::Data("unexpected end of file".to_string()))?;
+        token
+            .parse::<f32>()
+            .map_err(|_| StlError::Data(format!("invalid number: {}", token)))
+    };
+
+    // Loops with more than three corners are split into triangle fans.
+    let mut corners = Vec::new();
+    let mut polygon = Vec::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            "vertex" => {
+                let x = number(&mut tokens)?;
+                let y = number(&mut tokens)?;
+                let z = number(&mut tokens)?;
+                polygon.push(Point3::new(x, y, z));
+            }
+            "endloop" => {
+                for k in 1..polygon.len().saturating_sub(1) {
+                    corners.extend([polygon[0], polygon[k], polygon[k + 1]]);
+                }
+                polygon.clear();
+            }
+            // Anything after the end of the solid is ignored, and a file
+            // which stops short of it has been cut off.
+            "endsolid" => return Ok(corners),
+            _ => (),
+        }
+    }
+    Err(StlError::Data("missing endsolid".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two facets making a unit square, which share the corners along its
+    // diagonal.
+    const SQUARE: [[[f32; 3]; 3]; 2] = [
+        [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+        [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+    ];
+
+    fn binary(header: &[u8], facets: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.resize(BINARY_HEADER, 0);
+        bytes.extend((facets.len() as u32).to_le_bytes());
+        for facet in facets {
+            // The normal is ignored.
+            bytes.extend([0; 12]);
+            for value in facet.iter().flatten() {
+                bytes.extend(value.to_le_bytes());
+            }
+            bytes.extend([0; 2]);
+        }
+        bytes
+    }
+
+    fn ascii(facets: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        let mut text = "solid square\n".to_string();
+        for facet in facets {
+            text += "facet normal 0 0 1\nouter loop\n";
+            for [x, y, z] in facet {
+                text += &format!("vertex {x} {y} {z}\n");
+            }
+            text += "endloop\nendfacet\n";
+        }
+        text += "endsolid square\n";
+        text.into_bytes()
+    }
+
+    fn corners(mesh: &StlMesh) -> Vec<[[f32; 3]; 3]> {
+        let position = |i: u32| {
+            let p = mesh.positions[i as usize];
+            [p.x(), p.y(), p.z()]
+        };
+        mesh.triangles.iter().map(|t| t.map(position)).collect()
+    }
+
+    #[test]
+    fn reads_ascii_and_binary() {
+        for bytes in [ascii(&SQUARE), binary(b"exported", &SQUARE)] {
+            let mesh = parse_stl(&bytes, false).unwrap();
+            assert_eq!(corners(&mesh), SQUARE);
+        }
+    }
+
+    #[test]
+    fn reads_binary_with_solid_header() {
+        let bytes = binary(b"solid square", &SQUARE);
+        assert!(is_binary(&bytes));
+        let mesh = parse_stl(&bytes, false).unwrap();
+        assert_eq!(corners(&mesh), SQUARE);
+    }
+
+    #[test]
+    fn welds_shared_corners() {
+        let mut facets = SQUARE.to_vec();
+        // Negative zero is the same position as zero.
+        facets[1][0] = [-0.0, 0.0, -0.0];
+        // A facet which collapses to a line once welded.
+        facets.push([[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 0.0]]);
+
+        let mesh = parse_stl(&binary(b"", &facets), false).unwrap();
+        assert_eq!(mesh.positions.len(), 9);
+        assert_eq!(mesh.triangles.len(), 3);
+
+        let mesh = parse_stl(&binary(b"", &facets), true).unwrap();
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.triangles, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(corners(&mesh), SQUARE);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let files = [
+            binary(b"exported", &SQUARE),
+            binary(b"solid square", &SQUARE),
+            ascii(&SQUARE),
+        ];
+        for bytes in files {
+            let end = match bytes.windows(8).position(|w| w == b"endsolid") {
+                Some(end) => end,
+                None => bytes.len(),
+            };
+            for len in 0..end {
+                let result = parse_stl(&bytes[..len], true);
+                assert!(matches!(result, Err(StlError::Data(_))), "{}", len);
+            }
+        }
+    }
+}
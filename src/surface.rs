@@ -1,10 +1,14 @@
+use std::f32::consts::PI;
 use std::fmt::Debug;
 
+use palette::LinSrgb;
+
 use crate::bounds::BoundingBox;
 use crate::float;
+use crate::polynomial;
 use crate::ray::Ray;
 use crate::texture::TextureCoords;
-use crate::types::{Point3, Vector3};
+use crate::types::{Axis, Mat3, Point3, Vector3};
 use crate::{increment_statistic, statistics};
 
 #[derive(Copy, Clone, Debug)]
@@ -12,13 +16,51 @@ pub struct Intersection {
     pub distance: f32,
     pub position: Point3,
     pub incident: Vector3,
+    /// The shading normal, which may be interpolated across the surface.
     pub normal: Vector3,
+    /// The true normal of the surface, which rays leaving it are offset along.
+    pub geometric_normal: Vector3,
+    pub tangent: Vector3,
     pub texture_coords: TextureCoords,
+    /// How far the texture coordinates move per unit of distance across the
+    /// surface, which sets how blurred its textures are, or zero if unknown.
+    pub texture_density: f32,
+    /// The color interpolated from the surface's vertices, if it has any,
+    /// which tints its material.
+    pub vertex_color: Option<LinSrgb>,
 }
 
 pub trait Surface: Debug + Sync {
     fn bounding_box(&self) -> BoundingBox;
     fn intersect(&self, ray: Ray) -> Option<Intersection>;
+
+    /// Checks whether the ray hits the surface before reaching `t_max`.
+    /// Surfaces can override this to skip computing the full intersection.
+    fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        self.intersect(ray)
+            .is_some_and(|intersection| intersection.distance < t_max)
+    }
+
+    /// Bounds the part of the surface which lies within a box. Surfaces can
+    /// override this to give tighter bounds than the box's overlap with
+    /// their own bounding box.
+    fn clip_bounds(&self, bounds: &BoundingBox) -> BoundingBox {
+        BoundingBox::intersection(&self.bounding_box(), bounds)
+    }
+}
+
+/// An interval along a ray which lies inside of a solid.
+#[derive(Copy, Clone, Debug)]
+pub struct Span {
+    pub enter: Intersection,
+    pub exit: Intersection,
+}
+
+/// A closed surface, which encloses a volume.
+pub trait Solid: Surface {
+    /// Finds every interval along the entire line of a ray that lies inside
+    /// the solid, including those behind its origin, in increasing order.
+    fn spans(&self, ray: Ray) -> Vec<Span>;
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -46,29 +88,74 @@ impl Surface for Sphere {
     }
 
     fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        let (near, far) = self.roots(ray)?;
+        [near, far]
+            .into_iter()
+            .find(|&distance| distance > 0.0)
+            .map(|distance| self.hit(ray, distance))
+    }
+}
+
+impl Solid for Sphere {
+    fn spans(&self, ray: Ray) -> Vec<Span> {
+        self.roots(ray)
+            .map(|(near, far)| Span {
+                enter: self.hit(ray, near),
+                exit: self.hit(ray, far),
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+impl Sphere {
+    fn roots(&self, ray: Ray) -> Option<(f32, f32)> {
         // Find the discriminant
-        let b = (ray.origin - self.center).dot(ray.dir) * 2.0;
-        let c = (ray.origin - self.center).norm_squared() - self.radius * self.radius;
-        let dis = b * b - 4.0 * c;
+        let offset = ray.origin - self.center;
+        let b = offset.dot(ray.dir);
+        let c = offset.norm_squared() - self.radius * self.radius;
+        let dis = b * b - c;
 
         // If the discriminant is negative, then no intersection exists.
-        // Otherwise, solve the quadratic
+        // Otherwise, solve the quadratic. The root furthest from zero is found
+        // first, since subtracting to find the nearer one would cancel out
+        // most of its precision, which rays leaving the sphere depend on.
         if dis < 0.0 {
-            None
+            return None;
+        }
+        let root1 = -b - dis.sqrt().copysign(b);
+        let root2 = c / root1;
+        Some((root1.min(root2), root1.max(root2)))
+    }
+
+    fn hit(&self, ray: Ray, distance: f32) -> Intersection {
+        let normal = (ray.along(distance) - self.center).normalize();
+        // Project the hit back onto the sphere, which is more accurate than
+        // walking along the ray.
+        let position = self.center + normal * self.radius;
+
+        // Map latitude and longitude about the y axis onto the texture.
+        let u = azimuth(normal.x(), normal.z());
+        let v = 0.5 + normal.y().clamp(-1.0, 1.0).asin() / PI;
+        let tangent = Vector3::new(-normal.z(), 0.0, normal.x());
+        let tangent = if tangent.norm_squared() > 0.0 {
+            tangent.normalize()
         } else {
-            let distance = (-b - dis.sqrt()) / 2.0;
-            // Distance threshold to prevent self-intersection
-            if distance <= float::EPSILON {
-                return None;
-            }
-            let position = ray.along(distance);
-            Some(Intersection {
-                distance,
-                position,
-                incident: ray.dir,
-                normal: (position - self.center).normalize(),
-                texture_coords: TextureCoords::default(),
-            })
+            // At the poles, any direction is tangent.
+            Vector3::new(1.0, 0.0, 0.0)
+        };
+        Intersection {
+            distance,
+            position,
+            incident: ray.dir,
+            normal,
+            geometric_normal: normal,
+            tangent,
+            texture_coords: TextureCoords::new(u, v),
+            // Texture coordinates wrap once around the sphere, and half way
+            // from pole to pole.
+            texture_density: 1.0 / (PI * self.radius),
+            vertex_color: None,
         }
     }
 }
@@ -79,6 +166,7 @@ pub struct Triangle {
     edge1: Vector3,
     edge2: Vector3,
     normals: [Vector3; 3],
+    tangent: Vector3,
     texture_coords: [TextureCoords; 3],
 }
 
@@ -97,62 +185,837 @@ impl Triangle {
             edge1: e1,
             edge2: e2,
             normals,
+            tangent: triangle_tangent(e1, e2, &texture_coords),
             texture_coords,
         }
     }
+
+    fn vertices(&self) -> [Point3; 3] {
+        [
+            self.vertex,
+            self.vertex + self.edge1,
+            self.vertex + self.edge2,
+        ]
+    }
 }
 
 impl Surface for Triangle {
     fn bounding_box(&self) -> BoundingBox {
-        let v1 = self.vertex;
-        let v2 = self.vertex + self.edge1;
-        let v3 = self.vertex + self.edge2;
-        let (xmin, xmax) = float_bounds(&[v1.x(), v2.x(), v3.x()]);
-        let (ymin, ymax) = float_bounds(&[v1.y(), v2.y(), v3.y()]);
-        let (zmin, zmax) = float_bounds(&[v1.z(), v2.z(), v3.z()]);
-        BoundingBox::axis_aligned(xmin, xmax, ymin, ymax, zmin, zmax)
+        triangle_bounds(&self.vertices())
     }
 
     fn intersect(&self, ray: Ray) -> Option<Intersection> {
-        increment_statistic!(statistics::TRIANGLE_TESTS);
+        let hit = hit_triangle(&self.vertices(), ray)?;
+        Some(triangle_intersection(
+            ray,
+            (self.vertex, self.edge1, self.edge2),
+            hit,
+            &self.normals,
+            self.tangent,
+            &self.texture_coords,
+            None,
+        ))
+    }
 
-        let pvec = ray.dir.cross(self.edge2);
-        let det = self.edge1.dot(pvec);
-        if det.abs() < float::EPSILON {
-            // Ray is parallel to plane.
-            return None;
+    fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        hit_triangle(&self.vertices(), ray).is_some_and(|(distance, _u, _v)| distance < t_max)
+    }
+
+    fn clip_bounds(&self, bounds: &BoundingBox) -> BoundingBox {
+        clip_triangle(&self.vertices(), bounds)
+    }
+}
+
+pub(crate) fn triangle_bounds(vertices: &[Point3; 3]) -> BoundingBox {
+    let [v1, v2, v3] = vertices;
+    let (xmin, xmax) = float_bounds(&[v1.x(), v2.x(), v3.x()]);
+    let (ymin, ymax) = float_bounds(&[v1.y(), v2.y(), v3.y()]);
+    let (zmin, zmax) = float_bounds(&[v1.z(), v2.z(), v3.z()]);
+    BoundingBox::axis_aligned(xmin, xmax, ymin, ymax, zmin, zmax)
+}
+
+/// Finds the distance to a ray's hit on the triangle with the given vertices,
+/// and the hit's barycentric coordinates.
+///
+/// This uses the Möller-Trumbore test, which is fast but can let rays through
+/// the edges shared by neighbouring triangles. The `watertight` feature swaps
+/// it for a test which can't.
+pub(crate) fn hit_triangle(vertices: &[Point3; 3], ray: Ray) -> Option<(f32, f32, f32)> {
+    increment_statistic!(statistics::TRIANGLE_TESTS);
+
+    if cfg!(feature = "watertight") {
+        hit_triangle_watertight(vertices, ray)
+    } else {
+        hit_triangle_moller_trumbore(vertices, ray)
+    }
+}
+
+/// The Möller-Trumbore ray/triangle test.
+fn hit_triangle_moller_trumbore(
+    &[vertex, v1, v2]: &[Point3; 3],
+    ray: Ray,
+) -> Option<(f32, f32, f32)> {
+    let (edge1, edge2) = (v1 - vertex, v2 - vertex);
+    let pvec = ray.dir.cross(edge2);
+    let det = edge1.dot(pvec);
+    if det.abs() < float::EPSILON {
+        // Ray is parallel to plane.
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = ray.origin - vertex;
+    let u = tvec.dot(pvec) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let qvec = tvec.cross(edge1);
+    let v = qvec.dot(ray.dir) * inv_det;
+    if v < 0.0 || (u + v) > 1.0 {
+        return None;
+    }
+
+    let distance = edge2.dot(qvec) * inv_det;
+    if distance <= 0.0 {
+        return None;
+    }
+    Some((distance, u, v))
+}
+
+/// The watertight ray/triangle test from "Watertight Ray/Triangle
+/// Intersection" (Woop, Benthin and Wald). The triangle is transformed so
+/// that the ray runs along the z axis from the origin, which reduces the test
+/// to checking which side of each edge the origin lies on in 2D. Neighbouring
+/// triangles compute exactly the same value for a shared edge, as long as
+/// they are given the same vertices for it, so a ray can't slip between them.
+fn hit_triangle_watertight(vertices: &[Point3; 3], ray: Ray) -> Option<(f32, f32, f32)> {
+    // Permute the axes so that the ray's direction is largest along z, with
+    // the winding of x and y preserved.
+    let dir = ray.dir;
+    let kz = (0..3)
+        .max_by(|&a, &b| float::compare(&dir[a].abs(), &dir[b].abs()))
+        .unwrap();
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if dir[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear the vertices, relative to the ray's origin, so that the ray points
+    // along z.
+    let shear_x = dir[kx] / dir[kz];
+    let shear_y = dir[ky] / dir[kz];
+    let shear_z = 1.0 / dir[kz];
+    let relative = vertices.map(|v| v - ray.origin);
+    let [(ax, ay), (bx, by), (cx, cy)] =
+        relative.map(|v| (v[kx] - shear_x * v[kz], v[ky] - shear_y * v[kz]));
+
+    // Scaled barycentric coordinates, from which edge the origin lies beside.
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        // The origin is on an edge, or too close to tell which side it's on,
+        // so recompute them in double precision.
+        let [ax, ay, bx, by, cx, cy] = [ax, ay, bx, by, cx, cy].map(f64::from);
+        u = (cx * by - cy * bx) as f32;
+        v = (ax * cy - ay * cx) as f32;
+        w = (bx * ay - by * ax) as f32;
+    }
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        // Ray is parallel to plane.
+        return None;
+    }
+
+    let [az, bz, cz] = relative.map(|v| shear_z * v[kz]);
+    let distance = (u * az + v * bz + w * cz) / det;
+    if distance <= 0.0 {
+        return None;
+    }
+    Some((distance, v / det, w / det))
+}
+
+/// Solves for the direction in which the u texture coordinate increases
+/// across a triangle. Without usable texture coordinates, this falls back to
+/// an arbitrary edge.
+pub(crate) fn triangle_tangent(
+    edge1: Vector3,
+    edge2: Vector3,
+    texture_coords: &[TextureCoords; 3],
+) -> Vector3 {
+    let duv1 = texture_coords[1] - texture_coords[0];
+    let duv2 = texture_coords[2] - texture_coords[0];
+    let det = duv1.x() * duv2.y() - duv2.x() * duv1.y();
+    if det.abs() < float::EPSILON {
+        edge1.normalize()
+    } else {
+        ((duv2.y() * edge1 - duv1.y() * edge2) / det).normalize()
+    }
+}
+
+/// Interpolates the vertex attributes of the triangle with a vertex and two
+/// edges from it at a hit found by `hit_triangle`.
+pub(crate) fn triangle_intersection(
+    ray: Ray,
+    (vertex, edge1, edge2): (Point3, Vector3, Vector3),
+    (distance, u, v): (f32, f32, f32),
+    normals: &[Vector3; 3],
+    tangent: Vector3,
+    texture_coords: &[TextureCoords; 3],
+    colors: Option<&[LinSrgb; 3]>,
+) -> Intersection {
+    let w = 1.0 - u - v;
+    let normal = (w * normals[0] + u * normals[1] + v * normals[2]).normalize();
+    let tangent = (tangent - normal * normal.dot(tangent)).normalize();
+    // The ratio of the triangle's area in texture coordinates to its area in
+    // space.
+    let duv1 = texture_coords[1] - texture_coords[0];
+    let duv2 = texture_coords[2] - texture_coords[0];
+    let texture_area = (duv1.x() * duv2.y() - duv2.x() * duv1.y()).abs();
+    let texture_density = (texture_area / edge1.cross(edge2).norm()).sqrt();
+    let texture_coords = w * texture_coords[0] + u * texture_coords[1] + v * texture_coords[2];
+    Intersection {
+        distance,
+        // Barycentric coordinates place the hit more accurately than walking
+        // along the ray.
+        position: vertex + u * edge1 + v * edge2,
+        incident: ray.dir,
+        normal,
+        geometric_normal: edge1.cross(edge2).normalize(),
+        tangent,
+        texture_coords,
+        texture_density: if texture_density.is_finite() {
+            texture_density
+        } else {
+            0.0
+        },
+        vertex_color: colors.map(|c| c[0] * w + c[1] * u + c[2] * v),
+    }
+}
+
+/// Bounds the part of a triangle which lies within a box.
+pub(crate) fn clip_triangle(vertices: &[Point3; 3], bounds: &BoundingBox) -> BoundingBox {
+    // Clip the triangle against each side of the box in turn
+    // (Sutherland-Hodgman), and bound the polygon that remains.
+    let mut polygon = [Point3::origin(); MAX_CLIPPED_VERTICES];
+    polygon[..3].copy_from_slice(vertices);
+    let mut len = 3;
+    // Sides the triangle lies entirely within can be skipped.
+    let own = triangle_bounds(vertices);
+    for axis in [Axis::X, Axis::Y, Axis::Z] {
+        if own.min[axis] < bounds.min[axis] {
+            (polygon, len) = clip_polygon(&polygon[..len], |p| p[axis] - bounds.min[axis]);
         }
-        let inv_det = 1.0 / det;
+        if own.max[axis] > bounds.max[axis] {
+            (polygon, len) = clip_polygon(&polygon[..len], |p| bounds.max[axis] - p[axis]);
+        }
+    }
+    let mut bb = BoundingBox::empty();
+    for &p in &polygon[..len] {
+        bb.add_point(p);
+    }
+    // Rounding may leave points just outside of the box.
+    BoundingBox::intersection(&bb, bounds)
+}
 
-        let tvec = ray.origin - self.vertex;
-        let u = tvec.dot(pvec) * inv_det;
-        if !(0.0..=1.0).contains(&u) {
-            return None;
+// A triangle clipped by the six sides of a box has at most nine vertices.
+const MAX_CLIPPED_VERTICES: usize = 9;
+
+/// Clips a convex polygon to the region where `side` is not negative.
+fn clip_polygon<F>(polygon: &[Point3], side: F) -> ([Point3; MAX_CLIPPED_VERTICES], usize)
+where
+    F: Fn(Point3) -> f32,
+{
+    let mut result = [Point3::origin(); MAX_CLIPPED_VERTICES];
+    let mut len = 0;
+    for (i, &p) in polygon.iter().enumerate() {
+        let q = polygon[(i + 1) % polygon.len()];
+        let (dp, dq) = (side(p), side(q));
+        if dp >= 0.0 {
+            result[len] = p;
+            len += 1;
+        }
+        if (dp >= 0.0) != (dq >= 0.0) {
+            let t = dp / (dp - dq);
+            result[len] = p + (q - p) * t;
+            len += 1;
         }
+    }
+    (result, len)
+}
 
-        let qvec = tvec.cross(self.edge1);
-        let v = qvec.dot(ray.dir) * inv_det;
-        if v < 0.0 || (u + v) > 1.0 {
-            return None;
+#[derive(Copy, Clone, Debug)]
+pub struct Plane {
+    frame: Frame,
+}
+
+impl Plane {
+    pub fn new(point: Point3, normal: Vector3) -> Self {
+        Plane {
+            frame: Frame::along(point, normal),
         }
+    }
+}
 
-        let distance = self.edge2.dot(qvec) * inv_det;
-        if distance < float::EPSILON {
-            return None;
+impl LocalSurface for Plane {
+    fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    fn local_hits<F: FnMut(LocalHit)>(&self, ray: Ray, mut visit: F) {
+        let distance = -ray.origin.z() / ray.dir.z();
+        if distance.is_finite() {
+            let p = ray.along(distance);
+            visit(LocalHit {
+                distance,
+                normal: Vector3::new(0.0, 0.0, 1.0),
+                tangent: Vector3::new(1.0, 0.0, 0.0),
+                texture_coords: TextureCoords::new(p.x().rem_euclid(1.0), p.y().rem_euclid(1.0)),
+                texture_density: 1.0,
+            });
         }
+    }
+}
 
-        let w = 1.0 - u - v;
-        let normal = (w * self.normals[0] + u * self.normals[1] + v * self.normals[2]).normalize();
-        let texture_coords =
-            w * self.texture_coords[0] + u * self.texture_coords[1] + v * self.texture_coords[2];
+impl Surface for Plane {
+    fn bounding_box(&self) -> BoundingBox {
+        BoundingBox::infinite()
+    }
 
-        Some(Intersection {
-            distance,
-            position: ray.along(distance),
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        intersect_local(self, ray)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Disk {
+    frame: Frame,
+    radius: f32,
+}
+
+impl Disk {
+    pub fn new(center: Point3, normal: Vector3, radius: f32) -> Self {
+        Disk {
+            frame: Frame::along(center, normal),
+            radius,
+        }
+    }
+}
+
+impl LocalSurface for Disk {
+    fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    fn local_hits<F: FnMut(LocalHit)>(&self, ray: Ray, visit: F) {
+        cap_hit(ray, 0.0, 1.0, self.radius, visit);
+    }
+}
+
+impl Surface for Disk {
+    fn bounding_box(&self) -> BoundingBox {
+        let r = self.radius;
+        self.frame
+            .bounding_box(Point3::new(-r, -r, 0.0), Point3::new(r, r, 0.0))
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        intersect_local(self, ray)
+    }
+}
+
+/// A cylinder capped at both ends, extending from the center of its base
+/// along its axis.
+#[derive(Copy, Clone, Debug)]
+pub struct Cylinder {
+    frame: Frame,
+    radius: f32,
+    height: f32,
+}
+
+impl Cylinder {
+    pub fn new(base: Point3, axis: Vector3, radius: f32, height: f32) -> Self {
+        Cylinder {
+            frame: Frame::along(base, axis),
+            radius,
+            height,
+        }
+    }
+}
+
+impl LocalSurface for Cylinder {
+    fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    fn local_hits<F: FnMut(LocalHit)>(&self, ray: Ray, mut visit: F) {
+        let (o, d) = (ray.origin - Point3::origin(), ray.dir);
+        let a = d.x() * d.x() + d.y() * d.y();
+        if a > 0.0 {
+            let b = 2.0 * (o.x() * d.x() + o.y() * d.y());
+            let c = o.x() * o.x() + o.y() * o.y() - self.radius * self.radius;
+            for root in polynomial::solve_quadratic([c as f64, b as f64, a as f64]) {
+                let distance = root as f32;
+                let p = ray.along(distance);
+                if (0.0..=self.height).contains(&p.z()) {
+                    visit(LocalHit {
+                        distance,
+                        normal: Vector3::new(p.x(), p.y(), 0.0) / self.radius,
+                        tangent: azimuth_tangent(p.x(), p.y()),
+                        texture_coords: TextureCoords::new(
+                            azimuth(p.x(), p.y()),
+                            p.z() / self.height,
+                        ),
+                        texture_density: texture_density(
+                            1.0 / (2.0 * PI * self.radius),
+                            1.0 / self.height,
+                        ),
+                    });
+                }
+            }
+        }
+        cap_hit(ray, 0.0, -1.0, self.radius, &mut visit);
+        cap_hit(ray, self.height, 1.0, self.radius, &mut visit);
+    }
+}
+
+impl Solid for Cylinder {
+    fn spans(&self, ray: Ray) -> Vec<Span> {
+        local_spans(self, ray)
+    }
+}
+
+impl Surface for Cylinder {
+    fn bounding_box(&self) -> BoundingBox {
+        let r = self.radius;
+        self.frame
+            .bounding_box(Point3::new(-r, -r, 0.0), Point3::new(r, r, self.height))
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        intersect_local(self, ray)
+    }
+}
+
+/// A cone capped at its base, extending from the center of its base along its
+/// axis to its apex.
+#[derive(Copy, Clone, Debug)]
+pub struct Cone {
+    frame: Frame,
+    radius: f32,
+    height: f32,
+}
+
+impl Cone {
+    pub fn new(base: Point3, axis: Vector3, radius: f32, height: f32) -> Self {
+        Cone {
+            frame: Frame::along(base, axis),
+            radius,
+            height,
+        }
+    }
+}
+
+impl LocalSurface for Cone {
+    fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    fn local_hits<F: FnMut(LocalHit)>(&self, ray: Ray, mut visit: F) {
+        // The side satisfies x^2 + y^2 = k^2 (h - z)^2, where k is the slope
+        // of the cone.
+        let (o, d) = (ray.origin - Point3::origin(), ray.dir);
+        let k2 = (self.radius / self.height).powi(2);
+        let dz = self.height - o.z();
+        let a = d.x() * d.x() + d.y() * d.y() - k2 * d.z() * d.z();
+        let b = 2.0 * (o.x() * d.x() + o.y() * d.y() + k2 * dz * d.z());
+        let c = o.x() * o.x() + o.y() * o.y() - k2 * dz * dz;
+        let roots = if a.abs() < float::EPSILON {
+            // The ray is parallel to the side of the cone.
+            vec![(-c / b) as f64]
+        } else {
+            polynomial::solve_quadratic([c as f64, b as f64, a as f64])
+        };
+        for root in roots {
+            let distance = root as f32;
+            let p = ray.along(distance);
+            if distance.is_finite() && (0.0..=self.height).contains(&p.z()) {
+                let normal = Vector3::new(p.x(), p.y(), k2 * (self.height - p.z()));
+                // The side is this much longer than the cone is high.
+                let slant = (1.0 + k2).sqrt();
+                let radial = (p.x() * p.x() + p.y() * p.y()).sqrt();
+                visit(LocalHit {
+                    distance,
+                    normal: normal.normalize(),
+                    tangent: azimuth_tangent(p.x(), p.y()),
+                    texture_coords: TextureCoords::new(azimuth(p.x(), p.y()), p.z() / self.height),
+                    texture_density: texture_density(
+                        1.0 / (2.0 * PI * radial),
+                        1.0 / (slant * self.height),
+                    ),
+                });
+            }
+        }
+        cap_hit(ray, 0.0, -1.0, self.radius, &mut visit);
+    }
+}
+
+impl Solid for Cone {
+    fn spans(&self, ray: Ray) -> Vec<Span> {
+        local_spans(self, ray)
+    }
+}
+
+impl Surface for Cone {
+    fn bounding_box(&self) -> BoundingBox {
+        let r = self.radius;
+        self.frame
+            .bounding_box(Point3::new(-r, -r, 0.0), Point3::new(r, r, self.height))
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        intersect_local(self, ray)
+    }
+}
+
+/// A rectangular box, optionally rotated about its center.
+#[derive(Copy, Clone, Debug)]
+pub struct Cuboid {
+    frame: Frame,
+    half_extents: Vector3,
+}
+
+impl Cuboid {
+    pub fn new(min: Point3, max: Point3, rotation: Mat3) -> Self {
+        let half_extents = (max - min) / 2.0;
+        Cuboid {
+            frame: Frame::new(min + half_extents, rotation),
+            half_extents,
+        }
+    }
+}
+
+impl LocalSurface for Cuboid {
+    fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    fn local_hits<F: FnMut(LocalHit)>(&self, ray: Ray, mut visit: F) {
+        let mut tmin = f32::NEG_INFINITY;
+        let mut tmax = f32::INFINITY;
+        for i in 0..3 {
+            let t1 = (-self.half_extents[i] - ray.origin[i]) / ray.dir[i];
+            let t2 = (self.half_extents[i] - ray.origin[i]) / ray.dir[i];
+            tmin = float::max(tmin, float::min(t1, t2));
+            tmax = float::min(tmax, float::max(t1, t2));
+        }
+        if tmin > tmax {
+            return;
+        }
+
+        for distance in [tmin, tmax] {
+            // The face we hit is the one whose axis we are furthest along,
+            // relative to the size of the box.
+            let p = ray.along(distance);
+            let relative = |i: usize| p[i] / self.half_extents[i];
+            let axis = (0..3)
+                .max_by(|&i, &j| float::compare(&relative(i).abs(), &relative(j).abs()))
+                .unwrap();
+            let mut normal = [0.0; 3];
+            normal[axis] = relative(axis).signum();
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let mut tangent = [0.0; 3];
+            tangent[u] = 1.0;
+            visit(LocalHit {
+                distance,
+                normal: Vector3::new(normal[0], normal[1], normal[2]),
+                tangent: Vector3::new(tangent[0], tangent[1], tangent[2]),
+                texture_coords: TextureCoords::new(
+                    (relative(u) + 1.0) / 2.0,
+                    (relative(v) + 1.0) / 2.0,
+                ),
+                texture_density: texture_density(
+                    0.5 / self.half_extents[u],
+                    0.5 / self.half_extents[v],
+                ),
+            });
+        }
+    }
+}
+
+impl Solid for Cuboid {
+    fn spans(&self, ray: Ray) -> Vec<Span> {
+        local_spans(self, ray)
+    }
+}
+
+impl Surface for Cuboid {
+    fn bounding_box(&self) -> BoundingBox {
+        let h = self.half_extents;
+        self.frame.bounding_box(
+            Point3::new(-h.x(), -h.y(), -h.z()),
+            Point3::new(h.x(), h.y(), h.z()),
+        )
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        intersect_local(self, ray)
+    }
+}
+
+/// A torus centered on a point, with a tube of radius `minor_radius` swept
+/// around its axis at a distance of `major_radius`.
+#[derive(Copy, Clone, Debug)]
+pub struct Torus {
+    frame: Frame,
+    major_radius: f32,
+    minor_radius: f32,
+}
+
+impl Torus {
+    pub fn new(center: Point3, axis: Vector3, major_radius: f32, minor_radius: f32) -> Self {
+        Torus {
+            frame: Frame::along(center, axis),
+            major_radius,
+            minor_radius,
+        }
+    }
+}
+
+impl LocalSurface for Torus {
+    fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    fn local_hits<F: FnMut(LocalHit)>(&self, ray: Ray, mut visit: F) {
+        // Solving the quartic is numerically unstable when the ray starts far
+        // away from the torus, so first move its origin to the point closest
+        // to the center.
+        let offset = -(ray.origin - Point3::origin()).dot(ray.dir);
+        let o = ray.along(offset) - Point3::origin();
+        let d = ray.dir;
+
+        // Substitute the ray into (|p|^2 + R^2 - r^2)^2 = 4R^2(x^2 + y^2).
+        let r2 = (self.major_radius as f64).powi(2);
+        let (ox, oy, oz) = (o.x() as f64, o.y() as f64, o.z() as f64);
+        let (dx, dy, dz) = (d.x() as f64, d.y() as f64, d.z() as f64);
+        let u = ox * dx + oy * dy + oz * dz;
+        let k = ox * ox + oy * oy + oz * oz + r2 - (self.minor_radius as f64).powi(2);
+        let roots = polynomial::solve_quartic([
+            k * k - 4.0 * r2 * (ox * ox + oy * oy),
+            4.0 * k * u - 8.0 * r2 * (ox * dx + oy * dy),
+            2.0 * k + 4.0 * u * u - 4.0 * r2 * (dx * dx + dy * dy),
+            4.0 * u,
+            1.0,
+        ]);
+
+        for root in roots {
+            let distance = root as f32 + offset;
+            let p = ray.along(distance);
+            let radial = (p.x() * p.x() + p.y() * p.y()).sqrt();
+            let normal = if radial > 0.0 {
+                let ring = Point3::new(p.x(), p.y(), 0.0) - Point3::origin();
+                (p - (Point3::origin() + ring * (self.major_radius / radial))).normalize()
+            } else {
+                // Spindle and horn tori meet their axis in a point, where the
+                // surface faces along the axis.
+                Vector3::new(0.0, 0.0, if p.z() < 0.0 { -1.0 } else { 1.0 })
+            };
+            let tube_angle = p.z().atan2(radial - self.major_radius) / (2.0 * PI);
+            visit(LocalHit {
+                distance,
+                normal,
+                tangent: azimuth_tangent(p.x(), p.y()),
+                texture_coords: TextureCoords::new(
+                    azimuth(p.x(), p.y()),
+                    tube_angle.rem_euclid(1.0),
+                ),
+                texture_density: texture_density(
+                    1.0 / (2.0 * PI * radial),
+                    1.0 / (2.0 * PI * self.minor_radius),
+                ),
+            });
+        }
+    }
+}
+
+impl Solid for Torus {
+    fn spans(&self, ray: Ray) -> Vec<Span> {
+        local_spans(self, ray)
+    }
+}
+
+impl Surface for Torus {
+    fn bounding_box(&self) -> BoundingBox {
+        let r = self.major_radius + self.minor_radius;
+        let h = self.minor_radius;
+        self.frame
+            .bounding_box(Point3::new(-r, -r, -h), Point3::new(r, r, h))
+    }
+
+    fn intersect(&self, ray: Ray) -> Option<Intersection> {
+        intersect_local(self, ray)
+    }
+}
+
+/// A rigid transform from a surface's local coordinate space, in which its
+/// axis of symmetry lies along z, into world space.
+#[derive(Copy, Clone, Debug)]
+struct Frame {
+    origin: Point3,
+    rotation: Mat3,
+}
+
+impl Frame {
+    fn new(origin: Point3, rotation: Mat3) -> Self {
+        Frame { origin, rotation }
+    }
+
+    fn along(origin: Point3, axis: Vector3) -> Self {
+        Frame::new(origin, axis.normalize().tangent_space())
+    }
+
+    fn localize(&self, ray: Ray) -> Ray {
+        let inverse = self.rotation.transpose();
+        Ray {
+            origin: Point3::origin() + inverse * (ray.origin - self.origin),
+            dir: inverse * ray.dir,
+        }
+    }
+
+    fn bounding_box(&self, min: Point3, max: Point3) -> BoundingBox {
+        let mut bb = BoundingBox::empty();
+        for i in 0..8 {
+            let corner = Vector3::new(
+                if i & 1 == 0 { min.x() } else { max.x() },
+                if i & 2 == 0 { min.y() } else { max.y() },
+                if i & 4 == 0 { min.z() } else { max.z() },
+            );
+            bb.add_point(self.origin + self.rotation * corner);
+        }
+        bb
+    }
+
+    fn intersection(&self, ray: Ray, hit: LocalHit) -> Intersection {
+        Intersection {
+            distance: hit.distance,
+            position: ray.along(hit.distance),
             incident: ray.dir,
-            normal,
-            texture_coords,
-        })
+            normal: self.rotation * hit.normal,
+            geometric_normal: self.rotation * hit.normal,
+            tangent: self.rotation * hit.tangent,
+            texture_coords: hit.texture_coords,
+            // The density is unbounded where texture coordinates pinch to a
+            // point, such as at the center of a disk.
+            texture_density: if hit.texture_density.is_finite() {
+                hit.texture_density
+            } else {
+                0.0
+            },
+            vertex_color: None,
+        }
+    }
+}
+
+/// A ray intersection in a surface's local coordinate space.
+#[derive(Copy, Clone, Debug)]
+struct LocalHit {
+    distance: f32,
+    normal: Vector3,
+    tangent: Vector3,
+    texture_coords: TextureCoords,
+    texture_density: f32,
+}
+
+/// A surface which is simpler to intersect in its own coordinate space.
+trait LocalSurface {
+    fn frame(&self) -> &Frame;
+
+    /// Visits every intersection along the entire line of a ray, in local
+    /// coordinates, including those behind its origin.
+    fn local_hits<F: FnMut(LocalHit)>(&self, ray: Ray, visit: F);
+}
+
+fn intersect_local<S: LocalSurface>(surface: &S, ray: Ray) -> Option<Intersection> {
+    let mut nearest: Option<LocalHit> = None;
+    surface.local_hits(surface.frame().localize(ray), |hit| {
+        if hit.distance > 0.0 && nearest.is_none_or(|nearest| hit.distance < nearest.distance) {
+            nearest = Some(hit);
+        }
+    });
+    nearest.map(|hit| surface.frame().intersection(ray, hit))
+}
+
+fn local_spans<S: LocalSurface>(surface: &S, ray: Ray) -> Vec<Span> {
+    let local = surface.frame().localize(ray);
+    let mut hits = Vec::new();
+    surface.local_hits(local, |hit| hits.push(hit));
+    hits.sort_by(|left, right| float::compare(&left.distance, &right.distance));
+
+    // Normals face out of the solid, so a ray enters wherever it meets a
+    // normal head on, and exits wherever it leaves one behind. Pairing hits by
+    // which way they face, rather than by their order alone, keeps the spans
+    // right where a ray grazes the surface, or passes through an edge where
+    // two of its parts both report a hit.
+    let mut spans = Vec::new();
+    let mut enter = None;
+    for hit in hits {
+        let facing = hit.normal.dot(local.dir);
+        if facing < 0.0 && enter.is_none() {
+            enter = Some(hit);
+        } else if facing > 0.0 {
+            if let Some(enter) = enter.take() {
+                spans.push(Span {
+                    enter: surface.frame().intersection(ray, enter),
+                    exit: surface.frame().intersection(ray, hit),
+                });
+            }
+        }
+    }
+    spans
+}
+
+/// Intersects a local ray with a disk facing along the z axis.
+fn cap_hit<F: FnMut(LocalHit)>(ray: Ray, z: f32, facing: f32, radius: f32, mut visit: F) {
+    let distance = (z - ray.origin.z()) / ray.dir.z();
+    if !distance.is_finite() {
+        return;
+    }
+    let p = ray.along(distance);
+    let r = (p.x() * p.x() + p.y() * p.y()).sqrt();
+    if r <= radius {
+        visit(LocalHit {
+            distance,
+            normal: Vector3::new(0.0, 0.0, facing),
+            tangent: azimuth_tangent(p.x(), p.y()),
+            texture_coords: TextureCoords::new(azimuth(p.x(), p.y()), r / radius),
+            texture_density: texture_density(1.0 / (2.0 * PI * r), 1.0 / radius),
+        });
+    }
+}
+
+/// The texture density of a surface whose texture coordinates move at these
+/// rates, per unit of distance, along two perpendicular directions.
+fn texture_density(u_rate: f32, v_rate: f32) -> f32 {
+    (u_rate * v_rate).sqrt()
+}
+
+/// The angle around the z axis, as a fraction of a full turn.
+fn azimuth(x: f32, y: f32) -> f32 {
+    (y.atan2(x) / (2.0 * PI)).rem_euclid(1.0)
+}
+
+/// The direction of increasing azimuth around the z axis.
+fn azimuth_tangent(x: f32, y: f32) -> Vector3 {
+    if x == 0.0 && y == 0.0 {
+        // On the axis itself, any direction is tangent.
+        Vector3::new(1.0, 0.0, 0.0)
+    } else {
+        Vector3::new(-y, x, 0.0).normalize()
     }
 }
 
@@ -161,3 +1024,138 @@ fn float_bounds(fs: &[f32]) -> (f32, f32) {
     let max = fs.iter().cloned().max_by(float::compare).unwrap();
     (min, max)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ray(origin: (f32, f32, f32), dir: (f32, f32, f32)) -> Ray {
+        Ray::new(
+            Point3::new(origin.0, origin.1, origin.2),
+            Vector3::new(dir.0, dir.1, dir.2),
+        )
+    }
+
+    fn assert_hit(hit: Option<Intersection>, distance: f32, normal: (f32, f32, f32)) {
+        let hit = hit.expect("ray should hit");
+        assert!((hit.distance - distance).abs() < 1e-4, "{}", hit.distance);
+        let actual = (hit.normal.x(), hit.normal.y(), hit.normal.z());
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        assert!(
+            close(actual.0, normal.0) && close(actual.1, normal.1) && close(actual.2, normal.2),
+            "{:?}",
+            actual
+        );
+    }
+
+    #[test]
+    fn plane() {
+        let plane = Plane::new(Point3::origin(), Vector3::new(0.0, 1.0, 0.0));
+        let down = ray((1.0, 5.0, 2.0), (0.0, -1.0, 0.0));
+        assert_hit(plane.intersect(down), 5.0, (0.0, 1.0, 0.0));
+        let across = ray((0.0, 5.0, 0.0), (1.0, 0.0, 0.0));
+        assert!(plane.intersect(across).is_none());
+    }
+
+    #[test]
+    fn disk() {
+        let disk = Disk::new(Point3::origin(), Vector3::new(0.0, 0.0, 1.0), 1.0);
+        let inside = ray((0.5, 0.0, 3.0), (0.0, 0.0, -1.0));
+        assert_hit(disk.intersect(inside), 3.0, (0.0, 0.0, 1.0));
+        let outside = ray((1.5, 0.0, 3.0), (0.0, 0.0, -1.0));
+        assert!(disk.intersect(outside).is_none());
+    }
+
+    #[test]
+    fn cylinder() {
+        let cylinder = Cylinder::new(Point3::origin(), Vector3::new(0.0, 0.0, 1.0), 1.0, 2.0);
+        let side = ray((-5.0, 0.0, 1.0), (1.0, 0.0, 0.0));
+        assert_hit(cylinder.intersect(side), 4.0, (-1.0, 0.0, 0.0));
+        let top = ray((0.0, 0.0, 5.0), (0.0, 0.0, -1.0));
+        assert_hit(cylinder.intersect(top), 3.0, (0.0, 0.0, 1.0));
+        let above = ray((-5.0, 0.0, 3.0), (1.0, 0.0, 0.0));
+        assert!(cylinder.intersect(above).is_none());
+    }
+
+    #[test]
+    fn cone() {
+        let cone = Cone::new(Point3::origin(), Vector3::new(0.0, 0.0, 1.0), 1.0, 1.0);
+        let slope = std::f32::consts::FRAC_1_SQRT_2;
+        let side = ray((-5.0, 0.0, 0.5), (1.0, 0.0, 0.0));
+        assert_hit(cone.intersect(side), 4.5, (-slope, 0.0, slope));
+        let base = ray((0.0, 0.0, -5.0), (0.0, 0.0, 1.0));
+        assert_hit(cone.intersect(base), 5.0, (0.0, 0.0, -1.0));
+        let above = ray((-5.0, 0.0, 1.5), (1.0, 0.0, 0.0));
+        assert!(cone.intersect(above).is_none());
+    }
+
+    #[test]
+    fn cuboid() {
+        let (min, max) = (Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let cuboid = Cuboid::new(min, max, Mat3::identity());
+        let front = ray((0.0, 0.0, -5.0), (0.0, 0.0, 1.0));
+        assert_hit(cuboid.intersect(front), 4.0, (0.0, 0.0, -1.0));
+        let right = ray((5.0, 0.5, 0.5), (-1.0, 0.0, 0.0));
+        assert_hit(cuboid.intersect(right), 4.0, (1.0, 0.0, 0.0));
+
+        // Turned about y, the box's bottom stays put, but its edge reaches
+        // further along x.
+        let turned = Cuboid::new(min, max, Mat3::rotation(0.0, 45.0, 0.0));
+        let bottom = ray((0.0, -5.0, 0.0), (0.0, 1.0, 0.0));
+        assert_hit(turned.intersect(bottom), 4.0, (0.0, -1.0, 0.0));
+        let edge = turned.intersect(ray((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0)));
+        assert!((edge.unwrap().distance - (5.0 - std::f32::consts::SQRT_2)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn torus() {
+        let torus = Torus::new(Point3::origin(), Vector3::new(0.0, 0.0, 1.0), 2.0, 0.5);
+        let side = ray((-5.0, 0.0, 0.0), (1.0, 0.0, 0.0));
+        assert_hit(torus.intersect(side), 2.5, (-1.0, 0.0, 0.0));
+        let top = ray((0.0, 2.0, 5.0), (0.0, 0.0, -1.0));
+        assert_hit(torus.intersect(top), 4.5, (0.0, 0.0, 1.0));
+        let hole = ray((0.0, 0.0, 5.0), (0.0, 0.0, -1.0));
+        assert!(torus.intersect(hole).is_none());
+    }
+
+    #[test]
+    fn spindle_torus_meets_its_axis() {
+        let torus = Torus::new(Point3::origin(), Vector3::new(0.0, 0.0, 1.0), 0.5, 1.0);
+        let axis = ray((0.0, 0.0, -5.0), (0.0, 0.0, 1.0));
+        assert_hit(
+            torus.intersect(axis),
+            5.0 - 0.75f32.sqrt(),
+            (0.0, 0.0, -1.0),
+        );
+    }
+
+    #[test]
+    fn watertight_triangles_share_edges() {
+        // Two triangles of a mesh, which share the edge from `a` to `b` with
+        // opposite windings. Away from the origin, points on the edge are
+        // rounded enough for Möller-Trumbore to let rays through.
+        let (a, b) = (Point3::new(30.1, 70.3, 10.7), Point3::new(31.3, 71.1, 9.8));
+        let middle = a + (b - a) * 0.5;
+        let side = (b - a).cross(Vector3::new(0.2, 0.1, 1.0)).normalize();
+        let triangles = [[a, b, middle + side], [b, a, middle - side]];
+        let origin = Point3::new(-200.0, 50.0, 500.0);
+        let mut leaks = 0;
+        for i in 10..990 {
+            let ray = Ray::new(origin, a + (b - a) * (i as f32 / 1000.0) - origin);
+            let mut hit = false;
+            let mut leaked = true;
+            for vertices in &triangles {
+                let watertight = hit_triangle_watertight(vertices, ray);
+                let moller_trumbore = hit_triangle_moller_trumbore(vertices, ray);
+                if let (Some(w), Some(m)) = (watertight, moller_trumbore) {
+                    assert!((w.0 - m.0).abs() < 1e-3, "{:?} {:?}", w, m);
+                }
+                hit |= watertight.is_some();
+                leaked &= moller_trumbore.is_none();
+            }
+            assert!(hit, "ray {} slipped between the triangles", i);
+            leaks += leaked as usize;
+        }
+        assert!(leaks > 0);
+    }
+}
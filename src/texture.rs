@@ -1,15 +1,51 @@
+use bytemuck::{Pod, Zeroable};
 use palette::{LinSrgb, Srgb};
 use std::fmt::Debug;
-use std::ops::{Add, Index, Mul};
+use std::ops::{Add, Mul, Sub};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C)]
 pub struct TextureCoords {
     x: f32,
     y: f32,
 }
 
+/// How a texture blends the pixels around the point it's sampled at.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Filter {
+    /// The nearest pixel, unblended, as textures have always been sampled.
+    #[default]
+    Nearest,
+    /// The four nearest pixels, blended by distance.
+    Bilinear,
+    /// Bilinear samples of the two mipmaps whose pixels are closest in size
+    /// to the ray's footprint, blended, so that distant textures don't alias.
+    Trilinear,
+}
+
+/// How a texture repeats beyond texture coordinates from zero to one.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Wrap {
+    #[default]
+    Repeat,
+    /// Stretch the pixels along the edges outwards.
+    Clamp,
+    /// Repeat, flipping every other copy.
+    Mirror,
+}
+
 #[derive(Clone, Debug)]
 pub struct Texture {
+    // The full-size image, followed by its mipmaps when filtering trilinearly,
+    // each half the size of the one before, down to a single pixel.
+    levels: Vec<Level>,
+    filter: Filter,
+    // For the horizontal and vertical axes.
+    wrap: [Wrap; 2],
+}
+
+#[derive(Clone, Debug)]
+struct Level {
     width: usize,
     height: usize,
     pixels: Vec<LinSrgb>,
@@ -19,6 +55,14 @@ impl TextureCoords {
     pub fn new(x: f32, y: f32) -> Self {
         TextureCoords { x, y }
     }
+
+    pub fn x(&self) -> f32 {
+        self.x
+    }
+
+    pub fn y(&self) -> f32 {
+        self.y
+    }
 }
 
 impl Default for TextureCoords {
@@ -34,6 +78,13 @@ impl Add for TextureCoords {
     }
 }
 
+impl Sub for TextureCoords {
+    type Output = TextureCoords;
+    fn sub(self, other: TextureCoords) -> TextureCoords {
+        TextureCoords::new(self.x - other.x, self.y - other.y)
+    }
+}
+
 impl Mul<TextureCoords> for f32 {
     type Output = TextureCoords;
     fn mul(self, rhs: TextureCoords) -> TextureCoords {
@@ -43,28 +94,245 @@ impl Mul<TextureCoords> for f32 {
 
 impl Texture {
     pub fn from_image(img: image::RgbImage) -> Self {
-        let width = img.width() as usize;
-        let height = img.height() as usize;
         let pixels = img
             .pixels()
             .map(|image::Rgb(p)| Srgb::from(*p).into_linear())
             .collect();
+        Texture::new(img.width() as usize, img.height() as usize, pixels)
+    }
+
+    /// Makes a texture from an image whose values are linear rather than sRGB,
+    /// such as a normal map.
+    pub fn from_linear_image(img: image::RgbImage) -> Self {
+        let pixels = img
+            .pixels()
+            .map(|image::Rgb([r, g, b])| LinSrgb::new(*r as f32, *g as f32, *b as f32) / 255.0)
+            .collect();
+        Texture::new(img.width() as usize, img.height() as usize, pixels)
+    }
+
+    /// Makes a grey texture from linear values, given row by row from the
+    /// top, for data such as roughness rather than color.
+    pub fn from_values(width: usize, height: usize, values: Vec<f32>) -> Self {
+        assert_eq!(values.len(), width * height);
+        let pixels = values
+            .into_iter()
+            .map(|value| LinSrgb::new(value, value, value))
+            .collect();
+        Texture::new(width, height, pixels)
+    }
+
+    fn new(width: usize, height: usize, pixels: Vec<LinSrgb>) -> Self {
         Texture {
+            levels: vec![Level {
+                width,
+                height,
+                pixels,
+            }],
+            filter: Filter::default(),
+            wrap: [Wrap::default(); 2],
+        }
+    }
+
+    /// Chooses how the texture is filtered, building its mipmaps if it's to be
+    /// filtered trilinearly.
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self.levels.truncate(1);
+        if filter == Filter::Trilinear {
+            while let Some(level) = self.levels.last().unwrap().downsample() {
+                self.levels.push(level);
+            }
+        }
+        self
+    }
+
+    /// Chooses how the texture repeats horizontally and vertically.
+    pub fn with_wrap(mut self, horizontal: Wrap, vertical: Wrap) -> Self {
+        self.wrap = [horizontal, vertical];
+        self
+    }
+
+    /// The width and height of the texture, in pixels.
+    pub fn size(&self) -> (usize, usize) {
+        (self.levels[0].width, self.levels[0].height)
+    }
+
+    /// Multiplies every pixel by a color.
+    pub fn tinted(mut self, tint: LinSrgb) -> Self {
+        for level in &mut self.levels {
+            for pixel in &mut level.pixels {
+                *pixel *= tint;
+            }
+        }
+        self
+    }
+
+    /// Samples the texture at a point. The footprint is how far across the
+    /// texture the ray covers, in texture coordinates, which trilinear
+    /// filtering blurs the texture by.
+    pub fn sample(&self, coords: TextureCoords, footprint: f32) -> LinSrgb {
+        match self.filter {
+            Filter::Nearest => self.levels[0].nearest(coords, self.wrap),
+            Filter::Bilinear => self.levels[0].bilinear(coords, self.wrap),
+            Filter::Trilinear => {
+                let (width, height) = self.size();
+                let pixels = footprint * width.max(height) as f32;
+                let lod = pixels.max(1.0).log2().min((self.levels.len() - 1) as f32);
+                let finer = lod as usize;
+                let coarser = (finer + 1).min(self.levels.len() - 1);
+                let t = lod - finer as f32;
+                self.levels[finer].bilinear(coords, self.wrap) * (1.0 - t)
+                    + self.levels[coarser].bilinear(coords, self.wrap) * t
+            }
+        }
+    }
+}
+
+impl Level {
+    // Halves the image by averaging each 2x2 block of pixels, or returns none
+    // if it's already a single pixel.
+    fn downsample(&self) -> Option<Level> {
+        if self.width == 1 && self.height == 1 {
+            return None;
+        }
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                // Odd sizes leave the last row or column out.
+                let (x0, y0) = (2 * x, 2 * y);
+                let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+                let sum = self.pixel(x0, y0)
+                    + self.pixel(x1, y0)
+                    + self.pixel(x0, y1)
+                    + self.pixel(x1, y1);
+                pixels.push(sum * 0.25);
+            }
+        }
+        Some(Level {
             width,
             height,
             pixels,
-        }
+        })
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> LinSrgb {
+        self.pixels[x + y * self.width]
+    }
+
+    // The position of a point in pixels, from the top left of the image.
+    fn position(&self, coords: TextureCoords) -> (f32, f32) {
+        (
+            coords.x * self.width as f32,
+            (1.0 - coords.y) * self.height as f32,
+        )
+    }
+
+    fn wrapped(&self, x: i64, y: i64, wrap: [Wrap; 2]) -> LinSrgb {
+        let x = wrap_index(x, self.width, wrap[0]);
+        let y = wrap_index(y, self.height, wrap[1]);
+        self.pixel(x, y)
+    }
+
+    fn nearest(&self, coords: TextureCoords, wrap: [Wrap; 2]) -> LinSrgb {
+        let (x, y) = self.position(coords);
+        self.wrapped(x.floor() as i64, y.floor() as i64, wrap)
+    }
+
+    fn bilinear(&self, coords: TextureCoords, wrap: [Wrap; 2]) -> LinSrgb {
+        // Pixel centres lie half way across each pixel.
+        let (x, y) = self.position(coords);
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.wrapped(x0, y0, wrap) * (1.0 - tx) + self.wrapped(x0 + 1, y0, wrap) * tx;
+        let bottom =
+            self.wrapped(x0, y0 + 1, wrap) * (1.0 - tx) + self.wrapped(x0 + 1, y0 + 1, wrap) * tx;
+        top * (1.0 - ty) + bottom * ty
     }
 }
 
-impl Index<TextureCoords> for Texture {
-    type Output = LinSrgb;
+fn wrap_index(index: i64, size: usize, wrap: Wrap) -> usize {
+    let size = size as i64;
+    let index = match wrap {
+        Wrap::Repeat => index.rem_euclid(size),
+        Wrap::Clamp => index.clamp(0, size - 1),
+        Wrap::Mirror => {
+            let index = index.rem_euclid(2 * size);
+            if index < size {
+                index
+            } else {
+                2 * size - 1 - index
+            }
+        }
+    };
+    index as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(texture: &Texture, x: f32, y: f32, footprint: f32) -> f32 {
+        texture.sample(TextureCoords::new(x, y), footprint).red
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-6, "{}", actual);
+    }
+
+    #[test]
+    fn wrap_modes() {
+        let wrapped = |wrap| [-5, -1, 0, 3, 4, 5].map(|i| wrap_index(i, 4, wrap));
+        assert_eq!(wrapped(Wrap::Repeat), [3, 3, 0, 3, 0, 1]);
+        assert_eq!(wrapped(Wrap::Clamp), [0, 0, 0, 3, 3, 3]);
+        assert_eq!(wrapped(Wrap::Mirror), [3, 0, 0, 3, 3, 2]);
+    }
+
+    #[test]
+    fn nearest_sampling() {
+        let texture = Texture::from_values(2, 1, vec![0.0, 1.0]);
+        assert_close(value(&texture, 0.25, 0.5, 0.0), 0.0);
+        assert_close(value(&texture, 0.75, 0.5, 0.0), 1.0);
+        assert_close(value(&texture, 1.25, 0.5, 0.0), 0.0);
+        let clamped = texture.with_wrap(Wrap::Clamp, Wrap::Clamp);
+        assert_close(value(&clamped, 1.25, 0.5, 0.0), 1.0);
+    }
+
+    #[test]
+    fn bilinear_sampling() {
+        let texture = Texture::from_values(2, 1, vec![0.0, 1.0]).with_filter(Filter::Bilinear);
+        // Pixel centres give their own values, and points between them blend.
+        assert_close(value(&texture, 0.25, 0.5, 0.0), 0.0);
+        assert_close(value(&texture, 0.75, 0.5, 0.0), 1.0);
+        assert_close(value(&texture, 0.5, 0.5, 0.0), 0.5);
+        assert_close(value(&texture, 0.375, 0.5, 0.0), 0.25);
+        // At the edge, the pixel beyond depends on how the texture wraps.
+        assert_close(value(&texture, 0.0, 0.5, 0.0), 0.5);
+        let clamped = texture.clone().with_wrap(Wrap::Clamp, Wrap::Clamp);
+        assert_close(value(&clamped, 0.0, 0.5, 0.0), 0.0);
+        let mirrored = texture.with_wrap(Wrap::Mirror, Wrap::Mirror);
+        assert_close(value(&mirrored, 1.0, 0.5, 0.0), 1.0);
+    }
 
-    fn index(&self, coords: TextureCoords) -> &Self::Output {
-        // Snap to the previous pixel. An improvement would be to linearly
-        // interpolate between the 4 pixels we are between.
-        let x = (coords.x * self.width as f32) as usize % self.width;
-        let y = ((1.0 - coords.y) * self.height as f32) as usize % self.height;
-        &self.pixels[x + y * self.width]
+    #[test]
+    fn trilinear_sampling() {
+        // A checkerboard of single pixels, which averages to grey at every
+        // mipmap below the full size.
+        let values = (0..16).map(|i| ((i % 4 + i / 4) % 2) as f32).collect();
+        let texture = Texture::from_values(4, 4, values).with_filter(Filter::Trilinear);
+        let (x, y) = (0.375, 0.875);
+        // Footprints smaller than a pixel sample the full-size texture.
+        assert_close(value(&texture, x, y, 0.0), 1.0);
+        assert_close(value(&texture, x, y, 0.25), 1.0);
+        // Footprints between one and two pixels blend it with the first
+        // mipmap, here half way between them.
+        assert_close(value(&texture, x, y, 2.0_f32.sqrt() / 4.0), 0.75);
+        // Wider ones only see grey.
+        assert_close(value(&texture, x, y, 0.5), 0.5);
+        assert_close(value(&texture, x, y, 10.0), 0.5);
     }
 }
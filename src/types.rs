@@ -1,14 +1,17 @@
+use bytemuck::{Pod, Zeroable};
 use std::fmt;
-use std::ops::{Add, Div, Index, Mul, Neg, Sub};
+use std::ops::{Add, Div, Index, IndexMut, Mul, Neg, Sub};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
 pub struct Vector3 {
     x: f32,
     y: f32,
     z: f32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
 pub struct Point3 {
     x: f32,
     y: f32,
@@ -80,7 +83,8 @@ impl Vector3 {
             Vector3::new(self.y, -self.x, 0.0)
         } else {
             Vector3::new(0.0, self.z, -self.y)
-        };
+        }
+        .normalize();
         let binormal = self.cross(tangent);
         Mat3 {
             values: [
@@ -163,6 +167,10 @@ impl Point3 {
         Point3 { x, y, z }
     }
 
+    pub fn origin() -> Self {
+        Point3::new(0.0, 0.0, 0.0)
+    }
+
     pub fn x(&self) -> f32 {
         self.x
     }
@@ -225,6 +233,42 @@ impl Mat3 {
     pub fn new(values: [[f32; 3]; 3]) -> Self {
         Mat3 { values }
     }
+
+    pub fn identity() -> Self {
+        Mat3::new([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+    }
+
+    /// Builds a rotation from Euler angles in degrees, applied about the x,
+    /// then y, then z axes.
+    pub fn rotation(x: f32, y: f32, z: f32) -> Self {
+        let (x, y, z) = (x.to_radians(), y.to_radians(), z.to_radians());
+        let rx = Mat3::new([
+            [1.0, 0.0, 0.0],
+            [0.0, x.cos(), -x.sin()],
+            [0.0, x.sin(), x.cos()],
+        ]);
+        let ry = Mat3::new([
+            [y.cos(), 0.0, y.sin()],
+            [0.0, 1.0, 0.0],
+            [-y.sin(), 0.0, y.cos()],
+        ]);
+        let rz = Mat3::new([
+            [z.cos(), -z.sin(), 0.0],
+            [z.sin(), z.cos(), 0.0],
+            [0.0, 0.0, 1.0],
+        ]);
+        rz * ry * rx
+    }
+
+    pub fn transpose(self) -> Self {
+        let mut values = self.values;
+        for (i, row) in values.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.values[j][i];
+            }
+        }
+        Mat3::new(values)
+    }
 }
 
 impl Index<(usize, usize)> for Mat3 {
@@ -296,3 +340,13 @@ impl Index<Axis> for Point3 {
         }
     }
 }
+
+impl IndexMut<Axis> for Point3 {
+    fn index_mut(&mut self, axis: Axis) -> &mut f32 {
+        match axis {
+            Axis::X => &mut self.x,
+            Axis::Y => &mut self.y,
+            Axis::Z => &mut self.z,
+        }
+    }
+}